@@ -0,0 +1,79 @@
+use crate::protocol::{ClientId, Priority, ServerOperation};
+use crate::server::Server;
+use crate::AnyResult;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/* At-least-once delivery for messages sent with Qos::AtLeastOnce.
+    Every recipient numbers what it is owed from 1 up and gets each message wrapped in Reliable
+    with that seq. The message stays in Unacked until the recipient sends Ack(seq); run resends it
+    every qos.retry_interval_ms and on reconnect, and after qos.max_attempts sends, or once its ttl
+    is over, the relay gives up and tells the sender with DeliveryFailed. A retry while the
+    recipient is offline counts as a send, so one that stays away fails the same way. A recipient
+    owes at most qos.max_pending Acks, the sender of one more message for it gets a Nack.
+    Unacked messages only live in memory, a restart loses them.
+*/
+
+/// A message waiting for its Ack
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub operation: ServerOperation,
+    pub sender: ClientId,
+    pub priority: Priority,
+    pub expires: Option<Instant>,
+    // sends so far, including those while the recipient had no connection
+    pub attempts: u32,
+    // when to send it again, None until it was sent once
+    pub due: Option<Instant>,
+}
+
+impl Pending {
+    pub fn expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.due.is_none_or(|due| due <= now)
+    }
+}
+
+/// Everything one recipient has not acknowledged yet, by seq
+#[derive(Debug, Default)]
+pub struct Unacked {
+    last_seq: u64,
+    pub pending: BTreeMap<u64, Pending>,
+}
+
+impl Unacked {
+    pub fn push(&mut self, pending: Pending) -> u64 {
+        self.last_seq += 1;
+        self.pending.insert(self.last_seq, pending);
+        self.last_seq
+    }
+
+    pub fn ack(&mut self, seq: u64) -> Option<Pending> {
+        self.pending.remove(&seq)
+    }
+}
+
+/// Resends whatever is due until shutdown stops the listeners
+pub async fn run(server: Arc<Mutex<Server>>) -> AnyResult {
+    let (stop, retry_interval) = {
+        let server = server.lock().await;
+        (
+            server.shutdown.stop_accepting.clone(),
+            Duration::from_millis(server.config.qos.retry_interval_ms),
+        )
+    };
+    // checking twice per interval keeps a resend at most half an interval late
+    let mut ticker = tokio::time::interval(retry_interval / 2);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            _ = ticker.tick() => server.lock().await.redeliver(Instant::now()),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use crate::config::AuthConfig;
+use crate::protocol::Identity;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/* Two ways of proving who you are on ConnectAttempt:
+    a static token listed in auth.tokens, which maps straight to an identity, or a challenge
+    response where the client claims an identity, gets a random nonce back and answers with
+    hex(HMAC-SHA256(auth.secret, "<nonce>:<identity>")).
+*/
+
+pub fn identity_for_token(config: &AuthConfig, token: &str) -> Option<Identity> {
+    config
+        .tokens
+        .get(token)
+        .map(|identity| Identity(identity.clone()))
+}
+
+pub fn new_nonce() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+fn mac_for(secret: &str, nonce: &str, identity: &Identity) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(nonce.as_bytes());
+    mac.update(b":");
+    mac.update(identity.as_bytes());
+    mac
+}
+
+/// What a client should send back in AuthResponse
+#[cfg(test)]
+pub fn sign(secret: &str, nonce: &str, identity: &Identity) -> String {
+    hex::encode(mac_for(secret, nonce, identity).finalize().into_bytes())
+}
+
+/// Checks an AuthResponse in constant time
+pub fn verify(secret: &str, nonce: &str, identity: &Identity, response: &str) -> bool {
+    match hex::decode(response) {
+        Ok(bytes) => mac_for(secret, nonce, identity)
+            .verify_slice(&bytes)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
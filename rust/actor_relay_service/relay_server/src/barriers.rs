@@ -0,0 +1,108 @@
+use crate::protocol::{ClientId, Participants, Recipient};
+use std::time::Instant;
+
+/* Named barriers per room.
+    The first BarrierWait sets up who the barrier waits for, a number of participants or a list of
+    identities, and when it gives up. Everyone who waits is told with BarrierReleased the moment the
+    last of them arrives, or with BarrierTimedOut, naming who is missing, once the timeout passes.
+    Either way the barrier is gone afterwards and the same name can be waited on again.
+    A participant is an identity, or a connection for clients without one, so an identity waiting
+    from two connections still counts once. Leaving the room or disconnecting takes back an arrival.
+*/
+
+#[derive(Debug, Clone)]
+pub struct Barrier {
+    pub participants: Participants,
+    pub deadline: Instant,
+    // connections waiting and who they count as, in the order they arrived
+    arrivals: Vec<(ClientId, Recipient)>,
+}
+
+impl Barrier {
+    pub fn new(participants: Participants, deadline: Instant) -> Self {
+        Self {
+            participants,
+            deadline,
+            arrivals: Vec::new(),
+        }
+    }
+
+    /// Whether `who` is someone this barrier waits for
+    pub fn expects(&self, who: &Recipient) -> bool {
+        match &self.participants {
+            Participants::Count(_) => true,
+            Participants::Members(members) => {
+                matches!(who, Recipient::Identity(identity) if members.contains(identity))
+            }
+        }
+    }
+
+    pub fn arrive(&mut self, clientId: ClientId, who: Recipient) {
+        if !self
+            .arrivals
+            .iter()
+            .any(|(waiting, _)| *waiting == clientId)
+        {
+            self.arrivals.push((clientId, who));
+        }
+    }
+
+    /// Takes back a connection's arrival, true if it was waiting
+    pub fn leave(&mut self, clientId: &ClientId) -> bool {
+        let waiting = self.arrivals.len();
+        self.arrivals.retain(|(waiting, _)| waiting != clientId);
+        self.arrivals.len() < waiting
+    }
+
+    /// Every participant that arrived, once each, first arrival first
+    pub fn arrived(&self) -> Vec<Recipient> {
+        let mut arrived: Vec<Recipient> = Vec::new();
+        for (_, who) in &self.arrivals {
+            if !arrived.contains(who) {
+                arrived.push(who.clone());
+            }
+        }
+        arrived
+    }
+
+    pub fn needed(&self) -> usize {
+        match &self.participants {
+            Participants::Count(count) => *count,
+            Participants::Members(members) => members.len(),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.arrived().len() >= self.needed()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arrivals.is_empty()
+    }
+
+    pub fn waiters(&self) -> Vec<ClientId> {
+        self.arrivals
+            .iter()
+            .map(|(clientId, _)| *clientId)
+            .collect()
+    }
+
+    /// Listed members that did not arrive. A barrier counting heads can only name whoever else
+    /// is in the room, those are passed in as `present`
+    pub fn missing(&self, present: Vec<Recipient>) -> Vec<Recipient> {
+        let arrived = self.arrived();
+        let candidates = match &self.participants {
+            Participants::Count(_) => present,
+            Participants::Members(members) => {
+                members.iter().cloned().map(Recipient::Identity).collect()
+            }
+        };
+        let mut missing: Vec<Recipient> = Vec::new();
+        for who in candidates {
+            if !arrived.contains(&who) && !missing.contains(&who) {
+                missing.push(who);
+            }
+        }
+        missing
+    }
+}
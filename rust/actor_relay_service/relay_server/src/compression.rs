@@ -0,0 +1,162 @@
+use crate::config::CompressionConfig;
+use crate::metrics::Metrics;
+use crate::protocol::Compression;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, OnceLock};
+use thiserror::Error;
+use tracing::warn;
+
+/* Per connection frame compression.
+    A client lists the codecs it can handle in ConnectAttempt and the relay picks the first of them
+    it has enabled, announcing it with CompressionEnabled. From then on frames of at least
+    compression.min_bytes travel as
+        {"Compressed":{"codec":"Zstd","data":"<base64>"}}
+    in place of the frame itself, in both directions, newline terminated on the stream transports
+    like any other frame. An envelope with any other codec than the negotiated one is refused with
+    a Nack. Compression happens in each connection's writer, so a room can mix
+    clients that negotiated different codecs, or none, and every one of them gets frames it reads.
+*/
+
+// zstd's default, a good trade for frames of a few kilobytes
+const ZSTD_LEVEL: i32 = 3;
+
+// every compressed frame starts like this, which spares parsing the ones that are not
+const PREFIX: &str = r#"{"Compressed""#;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Envelope {
+    Compressed { codec: Compression, data: String },
+}
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("malformed compressed frame: {0}")]
+    Envelope(#[from] serde_json::Error),
+    #[error("{0:?} was not negotiated for this connection")]
+    NotNegotiated(Compression),
+    #[error("compressed data is not base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("could not decompress the frame: {0}")]
+    Corrupt(#[from] io::Error),
+    #[error("frame is over {0} bytes once decompressed")]
+    TooLarge(usize),
+    #[error("decompressed frame is not UTF-8")]
+    NotUtf8,
+}
+
+/// One connection's codec, shared by its session, which negotiates it, and its writer
+#[derive(Clone)]
+pub struct FrameCodec {
+    negotiated: Arc<OnceLock<Compression>>,
+    enabled: Arc<[Compression]>,
+    min_bytes: usize,
+    // a compressed frame has to fit in the frame limit after decompressing too
+    max_frame_bytes: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl FrameCodec {
+    pub fn new(config: &CompressionConfig, max_frame_bytes: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            negotiated: Arc::new(OnceLock::new()),
+            enabled: config.codecs.clone().into(),
+            min_bytes: config.min_bytes,
+            max_frame_bytes,
+            metrics,
+        }
+    }
+
+    /// Picks the first offered codec that is enabled. The writer starts compressing right away,
+    /// the caller announces it with CompressionEnabled
+    pub fn negotiate(&self, offered: &[Compression]) -> Option<Compression> {
+        let codec = *offered.iter().find(|codec| self.enabled.contains(codec))?;
+        // a second ConnectAttempt keeps what the first one negotiated
+        Some(*self.negotiated.get_or_init(|| codec))
+    }
+
+    pub fn min_bytes(&self) -> usize {
+        self.min_bytes
+    }
+
+    /// The frame as it goes on the wire, newline terminated like it came in
+    pub fn encode(&self, frame: String) -> String {
+        let Some(&codec) = self.negotiated.get() else {
+            return frame;
+        };
+        if frame.len() < self.min_bytes {
+            return frame;
+        }
+        let compressed = match compress(codec, frame.trim_end_matches('\n').as_bytes()) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                // the client reads plain frames too
+                warn!("Failed to compress a frame with {:?}: {}", codec, e);
+                return frame;
+            }
+        };
+        let envelope = Envelope::Compressed {
+            codec,
+            data: BASE64.encode(compressed),
+        };
+        let Ok(mut encoded) = serde_json::to_string(&envelope) else {
+            return frame;
+        };
+        // base64 costs a third, tiny or random payloads can come out bigger
+        if encoded.len() >= frame.len() {
+            return frame;
+        }
+        encoded.push('\n');
+        self.metrics.compressed(frame.len() - encoded.len());
+        encoded
+    }
+
+    /// The frame a client sent, decompressed if it came in an envelope
+    pub fn decode<'a>(&self, frame: &'a str) -> Result<Cow<'a, str>, CompressionError> {
+        if !frame.trim_start().starts_with(PREFIX) {
+            return Ok(Cow::Borrowed(frame));
+        }
+        let Envelope::Compressed { codec, data } = serde_json::from_str(frame)?;
+        // only what ConnectAttempt settled on, so nothing is inflated before the client is approved
+        if self.negotiated.get() != Some(&codec) {
+            return Err(CompressionError::NotNegotiated(codec));
+        }
+        let compressed = BASE64.decode(data)?;
+        let decompressed = decompress(codec, &compressed, self.max_frame_bytes)?;
+        String::from_utf8(decompressed)
+            .map(Cow::Owned)
+            .map_err(|_| CompressionError::NotUtf8)
+    }
+}
+
+fn compress(codec: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Compression::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+        Compression::Deflate => {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Decompresses at most `limit` bytes, anything past that is refused rather than inflated
+fn decompress(codec: Compression, data: &[u8], limit: usize) -> Result<Vec<u8>, CompressionError> {
+    let reader: Box<dyn Read + '_> = match codec {
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(data)?),
+        Compression::Deflate => Box::new(flate2::read::DeflateDecoder::new(data)),
+    };
+    let mut decompressed = Vec::new();
+    // one byte over the limit is enough to tell an oversized frame apart
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > limit {
+        return Err(CompressionError::TooLarge(limit));
+    }
+    Ok(decompressed)
+}
@@ -0,0 +1,780 @@
+/* Relay configuration.
+    Values come from, lowest precedence first: built in defaults, the TOML file given by --config,
+    environment variables and finally command line flags.  clap handles the last two, so every
+    flag below can also be set through the RELAY_* variable named next to it.
+*/
+use crate::protocol::Compression;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub const DEFAULT_TCP_ADDR: &str = "0.0.0.0:8080";
+pub const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+const MIN_SECRET_LEN: usize = 16;
+
+#[derive(Debug, Parser)]
+#[command(name = "relay_server", about = "Actor relay for ZenActors clients")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML configuration file
+    #[arg(short, long, env = "RELAY_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address the TCP listener binds to
+    #[arg(long, env = "RELAY_TCP")]
+    pub tcp: Option<SocketAddr>,
+
+    /// Do not listen on TCP, for relays only reachable through the unix socket
+    #[arg(long, env = "RELAY_NO_TCP")]
+    pub no_tcp: bool,
+
+    /// Path of a unix domain socket to listen on as well
+    #[arg(long, env = "RELAY_UNIX")]
+    pub unix: Option<PathBuf>,
+
+    /// Address to accept WebSocket connections on
+    #[arg(long, env = "RELAY_WEBSOCKET")]
+    pub websocket: Option<SocketAddr>,
+
+    /// Address of the admin HTTP API, normally a loopback one
+    #[arg(long, env = "RELAY_HTTP")]
+    pub http: Option<SocketAddr>,
+
+    /// Address to serve Prometheus /metrics on
+    #[arg(long, env = "RELAY_METRICS")]
+    pub metrics: Option<SocketAddr>,
+
+    /// PEM certificate chain, turns on TLS together with --tls-key
+    #[arg(long, env = "RELAY_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "RELAY_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Maximum number of simultaneously connected clients
+    #[arg(long, env = "RELAY_MAX_CLIENTS")]
+    pub max_clients: Option<usize>,
+
+    /// Largest accepted frame in bytes, longer frames close the connection
+    #[arg(long, env = "RELAY_MAX_FRAME_BYTES")]
+    pub max_frame_bytes: Option<usize>,
+
+    /// Maximum number of rooms a single client can be in
+    #[arg(long, env = "RELAY_MAX_ROOMS_PER_CLIENT")]
+    pub max_rooms_per_client: Option<usize>,
+
+    /// Messages kept per room and replayed to clients joining it
+    #[arg(long, env = "RELAY_HISTORY")]
+    pub history: Option<usize>,
+
+    /// A level (error, warn, info, debug, trace) or target=level list, e.g. info,relay_server::session=debug
+    #[arg(long, env = "RELAY_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Log format, text or json
+    #[arg(long, env = "RELAY_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Include frame contents in debug logs
+    #[arg(long, env = "RELAY_LOG_PAYLOADS")]
+    pub log_payloads: bool,
+
+    /// SQLite file to keep rooms, bans, identities and history in across restarts
+    #[arg(long, env = "RELAY_STORE")]
+    pub store: Option<PathBuf>,
+
+    /// Shared secret used to authenticate clients
+    #[arg(long, env = "RELAY_AUTH_SECRET", hide_env_values = true)]
+    pub auth_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Apply the store retention settings, reclaim the space and exit
+    Compact,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("could not parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid configuration:\n  - {}", .0.join("\n  - "))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub websocket: WebSocketConfig,
+    pub http: HttpConfig,
+    pub metrics: MetricsConfig,
+    pub tls: TlsConfig,
+    pub limits: LimitsConfig,
+    pub history: HistoryConfig,
+    pub store: StoreConfig,
+    pub mailbox: MailboxConfig,
+    pub qos: QosConfig,
+    pub dedup: DedupConfig,
+    pub compression: CompressionConfig,
+    pub state: StateConfig,
+    pub locks: LocksConfig,
+    pub barriers: BarriersConfig,
+    pub tasks: TasksConfig,
+    pub logging: LoggingConfig,
+    pub auth: AuthConfig,
+    pub shutdown: ShutdownConfig,
+    pub rooms: Vec<RoomConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `tcp = false` turns the TCP listener off
+    #[serde(deserialize_with = "addr_or_false")]
+    pub tcp: Option<SocketAddr>,
+    pub unix: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tcp: Some(DEFAULT_TCP_ADDR.parse().unwrap()),
+            unix: None,
+        }
+    }
+}
+
+fn addr_or_false<'de, D>(deserializer: D) -> Result<Option<SocketAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum AddrOrBool {
+        Addr(SocketAddr),
+        Enabled(bool),
+    }
+
+    match AddrOrBool::deserialize(deserializer)? {
+        AddrOrBool::Addr(addr) => Ok(Some(addr)),
+        AddrOrBool::Enabled(true) => Ok(Some(DEFAULT_TCP_ADDR.parse().unwrap())),
+        AddrOrBool::Enabled(false) => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// not set by default, which leaves the WebSocket endpoint off
+    pub bind: Option<SocketAddr>,
+    /// upgrade requests for any other path are answered with 404
+    pub path: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            path: "/relay".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// not set by default, which leaves the admin API off
+    pub bind: Option<SocketAddr>,
+    /// the API has no authentication, so binding it anywhere but loopback needs this
+    pub allow_remote: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// not set by default, the admin API serves /metrics as well
+    pub bind: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, TLS is off unless this and key are set
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// PEM CA bundle client certificates are checked against
+    pub client_ca: Option<PathBuf>,
+    /// refuse clients without a certificate signed by client_ca
+    pub require_client_cert: bool,
+    /// client certificate common name -> identity, unlisted names are used as they are
+    pub identities: HashMap<String, String>,
+    /// which listeners use TLS once it is on
+    pub tcp: bool,
+    pub websocket: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: None,
+            key: None,
+            client_ca: None,
+            require_client_cert: false,
+            identities: HashMap::new(),
+            tcp: true,
+            websocket: true,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.cert.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_clients: usize,
+    pub max_frame_bytes: usize,
+    pub max_rooms_per_client: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_clients: 1024,
+            max_frame_bytes: 64 * 1024,
+            max_rooms_per_client: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// 0 keeps no history, and Retransmit is Nacked
+    pub room_messages: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    /// SQLite file, not set by default which keeps everything in memory only
+    pub path: Option<PathBuf>,
+    /// history kept per room on disk, 0 for no limit
+    pub history_per_room: usize,
+    /// history older than this is removed by compaction, 0 keeps it forever
+    pub history_max_age_secs: u64,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            history_per_room: 1000,
+            history_max_age_secs: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailboxConfig {
+    /// direct messages kept per offline identity, the oldest go first, 0 turns mailboxes off
+    pub max_messages: usize,
+    /// queued messages older than this are dropped instead of delivered, 0 keeps them forever
+    pub max_age_secs: u64,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 100,
+            max_age_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QosConfig {
+    /// how long an AtLeastOnce message waits for its Ack before it is sent again
+    pub retry_interval_ms: u64,
+    /// sends before giving up and telling the sender with DeliveryFailed, offline recipients use
+    /// them up as well
+    pub max_attempts: u32,
+    /// messages one recipient can owe an Ack for, more for it are refused with a Nack
+    pub max_pending: usize,
+}
+
+impl Default for QosConfig {
+    fn default() -> Self {
+        Self {
+            retry_interval_ms: 2000,
+            max_attempts: 5,
+            max_pending: 256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    /// how long a message id is remembered per sender, 0 turns deduplication off
+    pub window_secs: u64,
+    /// ids remembered per sender, the oldest are forgotten first past this
+    pub max_ids: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            max_ids: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// codecs clients may negotiate, the client's preference decides between them, empty turns
+    /// compression off
+    pub codecs: Vec<Compression>,
+    /// frames shorter than this are sent as they are, compressing them costs more than it saves
+    pub min_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codecs: vec![Compression::Zstd, Compression::Deflate],
+            min_bytes: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /// keys of shared state a room can hold, StateSet of a new key fails past this
+    pub max_keys_per_room: usize,
+    /// longest value StateSet accepts, in bytes
+    pub max_value_bytes: usize,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            max_keys_per_room: 256,
+            max_value_bytes: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocksConfig {
+    /// longest lease LockAcquire can ask for, holders renew to keep a lock longer
+    pub max_ttl_ms: u64,
+}
+
+impl Default for LocksConfig {
+    fn default() -> Self {
+        Self {
+            max_ttl_ms: 5 * 60 * 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BarriersConfig {
+    /// longest timeout BarrierWait can ask for
+    pub max_timeout_ms: u64,
+}
+
+impl Default for BarriersConfig {
+    fn default() -> Self {
+        Self {
+            max_timeout_ms: 10 * 60 * 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TasksConfig {
+    /// most steps TaskDefine can give a task
+    pub max_steps: usize,
+    /// how long a member can stay behind before RequestCurrentTaskStep asks where it is, and
+    /// again after that, 0 never asks
+    pub poll_after_ms: u64,
+}
+
+impl Default for TasksConfig {
+    fn default() -> Self {
+        Self {
+            max_steps: 64,
+            poll_after_ms: 30 * 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// a level, or comma separated target=level directives with an optional default level
+    pub level: String,
+    pub format: LogFormat,
+    /// log what clients send, off by default since a raid sends a lot
+    pub payloads: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            payloads: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// one JSON object per line, for log shippers
+    Json,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// refuse clients that do not authenticate with a token, the secret or a client certificate
+    pub required: bool,
+    /// HMAC secret shared with the clients
+    pub secret: Option<String>,
+    /// static token -> identity it authenticates as
+    pub tokens: HashMap<String, String>,
+    /// identities allowed to kick, ban, mute and close rooms
+    pub admins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// sent to clients in ServerShuttingDown
+    pub reconnect_after_secs: u64,
+    /// how long queued messages get to drain before the relay exits anyway
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            reconnect_after_secs: 5,
+            drain_timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    pub name: String,
+    /// identity allowed to invite, mute and skip the password
+    pub owner: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub invite_only: bool,
+    /// identities let in without the password, and past invite_only
+    #[serde(default)]
+    pub invited: Vec<String>,
+    /// identities that receive but can not send
+    #[serde(default)]
+    pub read_only: Vec<String>,
+    /// keeps one member elected leader, see election
+    #[serde(default)]
+    pub elect_leader: bool,
+    /// identity to priority in the election, the highest leads and unlisted members have 0
+    #[serde(default)]
+    pub leader_priority: HashMap<String, i64>,
+}
+
+/// Every directive has to name a level, so a typo like "inof" is an error rather than a target
+fn valid_log_directives(directives: &str) -> bool {
+    let is_level = |level: &str| LOG_LEVELS.contains(&level.trim().to_lowercase().as_str());
+    !directives.trim().is_empty()
+        && directives
+            .split(',')
+            .all(|directive| match directive.split_once('=') {
+                Some((target, level)) => !target.trim().is_empty() && is_level(level),
+                None => is_level(directive),
+            })
+}
+
+impl Config {
+    /// Builds the effective configuration from the command line and validates it
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_overrides(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    pub fn apply_overrides(&mut self, cli: &Cli) {
+        if let Some(tcp) = cli.tcp {
+            self.server.tcp = Some(tcp);
+        }
+        if cli.no_tcp {
+            self.server.tcp = None;
+        }
+        if let Some(unix) = &cli.unix {
+            self.server.unix = Some(unix.clone());
+        }
+        if let Some(websocket) = cli.websocket {
+            self.websocket.bind = Some(websocket);
+        }
+        if let Some(http) = cli.http {
+            self.http.bind = Some(http);
+        }
+        if let Some(metrics) = cli.metrics {
+            self.metrics.bind = Some(metrics);
+        }
+        if let Some(cert) = &cli.tls_cert {
+            self.tls.cert = Some(cert.clone());
+        }
+        if let Some(key) = &cli.tls_key {
+            self.tls.key = Some(key.clone());
+        }
+        if let Some(max_clients) = cli.max_clients {
+            self.limits.max_clients = max_clients;
+        }
+        if let Some(max_frame_bytes) = cli.max_frame_bytes {
+            self.limits.max_frame_bytes = max_frame_bytes;
+        }
+        if let Some(max_rooms) = cli.max_rooms_per_client {
+            self.limits.max_rooms_per_client = max_rooms;
+        }
+        if let Some(history) = cli.history {
+            self.history.room_messages = history;
+        }
+        if let Some(store) = &cli.store {
+            self.store.path = Some(store.clone());
+        }
+        if let Some(level) = &cli.log_level {
+            self.logging.level = level.clone();
+        }
+        if let Some(format) = cli.log_format {
+            self.logging.format = format;
+        }
+        if cli.log_payloads {
+            self.logging.payloads = true;
+        }
+        if let Some(secret) = &cli.auth_secret {
+            self.auth.secret = Some(secret.clone());
+        }
+    }
+
+    /// Collects every problem instead of stopping at the first so they can all be fixed in one go
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.server.tcp.is_none() && self.server.unix.is_none() && self.websocket.bind.is_none()
+        {
+            problems
+                .push("no listeners, set server.tcp, server.unix or websocket.bind".to_string());
+        }
+        if let Some(bind) = self.http.bind {
+            if !bind.ip().is_loopback() && !self.http.allow_remote {
+                problems.push(format!(
+                    "http.bind {} is not a loopback address, set http.allow_remote to expose the admin API",
+                    bind
+                ));
+            }
+        }
+        if !self.websocket.path.starts_with('/') {
+            problems.push(format!(
+                "websocket.path '{}' must start with /",
+                self.websocket.path
+            ));
+        }
+        if self.server.unix.is_some() && !cfg!(unix) {
+            problems.push("server.unix is not supported on this platform".to_string());
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            problems.push("tls.cert and tls.key must be set together".to_string());
+        }
+        if self.tls.require_client_cert && self.tls.client_ca.is_none() {
+            problems.push("tls.require_client_cert needs tls.client_ca".to_string());
+        }
+        if self.tls.client_ca.is_some() && !self.tls.enabled() {
+            problems.push("tls.client_ca is set but tls.cert is not".to_string());
+        }
+        for (name, path) in [
+            ("tls.cert", &self.tls.cert),
+            ("tls.key", &self.tls.key),
+            ("tls.client_ca", &self.tls.client_ca),
+        ] {
+            if let Some(path) = path {
+                if !path.is_file() {
+                    problems.push(format!("{} {} does not exist", name, path.display()));
+                }
+            }
+        }
+        if self.limits.max_clients == 0 {
+            problems.push("limits.max_clients must be at least 1".to_string());
+        }
+        if self.limits.max_frame_bytes < 64 {
+            problems.push("limits.max_frame_bytes must be at least 64".to_string());
+        }
+        if self.limits.max_rooms_per_client == 0 {
+            problems.push("limits.max_rooms_per_client must be at least 1".to_string());
+        }
+        if self.store.history_per_room != 0
+            && self.store.history_per_room < self.history.room_messages
+        {
+            problems.push(format!(
+                "store.history_per_room {} is less than history.room_messages {}",
+                self.store.history_per_room, self.history.room_messages
+            ));
+        }
+        if self.qos.retry_interval_ms < 10 {
+            problems.push("qos.retry_interval_ms must be at least 10".to_string());
+        }
+        if self.qos.max_attempts == 0 {
+            problems.push("qos.max_attempts must be at least 1".to_string());
+        }
+        if self.qos.max_pending == 0 {
+            problems.push("qos.max_pending must be at least 1".to_string());
+        }
+        if self.dedup.window_secs > 0 && self.dedup.max_ids == 0 {
+            problems.push("dedup.max_ids must be at least 1 while dedup is on".to_string());
+        }
+        if !self.compression.codecs.is_empty()
+            && self.compression.min_bytes >= self.limits.max_frame_bytes
+        {
+            problems.push(format!(
+                "compression.min_bytes {} must be less than limits.max_frame_bytes {}",
+                self.compression.min_bytes, self.limits.max_frame_bytes
+            ));
+        }
+        if self.state.max_keys_per_room == 0 {
+            problems.push("state.max_keys_per_room must be at least 1".to_string());
+        }
+        if self.state.max_value_bytes == 0 {
+            problems.push("state.max_value_bytes must be at least 1".to_string());
+        }
+        if self.locks.max_ttl_ms == 0 {
+            problems.push("locks.max_ttl_ms must be at least 1".to_string());
+        }
+        if self.barriers.max_timeout_ms == 0 {
+            problems.push("barriers.max_timeout_ms must be at least 1".to_string());
+        }
+        if self.tasks.max_steps == 0 {
+            problems.push("tasks.max_steps must be at least 1".to_string());
+        }
+        if self.shutdown.drain_timeout_secs == 0 {
+            problems.push("shutdown.drain_timeout_secs must be at least 1".to_string());
+        }
+        if !valid_log_directives(&self.logging.level) {
+            problems.push(format!(
+                "logging.level '{}' is not a level or a list of target=level, levels are {}",
+                self.logging.level,
+                LOG_LEVELS.join(", ")
+            ));
+        }
+        if let Some(secret) = &self.auth.secret {
+            if secret.len() < MIN_SECRET_LEN {
+                problems.push(format!(
+                    "auth.secret must be at least {} characters",
+                    MIN_SECRET_LEN
+                ));
+            }
+        }
+        if self.auth.required
+            && self.auth.secret.is_none()
+            && self.auth.tokens.is_empty()
+            && self.tls.client_ca.is_none()
+        {
+            problems.push(
+                "auth.required needs auth.secret, auth.tokens or tls.client_ca to authenticate with"
+                    .to_string(),
+            );
+        }
+        if self.auth.admins.iter().any(|admin| admin.is_empty()) {
+            problems.push("auth.admins has an empty identity".to_string());
+        }
+        for (token, identity) in &self.auth.tokens {
+            if token.is_empty() {
+                problems.push(format!("auth.tokens has an empty token for '{}'", identity));
+            }
+            if identity.is_empty() {
+                problems.push("auth.tokens maps a token to an empty identity".to_string());
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for room in &self.rooms {
+            if room.name.trim().is_empty() {
+                problems.push("rooms entries need a non-empty name".to_string());
+            } else if !seen.insert(&room.name) {
+                problems.push(format!("room '{}' is configured more than once", room.name));
+            }
+            if room.password.as_deref() == Some("") {
+                problems.push(format!("room '{}' has an empty password", room.name));
+            }
+            if room.invite_only && room.owner.is_none() && room.invited.is_empty() {
+                problems.push(format!(
+                    "room '{}' is invite_only but has no owner or invited identities",
+                    room.name
+                ));
+            }
+            if !room.leader_priority.is_empty() && !room.elect_leader {
+                problems.push(format!(
+                    "room '{}' has leader_priority but does not elect_leader",
+                    room.name
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
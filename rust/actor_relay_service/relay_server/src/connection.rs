@@ -0,0 +1,185 @@
+use crate::protocol::Identity;
+use crate::server::Server;
+use crate::session::{Flow, Session};
+use crate::AnyResult;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn, Instrument};
+
+/// Anything the relay can accept byte stream connections from
+pub trait Accept: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    /// Waits for the next connection, returning it with a printable peer address
+    fn accept_stream(&self) -> impl Future<Output = io::Result<(Self::Stream, String)>> + Send;
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept_stream(&self) -> io::Result<(TcpStream, String)> {
+        let (stream, peer) = self.accept().await?;
+        Ok((stream, peer.to_string()))
+    }
+}
+
+#[cfg(unix)]
+impl Accept for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept_stream(&self) -> io::Result<(Self::Stream, String)> {
+        let (stream, _) = self.accept().await?;
+        let peer = match stream.peer_cred() {
+            Ok(cred) => format!("unix pid {:?} uid {}", cred.pid(), cred.uid()),
+            Err(_) => "unix socket".to_string(),
+        };
+        Ok((stream, peer))
+    }
+}
+
+/// Binds a unix socket, clearing out a socket file left behind by a previous run
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+/// Accepts connections until shutdown starts, spawning a task per client
+pub async fn run<L: Accept>(listener: L, server: Arc<Mutex<Server>>) -> AnyResult {
+    run_with(listener, server, serve_stream).await
+}
+
+/// Like run, with `serve` deciding what protocol the accepted streams speak
+pub async fn run_with<L, F, Fut>(listener: L, server: Arc<Mutex<Server>>, serve: F) -> AnyResult
+where
+    L: Accept,
+    F: Fn(L::Stream, Arc<Mutex<Server>>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let shutdown = server.lock().await.shutdown.clone();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept_stream() => accepted?,
+            _ = shutdown.stop_accepting.cancelled() => {
+                info!("No longer accepting connections");
+                return Ok(());
+            }
+        };
+        info!("Received connection attempt from client {}", peer);
+
+        let server = Arc::clone(&server);
+        {
+            let server = server.lock().await;
+            if server.clients.len() >= server.config.limits.max_clients {
+                warn!(
+                    "Refusing connection from {}, already at {} clients",
+                    peer, server.config.limits.max_clients
+                );
+                continue;
+            }
+        }
+
+        // Spawn task for each client connection
+        shutdown.tasks.spawn(serve(stream, server));
+    }
+}
+
+/// Runs the newline delimited protocol over any byte stream
+pub async fn serve_stream<S>(stream: S, server: Arc<Mutex<Server>>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    serve_stream_as(stream, server, None).await
+}
+
+/// serve_stream for transports that already know who is on the other end
+pub async fn serve_stream_as<S>(stream: S, server: Arc<Mutex<Server>>, identity: Option<Identity>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (max_frame_bytes, shutdown) = {
+        let server = server.lock().await;
+        (
+            server.config.limits.max_frame_bytes,
+            server.shutdown.clone(),
+        )
+    };
+    let (mut session, mut rx) = Session::open(server, identity).await;
+    let clientId = session.clientId;
+    let metrics = Arc::clone(&session.metrics);
+    let codec = session.codec.clone();
+
+    let span = session.span.clone();
+
+    // Task that writes queued messages to the client. It owns the write half and runs until every
+    // sender is gone, so whatever was queued before the client was removed still goes out
+    shutdown.tasks.spawn(
+        async move {
+            while let Some(message) = rx.recv().await {
+                let message = codec.encode(message);
+                if let Err(e) = writer.write_all(message.as_bytes()).await {
+                    // dropping rx makes the next send to this client fail, which gets it removed
+                    error!("Failed to write message to client {}: {}", clientId, e);
+                    metrics.dropped();
+                    return;
+                }
+                metrics.sent(message.len());
+            }
+            if let Err(e) = writer.shutdown().await {
+                warn!("Failed to close connection to client {}: {}", clientId, e);
+            }
+        }
+        .instrument(span.clone()),
+    );
+
+    async move {
+        let mut buf = vec![];
+        let mut reader = tokio::io::BufReader::new(reader);
+
+        loop {
+            buf.clear();
+            // one byte over the limit is enough to tell an oversized frame apart
+            let mut limited = (&mut reader).take(max_frame_bytes as u64 + 1);
+            let read = tokio::select! {
+                read = limited.read_until(b'\n', &mut buf) => read,
+                _ = session.closed.cancelled() => break,
+            };
+            match read {
+                Ok(0) => {
+                    info!("Client {} closed the connection", clientId);
+                    break;
+                }
+                Ok(_) if buf.len() > max_frame_bytes => {
+                    error!(
+                        "Client {} sent a frame over {} bytes",
+                        clientId, max_frame_bytes
+                    );
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to read from client {}: {}", clientId, e);
+                    break;
+                }
+            }
+
+            if session.handle_frame(&String::from_utf8_lossy(&buf)).await == Flow::Close {
+                break;
+            }
+        }
+
+        session.close().await;
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,34 @@
+use crate::server::Server;
+use crate::AnyResult;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/* Whatever the relay has to do at a given time rather than in answer to a frame: leases that run
+    out, barriers that time out and task stragglers to ask where they are. One task sleeps until
+    the earliest of them, woken early through Server::deadlines_changed when a new one comes up.
+*/
+
+/// Runs everything that falls due until shutdown stops the listeners
+pub async fn run(server: Arc<Mutex<Server>>) -> AnyResult {
+    let (stop, changed) = {
+        let server = server.lock().await;
+        (
+            server.shutdown.stop_accepting.clone(),
+            Arc::clone(&server.deadlines_changed),
+        )
+    };
+    loop {
+        let next = server.lock().await.expire_deadlines(Instant::now());
+        // nothing falls due until something new says otherwise
+        let sleep = match next {
+            Some(next) => tokio::time::sleep_until(next.into()),
+            None => tokio::time::sleep(Duration::from_secs(3600)),
+        };
+        tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            _ = changed.notified() => {}
+            _ = sleep => {}
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/* Publisher side deduplication.
+    A client can put an id on Message and DirectMessage, and a retry of a send it is unsure about
+    carries the same one. The relay remembers the ids each sender used within dedup.window_secs and
+    answers a repeat with Duplicate instead of relaying it again.
+*/
+
+/// The ids one sender used recently, oldest first
+#[derive(Debug, Default)]
+pub struct SeenIds {
+    order: VecDeque<(Instant, String)>,
+    ids: HashSet<String>,
+}
+
+impl SeenIds {
+    /// Whether the id was used within the window, forgets whatever fell out of it on the way
+    pub fn seen(&mut self, id: &str, now: Instant, window: Duration) -> bool {
+        while let Some((seen, _)) = self.order.front() {
+            if now.duration_since(*seen) < window {
+                break;
+            }
+            if let Some((_, old)) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        self.ids.contains(id)
+    }
+
+    /// Notes an id once the message carrying it went through
+    pub fn remember(&mut self, id: &str, now: Instant, max_ids: usize) {
+        if !self.ids.insert(id.to_string()) {
+            return;
+        }
+        self.order.push_back((now, id.to_string()));
+        while self.order.len() > max_ids {
+            if let Some((_, old)) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
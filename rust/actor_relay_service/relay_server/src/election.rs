@@ -0,0 +1,78 @@
+use crate::config::RoomConfig;
+use crate::protocol::{ClientId, Identity};
+use std::collections::HashMap;
+
+/* Leader election for rooms with elect_leader set under [[rooms]].
+    The leader is the member with the highest leader_priority, among equals the one that joined
+    first. Members without an identity listed there have priority 0. The rule always holds, so a
+    member that outranks the leader takes over as it joins, and the next in line does when the
+    leader leaves or disconnects.
+    Every member gets LeaderChanged when it joins and whenever the leader changes. term counts the
+    changes, so whatever follows the leader's orders can ignore those of a leader that was replaced.
+    Candidates are connections, an identity on two boxes is two candidates.
+*/
+
+#[derive(Debug, Clone, Default)]
+pub struct Election {
+    pub priorities: HashMap<Identity, i64>,
+    // members and their priority, in the order they joined
+    members: Vec<(ClientId, i64)>,
+    pub leader: Option<ClientId>,
+    pub term: u64,
+}
+
+impl Election {
+    /// The election a [[rooms]] entry asks for, None when it does not elect a leader
+    pub fn from_config(room: &RoomConfig) -> Option<Self> {
+        room.elect_leader.then(|| Self {
+            priorities: room
+                .leader_priority
+                .iter()
+                .map(|(identity, priority)| (Identity(identity.clone()), *priority))
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    /// Adds a candidate, returns the new leader when that changed it
+    pub fn join(&mut self, clientId: ClientId, identity: Option<&Identity>) -> Option<ClientId> {
+        if !self.members.iter().any(|(member, _)| *member == clientId) {
+            let priority = identity
+                .and_then(|identity| self.priorities.get(identity))
+                .copied()
+                .unwrap_or(0);
+            self.members.push((clientId, priority));
+        }
+        self.elect()
+    }
+
+    /// Removes a candidate, returns the new leader when that changed it
+    pub fn leave(&mut self, clientId: &ClientId) -> Option<ClientId> {
+        self.members.retain(|(member, _)| member != clientId);
+        self.elect()
+    }
+
+    /// Forgets the candidates, for a room that was closed
+    pub fn clear(&mut self) {
+        self.members.clear();
+        self.leader = None;
+    }
+
+    fn elect(&mut self) -> Option<ClientId> {
+        let mut best: Option<(ClientId, i64)> = None;
+        for &(member, priority) in &self.members {
+            // strictly higher, so the earlier of equals stays ahead
+            if best.is_none_or(|(_, best)| priority > best) {
+                best = Some((member, priority));
+            }
+        }
+        let elected = best.map(|(member, _)| member);
+        if elected == self.leader {
+            return None;
+        }
+        self.leader = elected;
+        let leader = elected?;
+        self.term += 1;
+        Some(leader)
+    }
+}
@@ -0,0 +1,75 @@
+use crate::protocol::{ClientId, Identity, Recipient, Room};
+use thiserror::Error;
+
+/// Why the relay refused a client operation, the Display text is what goes out in Nack
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum OperationError {
+    #[error("not authenticated, send ConnectAttempt with credentials first")]
+    NotAuthenticated,
+    #[error("no authentication challenge is outstanding")]
+    NoChallenge,
+    #[error("authenticate with an identity first")]
+    IdentityRequired,
+    #[error("room {0} already exists")]
+    RoomExists(Room),
+    #[error("only the owner of room {0} can do that")]
+    NotOwner(Room),
+    #[error("wrong or missing password for room {0}")]
+    WrongPassword(Room),
+    #[error("room {0} is invite only")]
+    NotInvited(Room),
+    #[error("already in {0} rooms, leave one first")]
+    TooManyRooms(usize),
+    #[error("not a member of room {0}")]
+    NotMember(Room),
+    #[error("read only in room {0}")]
+    ReadOnly(Room),
+    #[error("muted in room {0}")]
+    Muted(Room),
+    #[error("only admins can do that")]
+    NotAdmin,
+    #[error("no client {0}")]
+    NoSuchClient(ClientId),
+    #[error("no room {0}")]
+    NoSuchRoom(Room),
+    #[error("bad range {0}..={1}")]
+    BadRange(u64, u64),
+    #[error("the relay keeps no history to retransmit from, history.room_messages is 0")]
+    NoHistory,
+    #[error("{0} is not connected and has no mailbox")]
+    NoMailbox(Identity),
+    #[error("{0} already owes an Ack for {1} messages")]
+    TooManyPending(Recipient, usize),
+    #[error("{key} is at version {actual}, not {expected}")]
+    VersionMismatch {
+        key: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("room {0} can not hold more than {1} keys")]
+    TooManyKeys(Room, usize),
+    #[error("values are limited to {0} bytes")]
+    ValueTooLarge(usize),
+    #[error("ttl_ms must be between 1 and {0}")]
+    BadTtl(u64),
+    #[error("not holding or waiting for lock {0}")]
+    NotLockHolder(String),
+    #[error("timeout_ms must be between 1 and {0}")]
+    BadTimeout(u64),
+    #[error("a barrier needs at least one participant")]
+    NoParticipants,
+    #[error("barrier {0} is already waiting for different participants")]
+    BarrierMismatch(String),
+    #[error("barrier {0} is not waiting for you")]
+    NotParticipant(String),
+    #[error("no task {0}")]
+    NoSuchTask(String),
+    #[error("a task needs at least one step")]
+    NoSteps,
+    #[error("tasks are limited to {0} steps")]
+    TooManySteps(usize),
+    #[error("task {0} has only {1} steps")]
+    NoSuchStep(String, usize),
+    #[error("a Batch can not contain another Batch")]
+    NestedBatch,
+}
@@ -0,0 +1,190 @@
+use crate::outbox::Delivery;
+use crate::protocol::{Channel, ClientId, Identity, Message, Priority, Room, ServerOperation};
+use crate::server::Server;
+use crate::AnyResult;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/* Admin API for ops scripts, a JSON view over the same Server the sessions use.
+    It has no authentication of its own and is meant to sit on a loopback address, see
+    http.allow_remote.
+*/
+
+type Shared = Arc<Mutex<Server>>;
+
+// who kicked shows up as this in the Kicked notice
+const OPERATOR: &str = "operator";
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientInfo {
+    pub clientId: ClientId,
+    pub identity: Option<Identity>,
+    pub rooms: Vec<Room>,
+    // frames waiting for the client's writer
+    pub queued: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct RoomInfo {
+    pub room: Room,
+    pub members: Vec<ClientId>,
+    pub owner: Option<Identity>,
+    // has an acl, see room::RoomAcl
+    pub private: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Broadcast {
+    pub channel: Channel,
+    pub message: Message,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub ttl_ms: Option<u64>,
+}
+
+pub fn router(server: Shared) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/clients", get(list_clients))
+        .route("/clients/{clientId}/kick", post(kick))
+        .route("/rooms", get(list_rooms))
+        .route("/rooms/{room}/broadcast", post(broadcast))
+        .with_state(server)
+}
+
+/// Just /metrics, for a scraper that should not reach the rest of the API
+pub fn metrics_router(server: Shared) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(server)
+}
+
+/// Serves the API until shutdown stops the listeners
+pub async fn serve(listener: TcpListener, server: Shared) -> AnyResult {
+    serve_router(listener, Arc::clone(&server), router(server)).await
+}
+
+pub async fn serve_metrics(listener: TcpListener, server: Shared) -> AnyResult {
+    serve_router(listener, Arc::clone(&server), metrics_router(server)).await
+}
+
+async fn serve_router(listener: TcpListener, server: Shared, router: Router) -> AnyResult {
+    let stop_accepting = server.lock().await.shutdown.stop_accepting.clone();
+    axum::serve(listener, router)
+        .with_graceful_shutdown(stop_accepting.cancelled_owned())
+        .await?;
+    Ok(())
+}
+
+fn not_found(what: String) -> Response {
+    (StatusCode::NOT_FOUND, Json(json!({ "error": what }))).into_response()
+}
+
+async fn metrics(State(server): State<Shared>) -> Response {
+    let server = server.lock().await;
+    match server.metrics.render(&server) {
+        Ok(text) => (
+            [(
+                axum::http::header::CONTENT_TYPE,
+                "text/plain; version=0.0.4; charset=utf-8",
+            )],
+            text,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn list_clients(State(server): State<Shared>) -> Json<Vec<ClientInfo>> {
+    let server = server.lock().await;
+    let mut clients: Vec<ClientInfo> = server
+        .clients
+        .values()
+        .map(|client| {
+            let mut rooms: Vec<Room> = server
+                .rooms
+                .iter()
+                .filter(|(_, members)| members.contains(&client.clientId))
+                .map(|(room, _)| room.clone())
+                .collect();
+            rooms.sort_by(|a, b| a.0.cmp(&b.0));
+            ClientInfo {
+                clientId: client.clientId,
+                identity: client.identity.clone(),
+                rooms,
+                queued: client.tx.depth(),
+            }
+        })
+        .collect();
+    clients.sort_by_key(|client| client.clientId.get());
+    Json(clients)
+}
+
+async fn list_rooms(State(server): State<Shared>) -> Json<Vec<RoomInfo>> {
+    let server = server.lock().await;
+    let mut rooms: Vec<RoomInfo> = server
+        .rooms
+        .iter()
+        .map(|(room, members)| {
+            let mut members: Vec<ClientId> = members.iter().copied().collect();
+            members.sort_by_key(|clientId| clientId.get());
+            let acl = server.acls.get(room);
+            RoomInfo {
+                room: room.clone(),
+                members,
+                owner: acl.and_then(|acl| acl.owner.clone()),
+                private: acl.is_some(),
+            }
+        })
+        .collect();
+    rooms.sort_by(|a, b| a.room.0.cmp(&b.room.0));
+    Json(rooms)
+}
+
+async fn kick(State(server): State<Shared>, Path(clientId): Path<ClientId>) -> Response {
+    let notice = ServerOperation::Kicked {
+        by: Identity(OPERATOR.to_string()),
+    };
+    if server.lock().await.kick(&clientId, &notice) {
+        warn!("Client {} kicked through the admin API", clientId);
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        not_found(format!("no client {}", clientId))
+    }
+}
+
+async fn broadcast(
+    State(server): State<Shared>,
+    Path(room): Path<Room>,
+    Json(body): Json<Broadcast>,
+) -> Response {
+    let mut server = server.lock().await;
+    if !server.rooms.contains_key(&room) {
+        return not_found(format!("no room {}", room));
+    }
+    let seq = server.next_seq(&room, &body.channel);
+    let operation = ServerOperation::RoomMessage {
+        sender: ClientId::RELAY,
+        room: room.clone(),
+        channel: body.channel,
+        message: body.message,
+        seq,
+    };
+    server.record_history(&room, &operation);
+    let delivered = server.broadcast(&room, &operation, Delivery::new(body.priority, body.ttl_ms));
+    info!(
+        "Broadcast to {} members of {} through the admin API",
+        delivered, room
+    );
+    Json(json!({ "delivered": delivered })).into_response()
+}
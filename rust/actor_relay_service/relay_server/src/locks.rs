@@ -0,0 +1,129 @@
+use crate::protocol::ClientId;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/* Named locks per room, held on a lease.
+    LockAcquire either grants the lock or queues the client behind whoever holds it, first come
+    first served. The holder keeps it until LockRelease, until its connection goes away or until
+    the lease runs out, whichever comes first, and the next in line gets it. Acquiring a lock you
+    already hold renews the lease.
+    Every grant carries a fencing token that only grows, so whatever the holder acts on can refuse
+    a token older than the last one it saw from a holder whose lease ran out unnoticed. Tokens are
+    counted per room and the last one is stored, so they keep growing across restarts and a
+    CloseRoom. Locks themselves are tied to connections and only live in memory.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub holder: ClientId,
+    pub token: u64,
+    pub ttl: Duration,
+    pub expires: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Waiter {
+    pub client: ClientId,
+    pub ttl: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acquired {
+    Granted(Lease),
+    // the holder asked again, its lease starts over
+    Renewed(Lease),
+    // waiting behind the holder and this many others
+    Queued(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Released {
+    // the next waiter, if there was one, holds it now
+    Held(Option<Lease>),
+    Waiting,
+    NotHeld,
+}
+
+/// A lock's holder and queue. The methods that can grant it take the room's last fencing token
+/// and count it on
+#[derive(Debug, Default)]
+pub struct Lock {
+    pub lease: Option<Lease>,
+    pub waiters: VecDeque<Waiter>,
+}
+
+impl Lock {
+    pub fn acquire(
+        &mut self,
+        client: ClientId,
+        ttl: Duration,
+        now: Instant,
+        tokens: &mut u64,
+    ) -> Acquired {
+        match &mut self.lease {
+            None => Acquired::Granted(self.grant(client, ttl, now, tokens)),
+            Some(lease) if lease.holder == client => {
+                lease.ttl = ttl;
+                lease.expires = now + ttl;
+                Acquired::Renewed(*lease)
+            }
+            Some(_) => {
+                let position = match self.waiters.iter().position(|w| w.client == client) {
+                    Some(position) => {
+                        self.waiters[position].ttl = ttl;
+                        position
+                    }
+                    None => {
+                        self.waiters.push_back(Waiter { client, ttl });
+                        self.waiters.len() - 1
+                    }
+                };
+                Acquired::Queued(position)
+            }
+        }
+    }
+
+    /// Nobody holds it or waits for it, so there is nothing left to keep
+    pub fn is_idle(&self) -> bool {
+        self.lease.is_none() && self.waiters.is_empty()
+    }
+
+    /// Lets go of the lock, or of the place in line for it
+    pub fn release(&mut self, client: ClientId, now: Instant, tokens: &mut u64) -> Released {
+        if self.lease.is_some_and(|lease| lease.holder == client) {
+            self.lease = None;
+            return Released::Held(self.grant_next(now, tokens));
+        }
+        let waiting = self.waiters.len();
+        self.waiters.retain(|waiter| waiter.client != client);
+        if self.waiters.len() < waiting {
+            Released::Waiting
+        } else {
+            Released::NotHeld
+        }
+    }
+
+    /// Takes the lease away once it ran out, returning it and the lease of the next holder
+    pub fn expire(&mut self, now: Instant, tokens: &mut u64) -> Option<(Lease, Option<Lease>)> {
+        let lease = self.lease.filter(|lease| lease.expires <= now)?;
+        self.lease = None;
+        Some((lease, self.grant_next(now, tokens)))
+    }
+
+    fn grant_next(&mut self, now: Instant, tokens: &mut u64) -> Option<Lease> {
+        let next = self.waiters.pop_front()?;
+        Some(self.grant(next.client, next.ttl, now, tokens))
+    }
+
+    fn grant(&mut self, holder: ClientId, ttl: Duration, now: Instant, tokens: &mut u64) -> Lease {
+        *tokens += 1;
+        let lease = Lease {
+            holder,
+            token: *tokens,
+            ttl,
+            expires: now + ttl,
+        };
+        self.lease = Some(lease);
+        lease
+    }
+}
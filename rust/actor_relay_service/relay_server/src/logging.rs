@@ -0,0 +1,51 @@
+use crate::config::{LogFormat, LoggingConfig};
+use crate::protocol::{ClientId, Identity};
+use std::io::IsTerminal;
+use tracing::field::Empty;
+use tracing::{info_span, Span, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/* tracing setup.
+    Every connection gets a "client" span carrying its ClientId and, once known, its identity, so
+    a line logged anywhere while handling that client says who it was about.
+*/
+
+/// Installs the subscriber for the whole process, logging to stdout. Records of the log crate,
+/// which rustls and tungstenite write, are forwarded to it as well
+pub fn init(config: &LoggingConfig) {
+    let ansi = std::io::stdout().is_terminal();
+    subscriber(config, std::io::stdout, ansi)
+        .try_init()
+        .expect("logging is only initialised once");
+}
+
+/// The subscriber init installs, writing wherever `writer` says, colored if `ansi`
+pub fn subscriber<W>(
+    config: &LoggingConfig,
+    writer: W,
+    ansi: bool,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    // config validation already checked the directives
+    let filter = EnvFilter::try_new(&config.level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    match config.format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    }
+}
+
+pub fn client_span(clientId: ClientId, identity: Option<&Identity>) -> Span {
+    let span = info_span!("client", clientId = %clientId, identity = Empty);
+    if let Some(identity) = identity {
+        span.record("identity", identity.as_str());
+    }
+    span
+}
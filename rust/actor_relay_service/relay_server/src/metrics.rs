@@ -0,0 +1,227 @@
+use crate::server::Server;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::time::Duration;
+
+/* Relay health in Prometheus text format, served as /metrics.
+    Counters only ever go up, graph them with rate() for per second figures. Gauges about clients
+    and rooms are read from the Server when scraped rather than kept up to date on every change.
+*/
+pub struct Metrics {
+    registry: Registry,
+    clients: IntGauge,
+    rooms: IntGauge,
+    room_members: IntGaugeVec,
+    mailbox_messages: IntGauge,
+    unacked_messages: IntGauge,
+    messages_in: IntCounter,
+    messages_out: IntCounter,
+    bytes_in: IntCounter,
+    bytes_out: IntCounter,
+    dropped: IntCounter,
+    expired: IntCounter,
+    redelivered: IntCounter,
+    delivery_failures: IntCounter,
+    duplicates: IntCounter,
+    compressed_frames: IntCounter,
+    compression_saved_bytes: IntCounter,
+    parse_errors: IntCounter,
+    fanout_seconds: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("relay".to_string()), None)
+            .expect("relay is a valid metric prefix");
+        let metrics = Self {
+            clients: IntGauge::new("clients", "Connected clients").unwrap(),
+            rooms: IntGauge::new("rooms", "Rooms the relay knows about").unwrap(),
+            room_members: IntGaugeVec::new(
+                Opts::new("room_members", "Members of each room"),
+                &["room"],
+            )
+            .unwrap(),
+            mailbox_messages: IntGauge::new(
+                "mailbox_messages",
+                "Direct messages waiting for an offline identity",
+            )
+            .unwrap(),
+            unacked_messages: IntGauge::new(
+                "unacked_messages",
+                "AtLeastOnce messages waiting for an Ack",
+            )
+            .unwrap(),
+            messages_in: IntCounter::new("messages_in_total", "Frames read from clients").unwrap(),
+            messages_out: IntCounter::new("messages_out_total", "Frames written to clients")
+                .unwrap(),
+            bytes_in: IntCounter::new("bytes_in_total", "Bytes of frames read from clients")
+                .unwrap(),
+            bytes_out: IntCounter::new("bytes_out_total", "Bytes of frames written to clients")
+                .unwrap(),
+            dropped: IntCounter::new(
+                "dropped_messages_total",
+                "Frames for clients whose queue or connection was already gone",
+            )
+            .unwrap(),
+            expired: IntCounter::new(
+                "expired_messages_total",
+                "Frames whose ttl ran out before they could be written",
+            )
+            .unwrap(),
+            redelivered: IntCounter::new(
+                "redelivered_messages_total",
+                "AtLeastOnce messages sent again for want of an Ack",
+            )
+            .unwrap(),
+            delivery_failures: IntCounter::new(
+                "delivery_failures_total",
+                "AtLeastOnce messages given up on",
+            )
+            .unwrap(),
+            duplicates: IntCounter::new(
+                "duplicate_messages_total",
+                "Messages dropped because their sender already used the id",
+            )
+            .unwrap(),
+            compressed_frames: IntCounter::new(
+                "compressed_frames_total",
+                "Frames sent compressed to clients that negotiated a codec",
+            )
+            .unwrap(),
+            compression_saved_bytes: IntCounter::new(
+                "compression_saved_bytes_total",
+                "Bytes compression kept off the wire",
+            )
+            .unwrap(),
+            parse_errors: IntCounter::new(
+                "parse_errors_total",
+                "Frames that were not a valid client message",
+            )
+            .unwrap(),
+            fanout_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "fanout_seconds",
+                    "Time to queue a room message for every member",
+                )
+                .buckets(prometheus::exponential_buckets(0.000_01, 4.0, 10).unwrap()),
+            )
+            .unwrap(),
+            registry,
+        };
+        metrics.register().expect("metric names are unique");
+        metrics
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry.register(Box::new(self.clients.clone()))?;
+        self.registry.register(Box::new(self.rooms.clone()))?;
+        self.registry
+            .register(Box::new(self.room_members.clone()))?;
+        self.registry
+            .register(Box::new(self.mailbox_messages.clone()))?;
+        self.registry.register(Box::new(self.messages_in.clone()))?;
+        self.registry
+            .register(Box::new(self.messages_out.clone()))?;
+        self.registry.register(Box::new(self.bytes_in.clone()))?;
+        self.registry.register(Box::new(self.bytes_out.clone()))?;
+        self.registry.register(Box::new(self.dropped.clone()))?;
+        self.registry.register(Box::new(self.expired.clone()))?;
+        self.registry
+            .register(Box::new(self.unacked_messages.clone()))?;
+        self.registry.register(Box::new(self.redelivered.clone()))?;
+        self.registry
+            .register(Box::new(self.delivery_failures.clone()))?;
+        self.registry.register(Box::new(self.duplicates.clone()))?;
+        self.registry
+            .register(Box::new(self.compressed_frames.clone()))?;
+        self.registry
+            .register(Box::new(self.compression_saved_bytes.clone()))?;
+        self.registry
+            .register(Box::new(self.parse_errors.clone()))?;
+        self.registry
+            .register(Box::new(self.fanout_seconds.clone()))?;
+        Ok(())
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.messages_in.inc();
+        self.bytes_in.inc_by(bytes as u64);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.messages_out.inc();
+        self.bytes_out.inc_by(bytes as u64);
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.inc();
+    }
+
+    pub fn expired(&self) {
+        self.expired.inc();
+    }
+
+    pub fn redelivered(&self) {
+        self.redelivered.inc();
+    }
+
+    pub fn delivery_failed(&self) {
+        self.delivery_failures.inc();
+    }
+
+    pub fn duplicate(&self) {
+        self.duplicates.inc();
+    }
+
+    pub fn compressed(&self, saved_bytes: usize) {
+        self.compressed_frames.inc();
+        self.compression_saved_bytes.inc_by(saved_bytes as u64);
+    }
+
+    pub fn parse_error(&self) {
+        self.parse_errors.inc();
+    }
+
+    pub fn fanout(&self, elapsed: Duration) {
+        self.fanout_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    /// Refreshes the gauges from the server and encodes everything
+    pub fn render(&self, server: &Server) -> anyhow::Result<String> {
+        self.clients.set(server.clients.len() as i64);
+        self.rooms.set(server.rooms.len() as i64);
+        self.unacked_messages.set(
+            server
+                .unacked
+                .values()
+                .map(|unacked| unacked.pending.len())
+                .sum::<usize>() as i64,
+        );
+        self.mailbox_messages.set(
+            server
+                .mailboxes
+                .values()
+                .map(|mailbox| mailbox.len())
+                .sum::<usize>() as i64,
+        );
+        // rooms come and go, so start from nothing rather than leave closed rooms behind
+        self.room_members.reset();
+        for (room, members) in &server.rooms {
+            self.room_members
+                .with_label_values(&[room.as_str()])
+                .set(members.len() as i64);
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,231 @@
+use crate::metrics::Metrics;
+use crate::protocol::Priority;
+use std::collections::VecDeque;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/* A client's queue of outgoing frames.
+    The session and the server push frames in, the transport's writer task takes them out. Frames
+    wait in one lane per Priority and the writer always empties the more urgent lanes first, so a
+    control command never sits behind a flood of bulk chatter. Frames past their expiry are
+    dropped when the writer gets to them instead of being written late.
+    Frames of one stream, a room's channel whose messages carry a seq, stay in the order they were
+    queued: an urgent one takes the earlier ones still waiting in slower lanes along to its own.
+    Both halves share a count of what is still waiting, which is how far behind a slow client is.
+*/
+pub fn channel() -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        lanes: Mutex::new(Lanes {
+            queues: Default::default(),
+            senders: 1,
+            receiving: true,
+        }),
+        ready: Notify::new(),
+        depth: AtomicUsize::new(0),
+    });
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver {
+            shared,
+            metrics: None,
+        },
+    )
+}
+
+/// How a frame is queued: which lane and until when it is still worth writing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Delivery {
+    pub priority: Priority,
+    pub expires: Option<Instant>,
+    // frames with the same stream are written in the order they were queued, whatever their lane
+    pub stream: Option<u64>,
+}
+
+impl Delivery {
+    /// A ttl too long for an Instant never expires
+    pub fn new(priority: Priority, ttl_ms: Option<u64>) -> Self {
+        Self {
+            priority,
+            expires: ttl_ms.and_then(|ttl| Instant::now().checked_add(Duration::from_millis(ttl))),
+            stream: None,
+        }
+    }
+
+    /// The same delivery, ordered with every other frame of `stream`
+    pub fn in_stream(self, stream: impl Hash) -> Self {
+        let mut hasher = DefaultHasher::new();
+        stream.hash(&mut hasher);
+        Self {
+            stream: Some(hasher.finish()),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("the client's queue is closed")]
+pub struct Closed;
+
+struct Queued {
+    frame: String,
+    expires: Option<Instant>,
+    stream: Option<u64>,
+}
+
+struct Lanes {
+    // indexed by Priority, most urgent first
+    queues: [VecDeque<Queued>; Priority::COUNT],
+    senders: usize,
+    receiving: bool,
+}
+
+struct Shared {
+    lanes: Mutex<Lanes>,
+    // woken on every push and when the last sender goes away
+    ready: Notify,
+    depth: AtomicUsize,
+}
+
+impl Shared {
+    fn lanes(&self) -> std::sync::MutexGuard<'_, Lanes> {
+        // nothing panics while holding it, but a poisoned queue is still a usable queue
+        self.lanes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl std::fmt::Debug for Shared {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shared")
+            .field("depth", &self.depth.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.shared.lanes().senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut lanes = self.shared.lanes();
+        lanes.senders -= 1;
+        if lanes.senders == 0 {
+            self.shared.ready.notify_one();
+        }
+    }
+}
+
+impl Sender {
+    /// Queues a frame in the normal lane, without an expiry
+    #[cfg(test)]
+    pub fn send(&self, frame: String) -> Result<(), Closed> {
+        self.send_with(frame, Delivery::default())
+    }
+
+    pub fn send_with(&self, frame: String, delivery: Delivery) -> Result<(), Closed> {
+        let mut lanes = self.shared.lanes();
+        if !lanes.receiving {
+            return Err(Closed);
+        }
+        let lane = delivery.priority.lane();
+        if let Some(stream) = delivery.stream {
+            // slower lanes only ever hold later frames of a stream than faster ones, so going
+            // through them in order keeps the stream's order
+            let mut earlier = Vec::new();
+            for queue in &mut lanes.queues[lane + 1..] {
+                if queue.iter().any(|queued| queued.stream == Some(stream)) {
+                    let (same, rest) = std::mem::take(queue)
+                        .into_iter()
+                        .partition(|queued| queued.stream == Some(stream));
+                    *queue = rest;
+                    earlier.extend::<VecDeque<_>>(same);
+                }
+            }
+            lanes.queues[lane].extend(earlier);
+        }
+        lanes.queues[lane].push_back(Queued {
+            frame,
+            expires: delivery.expires,
+            stream: delivery.stream,
+        });
+        self.shared.depth.fetch_add(1, Ordering::Relaxed);
+        drop(lanes);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Frames queued and not yet picked up by the writer
+    pub fn depth(&self) -> usize {
+        self.shared.depth.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+    // counts the frames that expired in the queue
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.shared.lanes().receiving = false;
+    }
+}
+
+impl Receiver {
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// The most urgent frame still worth writing, None once every sender is gone and the queue is
+    /// empty
+    pub async fn recv(&mut self) -> Option<String> {
+        loop {
+            if let Some(frame) = self.next() {
+                return frame;
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+
+    /// Some(frame) when there is something to do, None when the caller has to wait
+    fn next(&self) -> Option<Option<String>> {
+        let now = Instant::now();
+        let mut lanes = self.shared.lanes();
+        for queue in &mut lanes.queues {
+            while let Some(queued) = queue.pop_front() {
+                self.shared.depth.fetch_sub(1, Ordering::Relaxed);
+                if queued.expires.is_some_and(|expires| expires <= now) {
+                    if let Some(metrics) = &self.metrics {
+                        metrics.expired();
+                    }
+                    continue;
+                }
+                return Some(Some(queued.frame));
+            }
+        }
+        if lanes.senders == 0 {
+            return Some(None);
+        }
+        None
+    }
+}
@@ -0,0 +1,605 @@
+use derive_more::Display;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::ops::Deref;
+use uuid::Uuid;
+
+#[derive(Debug, Display, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Hash)]
+pub struct ClientId(Uuid);
+
+impl ClientId {
+    /// Sender of messages the relay itself puts in a room, e.g. from the http api
+    pub const RELAY: ClientId = ClientId(Uuid::nil());
+
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+
+    pub fn get(&self) -> Uuid {
+        self.0
+    }
+}
+
+impl Default for ClientId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientMessage {
+    pub clientId: Option<ClientId>,
+    #[serde(deserialize_with = "deserialize_operation")]
+    pub clientOperation: ClientOperation,
+}
+
+/// init.lua sends ConnectAttempt as a bare string, from before it could carry credentials
+fn deserialize_operation<'de, D>(deserializer: D) -> Result<ClientOperation, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    if value.as_str() == Some("ConnectAttempt") {
+        return Ok(ClientOperation::ConnectAttempt {
+            credentials: None,
+            compression: Vec::new(),
+        });
+    }
+    serde_json::from_value(value).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ServerOperation {
+    ClientConnectApproved(ClientId),
+    // the credentials were refused, the relay closes the connection after sending this
+    ConnectRejected {
+        reason: String,
+    },
+    // answer with AuthResponse, see auth::sign
+    AuthChallenge {
+        nonce: String,
+    },
+    // the operation was refused, the connection stays open
+    Nack {
+        reason: String,
+    },
+    // the relay has not heard from this client about the task in a while and it is behind the
+    // others, answer with TaskReport
+    RequestCurrentTaskStep {
+        room: Room,
+        name: String,
+    },
+    // answers TaskQuery, and goes to the room when a task is defined. Members that did not report
+    // yet have no step
+    TaskProgress {
+        room: Room,
+        name: String,
+        steps: Vec<String>,
+        members: Vec<TaskMember>,
+    },
+    // every member of the room got to this step, steps.len() once they are all done
+    TaskStepReached {
+        room: Room,
+        name: String,
+        step: usize,
+    },
+    // an admin removed this client, the relay closes the connection after sending this
+    Kicked {
+        by: Identity,
+    },
+    // this client's identity was banned until the given unix time, forever without one
+    Banned {
+        until: Option<u64>,
+    },
+    // messages from this client to the room are refused until Unmuted
+    Muted {
+        room: Room,
+    },
+    Unmuted {
+        room: Room,
+    },
+    // an admin closed a room this client was in, it is no longer a member
+    RoomClosed(Room),
+    // who leads a room that elects a leader, sent on joining it and whenever that changes. term
+    // grows with every change
+    LeaderChanged {
+        room: Room,
+        leader: ClientId,
+        identity: Option<Identity>,
+        term: u64,
+    },
+    // a client Message fanned out to every member of the room, sender included
+    RoomMessage {
+        sender: ClientId,
+        room: Room,
+        channel: Channel,
+        message: Message,
+        // counts up by one per message in this room and channel, a jump means something was missed
+        #[serde(default)]
+        seq: u64,
+    },
+    // answers Retransmit for the part of the range that is no longer in the history
+    HistoryGap {
+        room: Room,
+        channel: Channel,
+        from: u64,
+        to: u64,
+    },
+    // a DirectMessage addressed to this client's identity
+    DirectMessage {
+        sender: ClientId,
+        // identity of the sender, None when it did not authenticate as one
+        from: Option<Identity>,
+        channel: Channel,
+        message: Message,
+        // unix time the relay put it in the mailbox, only set when it waited for this client
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        queued_at: Option<u64>,
+    },
+    // an AtLeastOnce message, answer with Ack(seq). Redeliveries keep the seq, so a client that
+    // sees one twice can tell
+    Reliable {
+        seq: u64,
+        operation: Box<ServerOperation>,
+    },
+    // an AtLeastOnce message this client sent was given up on
+    DeliveryFailed {
+        to: Recipient,
+        attempts: u32,
+        operation: Box<ServerOperation>,
+    },
+    // a Message or DirectMessage with this id was already relayed, this copy was dropped
+    Duplicate {
+        id: String,
+    },
+    // answers StateGet, version 0 and no value when the key is not set
+    StateValue {
+        room: Room,
+        key: String,
+        value: Option<String>,
+        version: u64,
+    },
+    // a watched key changed, value None when it was deleted. by is who changed it, None for the
+    // current values a new watch starts with
+    StateChanged {
+        room: Room,
+        key: String,
+        value: Option<String>,
+        version: u64,
+        by: Option<ClientId>,
+    },
+    // this client holds the lock until it releases it or ttl_ms pass. token grows with every
+    // grant of the lock, hand it to whatever the lock protects
+    LockGranted {
+        room: Room,
+        name: String,
+        token: u64,
+        ttl_ms: u64,
+    },
+    // someone else holds the lock, LockGranted follows once this client's turn comes
+    LockQueued {
+        room: Room,
+        name: String,
+        // how many are ahead in the queue, the holder not counted
+        position: usize,
+    },
+    // the lease with this token ran out before it was released, the lock moved on
+    LockExpired {
+        room: Room,
+        name: String,
+        token: u64,
+    },
+    // this client's arrival at a barrier that is still waiting for others
+    BarrierWaiting {
+        room: Room,
+        name: String,
+        arrived: usize,
+        needed: usize,
+    },
+    // everyone the barrier waited for arrived
+    BarrierReleased {
+        room: Room,
+        name: String,
+        arrived: Vec<Recipient>,
+    },
+    // the timeout passed first. A barrier counting heads names the room members that did not
+    // wait as missing
+    BarrierTimedOut {
+        room: Room,
+        name: String,
+        arrived: Vec<Recipient>,
+        missing: Vec<Recipient>,
+    },
+    // answers a Batch, one result per operation in the order they were sent
+    BatchResults(Vec<BatchResult>),
+    // follows ClientConnectApproved when the client offered a codec the relay has enabled. Frames
+    // of at least min_bytes may come compressed with it from here on, both ways
+    CompressionEnabled {
+        codec: Compression,
+        min_bytes: usize,
+    },
+    // the relay is going away, reconnect after this many seconds
+    ServerShuttingDown {
+        reconnect_after: u64,
+    },
+}
+
+impl ServerOperation {
+    /// The room and channel whose seq this carries, their frames never overtake each other
+    pub fn sequenced(&self) -> Option<(&Room, &Channel)> {
+        match self {
+            ServerOperation::RoomMessage { room, channel, .. } => Some((room, channel)),
+            ServerOperation::Reliable { operation, .. } => operation.sequenced(),
+            _ => None,
+        }
+    }
+
+    /// The lane the relay queues this in when nothing more specific was asked for
+    pub fn priority(&self) -> Priority {
+        match self {
+            ServerOperation::ConnectRejected { .. }
+            | ServerOperation::Kicked { .. }
+            | ServerOperation::Banned { .. }
+            | ServerOperation::Muted { .. }
+            | ServerOperation::Unmuted { .. }
+            | ServerOperation::RoomClosed(_)
+            | ServerOperation::LeaderChanged { .. }
+            | ServerOperation::ServerShuttingDown { .. } => Priority::System,
+            ServerOperation::ClientConnectApproved(_)
+            | ServerOperation::CompressionEnabled { .. }
+            | ServerOperation::AuthChallenge { .. }
+            | ServerOperation::Nack { .. }
+            | ServerOperation::DeliveryFailed { .. }
+            | ServerOperation::HistoryGap { .. }
+            | ServerOperation::Duplicate { .. }
+            | ServerOperation::BatchResults(_)
+            | ServerOperation::StateValue { .. }
+            | ServerOperation::LockGranted { .. }
+            | ServerOperation::LockQueued { .. }
+            | ServerOperation::LockExpired { .. }
+            | ServerOperation::BarrierWaiting { .. }
+            | ServerOperation::BarrierReleased { .. }
+            | ServerOperation::BarrierTimedOut { .. }
+            | ServerOperation::TaskProgress { .. }
+            | ServerOperation::RequestCurrentTaskStep { .. } => Priority::Control,
+            ServerOperation::Reliable { operation, .. } => operation.priority(),
+            ServerOperation::RoomMessage { .. }
+            | ServerOperation::DirectMessage { .. }
+            | ServerOperation::StateChanged { .. }
+            | ServerOperation::TaskStepReached { .. } => Priority::Normal,
+        }
+    }
+
+    /// Serializes the operation as a single newline terminated JSON frame,
+    /// which is what the lua side reads with `receive('*l')`
+    pub fn to_frame(&self) -> serde_json::Result<String> {
+        let mut frame = serde_json::to_string(self)?;
+        frame.push('\n');
+        Ok(frame)
+    }
+}
+
+/* intended as a grouping of clients, so things like
+    "every one of your own characters" or "all the characters in the raid".
+    String currently for flexibility until I figure out something better.
+    Intended such that each client "connects" to one or more rooms at a time
+    and each room has 1 or more channels.  A message is sent to a Room/Channel combination
+*/
+#[derive(Debug, Clone, Serialize, Deserialize, Display, PartialEq, Eq, Hash)]
+pub struct Room(pub String);
+#[derive(Debug, Clone, Serialize, Deserialize, Display, PartialEq, Eq, Hash)]
+pub struct Channel(pub String);
+#[derive(Debug, Clone, Serialize, Deserialize, Display, PartialEq, Eq, Hash)]
+pub struct Message(pub String);
+// who a client is, as opposed to ClientId which only lives as long as one connection
+#[derive(Debug, Clone, Serialize, Deserialize, Display, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Identity(pub String);
+
+impl Deref for Room {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Deref for Channel {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Deref for Message {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Deref for Identity {
+    type Target = String;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Which of a client's queue lanes a frame waits in, more urgent lanes are written first
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // the relay's own notices, clients asking for it get Control
+    System,
+    // commands that must not wait, e.g. "stop attacking"
+    Control,
+    #[default]
+    Normal,
+    // status chatter that can wait for everything else
+    Bulk,
+}
+
+impl Priority {
+    pub const COUNT: usize = 4;
+
+    const ALL: [Priority; Priority::COUNT] = [
+        Priority::System,
+        Priority::Control,
+        Priority::Normal,
+        Priority::Bulk,
+    ];
+
+    pub fn lane(self) -> usize {
+        self as usize
+    }
+
+    pub fn from_lane(lane: usize) -> Option<Self> {
+        Self::ALL.get(lane).copied()
+    }
+
+    /// System is reserved for the relay
+    pub fn for_client(self) -> Self {
+        self.max(Priority::Control)
+    }
+}
+
+/// What became of one operation in a Batch
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum BatchResult {
+    Ok,
+    // the relay refused it, with what a Nack would have said
+    Failed { reason: String },
+    // an earlier operation closed the connection
+    Skipped,
+}
+
+/// Codecs for frames over the compression threshold
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Compression {
+    Zstd,
+    // raw deflate, without zlib or gzip headers
+    Deflate,
+}
+
+/// Delivery guarantee a client asks for per message
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Qos {
+    // queued once, lost if the connection goes away first
+    #[default]
+    AtMostOnce,
+    // wrapped in Reliable and redelivered until acked, see config qos
+    AtLeastOnce,
+}
+
+/// Who an AtLeastOnce message is owed to, or who sent a message for dedup: an identity across
+/// reconnects or else one connection
+#[derive(Debug, Clone, Serialize, Deserialize, Display, PartialEq, Eq, Hash)]
+pub enum Recipient {
+    Identity(Identity),
+    Client(ClientId),
+}
+
+impl Recipient {
+    pub fn of(clientId: ClientId, identity: Option<&Identity>) -> Self {
+        match identity {
+            Some(identity) => Recipient::Identity(identity.clone()),
+            None => Recipient::Client(clientId),
+        }
+    }
+}
+
+/// Who a barrier waits for, a number of participants or the identities that have to arrive
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Participants {
+    Count(usize),
+    Members(Vec<Identity>),
+}
+
+/// Where one member of a room is in a task
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TaskMember {
+    pub who: Recipient,
+    pub step: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Credentials {
+    // static token from auth.tokens
+    Token(String),
+    // asks for an AuthChallenge to prove knowledge of auth.secret
+    Hmac { identity: Identity },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientOperation {
+    // connects to a socket
+    ConnectAttempt {
+        #[serde(default)]
+        credentials: Option<Credentials>,
+        // codecs this client can read and write, most preferred first, see compression.rs
+        #[serde(default)]
+        compression: Vec<Compression>,
+    },
+    // hex HMAC-SHA256 answering an AuthChallenge
+    AuthResponse {
+        mac: String,
+    },
+    RoomJoin(Room), // joins a room
+    // joins a room that has a password
+    RoomJoinWithPassword {
+        room: Room,
+        password: String,
+    },
+    // creates a room owned by the caller's identity and joins it
+    RoomCreate {
+        room: Room,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        invite_only: bool,
+    },
+    // owner only, lets an identity in past the password and invite_only
+    RoomInvite {
+        room: Room,
+        identity: Identity,
+    },
+    // admin only, the rest of these
+    Kick(ClientId),
+    Ban {
+        identity: Identity,
+        // forever when left out
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+    Unban(Identity),
+    Mute {
+        room: Room,
+        client: ClientId,
+    },
+    Unmute {
+        room: Room,
+        client: ClientId,
+    },
+    CloseRoom(Room),
+    // owner only, a read only member still receives but can not send
+    RoomSetReadOnly {
+        room: Room,
+        identity: Identity,
+        read_only: bool,
+    },
+    RoomLeave(Room), // leaves a room
+    Disconnect,
+    Message {
+        room: Room,
+        channel: Channel,
+        message: Message,
+        #[serde(default)]
+        priority: Priority,
+        // dropped instead of delivered once this old, kept until delivered without one
+        #[serde(default)]
+        ttl_ms: Option<u64>,
+        #[serde(default)]
+        qos: Qos,
+        // client chosen, a repeat from the same sender within dedup.window_secs is dropped
+        #[serde(default)]
+        id: Option<String>,
+    },
+    // to every client connected as the identity, or its mailbox while none is
+    DirectMessage {
+        to: Identity,
+        channel: Channel,
+        message: Message,
+        #[serde(default)]
+        priority: Priority,
+        #[serde(default)]
+        ttl_ms: Option<u64>,
+        // AtLeastOnce waits for the identity to reconnect instead of going to its mailbox
+        #[serde(default)]
+        qos: Qos,
+        #[serde(default)]
+        id: Option<String>,
+    },
+    // acknowledges the Reliable with this seq
+    Ack(u64),
+    // resends the room and channel's messages with seq from..=to that are still in the history.
+    // Nacked while history.room_messages is 0, which it is by default
+    Retransmit {
+        room: Room,
+        channel: Channel,
+        from: u64,
+        to: u64,
+    },
+    // sets a key of the room's shared state. With if_version it only applies while the key is
+    // still at that version, 0 meaning not set
+    StateSet {
+        room: Room,
+        key: String,
+        value: String,
+        #[serde(default)]
+        if_version: Option<u64>,
+    },
+    // answered with StateValue
+    StateGet {
+        room: Room,
+        key: String,
+    },
+    StateDelete {
+        room: Room,
+        key: String,
+        #[serde(default)]
+        if_version: Option<u64>,
+    },
+    // StateChanged for every change to the key, or to any key of the room without one. The
+    // current values are sent first
+    StateWatch {
+        room: Room,
+        #[serde(default)]
+        key: Option<String>,
+    },
+    StateUnwatch {
+        room: Room,
+        #[serde(default)]
+        key: Option<String>,
+    },
+    // takes the room's lock of that name for ttl_ms, or queues for it. Answered with LockGranted
+    // or LockQueued, the holder asking again renews its lease
+    LockAcquire {
+        room: Room,
+        name: String,
+        ttl_ms: u64,
+    },
+    // lets go of a held lock or of the place in its queue
+    LockRelease {
+        room: Room,
+        name: String,
+    },
+    // waits at the room's barrier of that name. The first waiter says who it waits for and how
+    // long, BarrierReleased or BarrierTimedOut tells everyone waiting how it went
+    BarrierWait {
+        room: Room,
+        name: String,
+        count_or_members: Participants,
+        timeout_ms: u64,
+    },
+    // defines the room's task of that name, or starts it over with new steps
+    TaskDefine {
+        room: Room,
+        name: String,
+        steps: Vec<String>,
+    },
+    // the step of the task this client is on, counted from 0. steps.len() means done
+    TaskReport {
+        room: Room,
+        name: String,
+        step: usize,
+    },
+    // answered with TaskProgress
+    TaskQuery {
+        room: Room,
+        name: String,
+    },
+    // applied one after the other as if sent in separate frames, answered with BatchResults.
+    // Batches do not nest
+    Batch(Vec<ClientOperation>),
+}
@@ -0,0 +1,84 @@
+use crate::config::RoomConfig;
+use crate::error::OperationError;
+use crate::protocol::{Identity, Room};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/* Who may do what in a room.
+    Rooms without an acl stay open to everyone, like every room used to be. An acl is made by
+    RoomCreate or by listing owner/password/invite_only/... under [[rooms]], and rules are about
+    identities, so only authenticated clients can own, be invited to or be muted in a room.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoomAcl {
+    pub owner: Option<Identity>,
+    pub password: Option<String>,
+    pub invite_only: bool,
+    pub invited: HashSet<Identity>,
+    // members who may listen but not send
+    pub read_only: HashSet<Identity>,
+}
+
+impl RoomAcl {
+    /// The acl a [[rooms]] entry asks for, None when it only names an open room
+    pub fn from_config(room: &RoomConfig) -> Option<Self> {
+        let acl = Self {
+            owner: room.owner.clone().map(Identity),
+            password: room.password.clone(),
+            invite_only: room.invite_only,
+            invited: room.invited.iter().cloned().map(Identity).collect(),
+            read_only: room.read_only.iter().cloned().map(Identity).collect(),
+        };
+        (acl != Self::default()).then_some(acl)
+    }
+
+    /// Adds the invitations and read only members an owner gave out at runtime, as the store kept
+    /// them, to the acl of a [[rooms]] entry. The owner, password and invite_only stay as written
+    /// there, and so does everyone it lists
+    pub fn merge(&mut self, stored: RoomAcl) {
+        self.invited.extend(stored.invited);
+        self.read_only.extend(stored.read_only);
+    }
+
+    pub fn is_owner(&self, identity: Option<&Identity>) -> bool {
+        identity.is_some() && self.owner.as_ref() == identity
+    }
+
+    /// The owner and invited identities skip the password, everyone else needs it
+    pub fn check_join(
+        &self,
+        room: &Room,
+        identity: Option<&Identity>,
+        password: Option<&str>,
+    ) -> Result<(), OperationError> {
+        let invited = identity.is_some_and(|identity| self.invited.contains(identity));
+        if self.is_owner(identity) || invited {
+            return Ok(());
+        }
+        if self.invite_only {
+            return Err(OperationError::NotInvited(room.clone()));
+        }
+        match &self.password {
+            Some(expected) if password != Some(expected.as_str()) => {
+                Err(OperationError::WrongPassword(room.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_owner(
+        &self,
+        room: &Room,
+        identity: Option<&Identity>,
+    ) -> Result<(), OperationError> {
+        if self.is_owner(identity) {
+            Ok(())
+        } else {
+            Err(OperationError::NotOwner(room.clone()))
+        }
+    }
+
+    pub fn can_send(&self, identity: Option<&Identity>) -> bool {
+        !identity.is_some_and(|identity| self.read_only.contains(identity))
+    }
+}
@@ -0,0 +1,1355 @@
+use crate::acks::{Pending, Unacked};
+use crate::barriers::Barrier;
+use crate::config::Config;
+use crate::dedup::SeenIds;
+use crate::election::Election;
+use crate::locks::{Acquired, Lease, Lock, Released};
+use crate::metrics::Metrics;
+use crate::outbox::{self, Delivery};
+use crate::protocol::{
+    Channel, ClientId, Identity, Priority, Recipient, Room, ServerOperation, TaskMember,
+};
+use crate::room::RoomAcl;
+use crate::shutdown::Shutdown;
+use crate::state::{Entry, RoomState};
+use crate::store::{IdentityRecord, Store, StoreWriter};
+use crate::tasks::Task;
+use crate::AnyResult;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace, warn};
+
+pub struct Client {
+    pub tx: outbox::Sender,
+    pub clientId: ClientId,
+    // set when the transport vouched for the client, e.g. a TLS client certificate
+    pub identity: Option<Identity>,
+    // cancelled to make the transport stop reading, e.g. when an admin kicks the client
+    pub closed: CancellationToken,
+}
+
+impl Client {
+    pub fn new(
+        clientId: ClientId,
+        tx: outbox::Sender,
+        identity: Option<Identity>,
+        closed: CancellationToken,
+    ) -> Self {
+        Self {
+            tx,
+            clientId,
+            identity,
+            closed,
+        }
+    }
+
+    /// Queues a server operation for this client's writer task, in the lane its kind goes in
+    pub fn send(&self, operation: &ServerOperation) -> AnyResult {
+        self.send_with(
+            operation,
+            Delivery {
+                priority: operation.priority(),
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn send_with(&self, operation: &ServerOperation, delivery: Delivery) -> AnyResult {
+        let frame = operation.to_frame()?;
+        let delivery = match operation.sequenced() {
+            Some(stream) => delivery.in_stream(stream),
+            None => delivery,
+        };
+        self.tx.send_with(frame, delivery)?;
+        Ok(())
+    }
+}
+
+/// Keeps an identity from connecting, until is in unix seconds and None means for good
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub until: Option<u64>,
+}
+
+impl Ban {
+    pub fn active(&self, now: u64) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+}
+
+/// A direct message waiting in the mailbox of an identity nobody is connected as
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mail {
+    // unix seconds it was queued at
+    pub at: u64,
+    pub operation: ServerOperation,
+    pub priority: Priority,
+    // unix milliseconds past which it is no longer worth delivering
+    pub expires_ms: Option<u64>,
+}
+
+impl Mail {
+    pub fn expired(&self, now_ms: u64) -> bool {
+        self.expires_ms.is_some_and(|expires| expires <= now_ms)
+    }
+
+    /// The operation as delivered, marked with when it was queued, and how to queue it
+    pub fn delivery(self, now_ms: u64) -> (ServerOperation, Delivery) {
+        let delivery = Delivery::new(
+            self.priority,
+            self.expires_ms
+                .map(|expires| expires.saturating_sub(now_ms)),
+        );
+        let operation = match self.operation {
+            ServerOperation::DirectMessage {
+                sender,
+                from,
+                channel,
+                message,
+                ..
+            } => ServerOperation::DirectMessage {
+                sender,
+                from,
+                channel,
+                message,
+                queued_at: Some(self.at),
+            },
+            operation => operation,
+        };
+        (operation, delivery)
+    }
+}
+
+pub fn unix_now() -> u64 {
+    unix_now_ms() / 1000
+}
+
+pub fn unix_now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub struct Server {
+    pub config: Arc<Config>,
+    pub shutdown: Shutdown,
+    pub metrics: Arc<Metrics>,
+    pub clients: HashMap<ClientId, Client>,
+    pub rooms: HashMap<Room, HashSet<ClientId>>,
+    // rooms missing here are open to everyone
+    pub acls: HashMap<Room, RoomAcl>,
+    // rooms that keep a leader elected, see election
+    pub elections: HashMap<Room, Election>,
+    pub bans: HashMap<Identity, Ban>,
+    // every identity that authenticated, since the last restart unless the store is on
+    pub identities: HashMap<Identity, IdentityRecord>,
+    // direct messages for identities that were offline, oldest first
+    pub mailboxes: HashMap<Identity, VecDeque<Mail>>,
+    // clients an admin silenced in a room, they still receive
+    pub muted: HashMap<Room, HashSet<ClientId>>,
+    // last config.history.room_messages messages of every room, oldest first
+    pub history: HashMap<Room, VecDeque<ServerOperation>>,
+    // seq of the last RoomMessage in every room and channel
+    pub sequences: HashMap<(Room, Channel), u64>,
+    // message ids every sender used lately, see dedup
+    pub seen_ids: HashMap<Recipient, SeenIds>,
+    // AtLeastOnce messages waiting for an Ack, see acks
+    pub unacked: HashMap<Recipient, Unacked>,
+    // shared key/value state and who watches it, see state
+    pub state: HashMap<Room, RoomState>,
+    // named locks of every room, see locks
+    pub locks: HashMap<(Room, String), Lock>,
+    // the last fencing token granted in every room, outlives the room's locks
+    pub lock_tokens: HashMap<Room, u64>,
+    // barriers of every room that someone waits at, see barriers
+    pub barriers: HashMap<(Room, String), Barrier>,
+    // multi-step tasks of every room and where their members are, see tasks
+    pub tasks: HashMap<(Room, String), Task>,
+    // wakes deadlines::run when a lease, barrier timeout or straggler poll comes up
+    pub deadlines_changed: Arc<Notify>,
+    // set when config.store.path is, changes are written through to it as they happen
+    pub store: Option<StoreWriter>,
+}
+
+impl Server {
+    pub fn new(config: Arc<Config>) -> Self {
+        let rooms = config
+            .rooms
+            .iter()
+            .map(|room| (Room(room.name.clone()), HashSet::new()))
+            .collect();
+        let acls = config
+            .rooms
+            .iter()
+            .filter_map(|room| Some((Room(room.name.clone()), RoomAcl::from_config(room)?)))
+            .collect();
+        let elections = config
+            .rooms
+            .iter()
+            .filter_map(|room| Some((Room(room.name.clone()), Election::from_config(room)?)))
+            .collect();
+        Self {
+            config,
+            shutdown: Shutdown::default(),
+            metrics: Arc::new(Metrics::new()),
+            clients: HashMap::new(),
+            rooms,
+            acls,
+            elections,
+            bans: HashMap::new(),
+            identities: HashMap::new(),
+            mailboxes: HashMap::new(),
+            muted: HashMap::new(),
+            history: HashMap::new(),
+            sequences: HashMap::new(),
+            seen_ids: HashMap::new(),
+            unacked: HashMap::new(),
+            state: HashMap::new(),
+            locks: HashMap::new(),
+            lock_tokens: HashMap::new(),
+            barriers: HashMap::new(),
+            tasks: HashMap::new(),
+            deadlines_changed: Arc::new(Notify::new()),
+            store: None,
+        }
+    }
+
+    /// Server::new, plus whatever the store kept from the last run when one is configured
+    pub fn open(config: Arc<Config>) -> anyhow::Result<Self> {
+        let mut server = Self::new(config);
+        if server.config.store.path.is_some() {
+            let store = Store::open(&server.config.store)?;
+            server.restore(store)?;
+        }
+        Ok(server)
+    }
+
+    fn restore(&mut self, store: Store) -> anyhow::Result<()> {
+        // rooms from the config file keep the acl written there, plus what their owner changed
+        for (room, acl) in store.acls()? {
+            self.rooms.entry(room.clone()).or_default();
+            match self.acls.get_mut(&room) {
+                Some(configured) => configured.merge(acl),
+                None => {
+                    self.acls.insert(room, acl);
+                }
+            }
+        }
+        self.bans = store.bans()?;
+        self.identities = store.identities()?;
+        self.mailboxes = store.mailboxes()?;
+        self.state = store.states()?;
+        self.lock_tokens = store.lock_tokens()?;
+        // rooms that only existed for their state come back too
+        for room in self.state.keys() {
+            self.rooms.entry(room.clone()).or_default();
+        }
+        if self.config.history.room_messages > 0 {
+            self.history = store.history(self.config.history.room_messages)?;
+        }
+        // carry on counting where the stored history left off
+        for operation in self.history.values().flatten() {
+            if let ServerOperation::RoomMessage {
+                room, channel, seq, ..
+            } = operation
+            {
+                let last = self
+                    .sequences
+                    .entry((room.clone(), channel.clone()))
+                    .or_default();
+                *last = (*last).max(*seq);
+            }
+        }
+        info!(
+            "Restored {} private rooms, {} bans, {} identities and {} mailboxes from the store",
+            self.acls.len(),
+            self.bans.len(),
+            self.identities.len(),
+            self.mailboxes.len()
+        );
+        self.store = Some(StoreWriter::spawn(store)?);
+        Ok(())
+    }
+
+    /// Queues a write for the store when there is one, see StoreWriter
+    fn persist(
+        &self,
+        what: &'static str,
+        write: impl FnOnce(&Store) -> anyhow::Result<()> + Send + 'static,
+    ) {
+        if let Some(store) = &self.store {
+            store.write(what, write);
+        }
+    }
+
+    /// Writes a room's acl through to the store after it changed
+    pub fn save_acl(&self, room: &Room) {
+        if let Some(acl) = self.acls.get(room) {
+            let (room, acl) = (room.clone(), acl.clone());
+            self.persist("room acl", move |store| store.save_acl(&room, &acl));
+        }
+    }
+
+    pub fn ban(&mut self, identity: Identity, ban: Ban) {
+        let banned = identity.clone();
+        self.persist("ban", move |store| store.save_ban(&banned, ban));
+        self.bans.insert(identity, ban);
+    }
+
+    pub fn unban(&mut self, identity: &Identity) {
+        let unbanned = identity.clone();
+        self.persist("unban", move |store| store.delete_ban(&unbanned));
+        self.bans.remove(identity);
+    }
+
+    /// Notes that an identity just authenticated
+    pub fn saw_identity(&mut self, identity: &Identity) {
+        let now = unix_now();
+        self.identities
+            .entry(identity.clone())
+            .and_modify(|record| record.last_seen = now)
+            .or_insert(IdentityRecord {
+                first_seen: now,
+                last_seen: now,
+            });
+        let identity = identity.clone();
+        self.persist("identity", move |store| {
+            store.touch_identity(&identity, now).map(|_| ())
+        });
+    }
+
+    /// Whether the relay knows of an identity, from the config or from it authenticating before
+    pub fn is_registered(&self, identity: &Identity) -> bool {
+        let auth = &self.config.auth;
+        self.identities.contains_key(identity)
+            || auth.tokens.values().any(|known| **known == **identity)
+            || auth.admins.iter().any(|known| **known == **identity)
+            || self
+                .config
+                .tls
+                .identities
+                .values()
+                .any(|known| **known == **identity)
+    }
+
+    /// Queues an operation for every client connected as the identity, returns how many
+    pub fn send_to_identity(
+        &mut self,
+        identity: &Identity,
+        operation: &ServerOperation,
+        delivery: Delivery,
+    ) -> usize {
+        let mut delivered = 0;
+        for client in self.clients.values() {
+            if client.identity.as_ref() != Some(identity) {
+                continue;
+            }
+            match client.send_with(operation, delivery) {
+                Ok(()) => delivered += 1,
+                Err(e) => {
+                    error!("Failed to send to client {}: {}", client.clientId, e);
+                    self.metrics.dropped();
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Keeps a direct message for an identity until it connects or its ttl runs out. False when
+    /// it has no mailbox, because mailboxes are off or the relay never heard of the identity
+    pub fn post(
+        &mut self,
+        identity: &Identity,
+        operation: ServerOperation,
+        priority: Priority,
+        ttl_ms: Option<u64>,
+    ) -> bool {
+        let max_messages = self.config.mailbox.max_messages;
+        if max_messages == 0 || !self.is_registered(identity) {
+            return false;
+        }
+        let now_ms = unix_now_ms();
+        let mail = Mail {
+            at: now_ms / 1000,
+            operation,
+            priority,
+            expires_ms: ttl_ms.map(|ttl| now_ms.saturating_add(ttl)),
+        };
+        let (recipient, stored) = (identity.clone(), mail.clone());
+        self.persist("mail", move |store| {
+            store.push_mail(&recipient, &stored, max_messages)
+        });
+        let mailbox = self.mailboxes.entry(identity.clone()).or_default();
+        mailbox.push_back(mail);
+        while mailbox.len() > max_messages {
+            warn!(
+                "Mailbox of {} is full, dropping its oldest message",
+                identity
+            );
+            mailbox.pop_front();
+            self.metrics.dropped();
+        }
+        true
+    }
+
+    /// Empties an identity's mailbox, oldest first and without what expired while it waited
+    pub fn take_mail(&mut self, identity: &Identity) -> Vec<(ServerOperation, Delivery)> {
+        let Some(mailbox) = self.mailboxes.remove(identity) else {
+            return Vec::new();
+        };
+        let recipient = identity.clone();
+        self.persist("mail delivery", move |store| store.delete_mail(&recipient));
+        let max_age = self.config.mailbox.max_age_secs;
+        let now_ms = unix_now_ms();
+        let now = now_ms / 1000;
+        mailbox
+            .into_iter()
+            .filter(|mail| {
+                let fresh = !mail.expired(now_ms)
+                    && (max_age == 0 || now.saturating_sub(mail.at) <= max_age);
+                if !fresh {
+                    self.metrics.expired();
+                }
+                fresh
+            })
+            .map(|mail| mail.delivery(now_ms))
+            .collect()
+    }
+
+    /// Whether a room is taken, by the config, an acl, members past or present, history or state.
+    /// RoomCreate only hands out rooms that are none of those
+    pub fn room_exists(&self, room: &Room) -> bool {
+        self.config
+            .rooms
+            .iter()
+            .any(|configured| configured.name == room.0)
+            || self.rooms.contains_key(room)
+            || self.acls.contains_key(room)
+            || self.history.contains_key(room)
+            || self.state.contains_key(room)
+    }
+
+    pub fn rooms_of(&self, clientId: &ClientId) -> usize {
+        self.rooms
+            .values()
+            .filter(|members| members.contains(clientId))
+            .count()
+    }
+
+    /// Stamps the next message of a room and channel
+    pub fn next_seq(&mut self, room: &Room, channel: &Channel) -> u64 {
+        let last = self
+            .sequences
+            .entry((room.clone(), channel.clone()))
+            .or_default();
+        *last += 1;
+        *last
+    }
+
+    /// The RoomMessages of a channel with seq in from..=to that the history still has, and the
+    /// part of the range it no longer has
+    pub fn retransmit(
+        &self,
+        room: &Room,
+        channel: &Channel,
+        from: u64,
+        to: u64,
+    ) -> (Vec<ServerOperation>, Option<(u64, u64)>) {
+        let last = self
+            .sequences
+            .get(&(room.clone(), channel.clone()))
+            .copied()
+            .unwrap_or(0);
+        let to = to.min(last);
+        let found: Vec<ServerOperation> = self
+            .history
+            .get(room)
+            .into_iter()
+            .flatten()
+            .filter(|operation| {
+                matches!(operation, ServerOperation::RoomMessage { channel: c, seq, .. }
+                    if c == channel && (from..=to).contains(seq))
+            })
+            .cloned()
+            .collect();
+        let first_found = found.iter().find_map(|operation| match operation {
+            ServerOperation::RoomMessage { seq, .. } => Some(*seq),
+            _ => None,
+        });
+        let gap_end = first_found.map_or(to, |seq| seq - 1);
+        let gap = (from <= gap_end).then_some((from, gap_end));
+        (found, gap)
+    }
+
+    pub fn record_history(&mut self, room: &Room, operation: &ServerOperation) {
+        let limit = self.config.history.room_messages;
+        if limit == 0 {
+            return;
+        }
+        let history = self.history.entry(room.clone()).or_default();
+        history.push_back(operation.clone());
+        while history.len() > limit {
+            history.pop_front();
+        }
+        let (room, operation, now) = (room.clone(), operation.clone(), unix_now());
+        self.persist("history", move |store| {
+            store.append_history(&room, &operation, now)
+        });
+    }
+
+    /// Queues an operation for every member of the room, members whose queue is gone are removed.
+    /// Returns how many members it was queued for
+    pub fn broadcast(
+        &mut self,
+        room: &Room,
+        operation: &ServerOperation,
+        delivery: Delivery,
+    ) -> usize {
+        let started = Instant::now();
+        let mut delivered = 0;
+        let mut dead_clients = Vec::new();
+        if let Some(clients_in_room) = self.rooms.get(room) {
+            for client_id in clients_in_room {
+                if let Some(client) = self.clients.get(client_id) {
+                    if let Err(e) = client.send_with(operation, delivery) {
+                        error!("Failed to send message to client {}: {}", client_id, e);
+                        self.metrics.dropped();
+                        // Queue dead client for removal
+                        dead_clients.push(*client_id);
+                    } else {
+                        trace!("Sent message to client: {}", client_id);
+                        delivered += 1;
+                    }
+                }
+            }
+        }
+
+        // Remove dead clients
+        for client_id in dead_clients {
+            self.remove_client(&client_id);
+        }
+        self.metrics.fanout(started.elapsed());
+        delivered
+    }
+
+    /// Forgets everything the server knows about a client, including its room memberships.
+    /// What it still owed acks for is given up on, nobody else can ack it
+    pub fn remove_client(&mut self, clientId: &ClientId) {
+        self.clients.remove(clientId);
+        let mut left = Vec::new();
+        for (room, members) in self.rooms.iter_mut() {
+            if members.remove(clientId) {
+                left.push(room.clone());
+            }
+        }
+        for muted in self.muted.values_mut() {
+            muted.remove(clientId);
+        }
+        self.leave_elections(clientId, None);
+        for (room, state) in self.state.iter_mut() {
+            state.unwatch(clientId, None);
+            // watching a room does not take joining it
+            if state.is_unused() {
+                left.push(room.clone());
+            }
+        }
+        let locks: Vec<(Room, String)> = self.locks.keys().cloned().collect();
+        for (room, name) in locks {
+            self.release_lock(&room, &name, *clientId);
+        }
+        self.leave_barriers(clientId, None);
+        let recipient = Recipient::Client(*clientId);
+        for task in self.tasks.values_mut() {
+            task.forget(&recipient);
+        }
+        self.advance_tasks(None);
+        self.seen_ids.remove(&recipient);
+        let seqs: Vec<u64> = self
+            .unacked
+            .get(&recipient)
+            .map(|unacked| unacked.pending.keys().copied().collect())
+            .unwrap_or_default();
+        for seq in seqs {
+            self.give_up(&recipient, seq);
+        }
+        self.unacked.remove(&recipient);
+        for room in left {
+            self.prune_room(&room);
+        }
+    }
+
+    /// Forgets a room once its last member left, unless it is configured or has an acl, state or
+    /// tasks to keep. Its history and sequences stay for whoever joins it next. State that was
+    /// never written and that nobody watches is dropped either way
+    pub fn prune_room(&mut self, room: &Room) {
+        if self.state.get(room).is_some_and(RoomState::is_unused) {
+            self.state.remove(room);
+        }
+        let empty = self
+            .rooms
+            .get(room)
+            .is_some_and(|members| members.is_empty());
+        let kept = self
+            .config
+            .rooms
+            .iter()
+            .any(|configured| configured.name == room.0)
+            || self.acls.contains_key(room)
+            || self.state.contains_key(room)
+            || self.tasks.keys().any(|(of, _)| of == room);
+        if empty && !kept {
+            self.rooms.remove(room);
+            self.muted.remove(room);
+        }
+    }
+
+    /// Sets a key of a room's state and tells its watchers, returns the new version
+    pub fn set_state(&mut self, room: &Room, key: &str, value: String, by: ClientId) -> u64 {
+        let state = self.state.entry(room.clone()).or_default();
+        let version = state.set(key, value.clone());
+        let entry = Entry {
+            value: value.clone(),
+            version,
+        };
+        let (stored_room, stored_key) = (room.clone(), key.to_string());
+        self.persist("state", move |store| {
+            store.save_state(&stored_room, &stored_key, &entry)
+        });
+        self.notify_state(room, key, Some(value), version, by);
+        version
+    }
+
+    /// Deletes a key of a room's state and tells its watchers, None when it was not set
+    pub fn delete_state(&mut self, room: &Room, key: &str, by: ClientId) -> Option<u64> {
+        let version = self.state.get_mut(room)?.delete(key)?;
+        let (stored_room, stored_key) = (room.clone(), key.to_string());
+        self.persist("state removal", move |store| {
+            store.delete_state(&stored_room, &stored_key, version)
+        });
+        self.notify_state(room, key, None, version, by);
+        Some(version)
+    }
+
+    fn notify_state(
+        &self,
+        room: &Room,
+        key: &str,
+        value: Option<String>,
+        version: u64,
+        by: ClientId,
+    ) {
+        let Some(state) = self.state.get(room) else {
+            return;
+        };
+        let change = ServerOperation::StateChanged {
+            room: room.clone(),
+            key: key.to_string(),
+            value,
+            version,
+            by: Some(by),
+        };
+        for clientId in state.watchers_of(key) {
+            if let Some(client) = self.clients.get(&clientId) {
+                if let Err(e) = client.send(&change) {
+                    warn!(
+                        "Failed to tell client {} of a state change: {}",
+                        clientId, e
+                    );
+                    self.metrics.dropped();
+                }
+            }
+        }
+    }
+
+    /// Grants a lock or queues for it, telling the client which
+    pub fn acquire_lock(&mut self, room: &Room, name: &str, clientId: ClientId, ttl: Duration) {
+        let tokens = self.lock_tokens.entry(room.clone()).or_default();
+        let acquired = self
+            .locks
+            .entry((room.clone(), name.to_string()))
+            .or_default()
+            .acquire(clientId, ttl, Instant::now(), tokens);
+        match acquired {
+            Acquired::Granted(lease) => {
+                self.save_lock_token(room, lease.token);
+                self.deadlines_changed.notify_one();
+                self.tell_granted(room, name, lease);
+            }
+            Acquired::Renewed(lease) => {
+                self.deadlines_changed.notify_one();
+                self.tell_granted(room, name, lease);
+            }
+            Acquired::Queued(position) => {
+                let queued = ServerOperation::LockQueued {
+                    room: room.clone(),
+                    name: name.to_string(),
+                    position,
+                };
+                if let Some(client) = self.clients.get(&clientId) {
+                    if let Err(e) = client.send(&queued) {
+                        warn!("Failed to tell client {} it is queued: {}", clientId, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Lets go of a lock or a place in its queue, the next in line gets a held lock. A lock nobody
+    /// holds or waits for is forgotten
+    pub fn release_lock(&mut self, room: &Room, name: &str, clientId: ClientId) -> Released {
+        let key = (room.clone(), name.to_string());
+        let Some(lock) = self.locks.get_mut(&key) else {
+            return Released::NotHeld;
+        };
+        let tokens = self.lock_tokens.entry(room.clone()).or_default();
+        let released = lock.release(clientId, Instant::now(), tokens);
+        if lock.is_idle() {
+            self.locks.remove(&key);
+        }
+        if let Released::Held(Some(next)) = released {
+            self.save_lock_token(room, next.token);
+            self.deadlines_changed.notify_one();
+            self.tell_granted(room, name, next);
+        }
+        released
+    }
+
+    /// Takes away the leases that ran out and hands their locks on. Returns when the next lease
+    /// runs out
+    pub fn expire_leases(&mut self, now: Instant) -> Option<Instant> {
+        let mut expired = Vec::new();
+        for ((room, name), lock) in &mut self.locks {
+            let tokens = self.lock_tokens.entry(room.clone()).or_default();
+            if let Some((lease, next)) = lock.expire(now, tokens) {
+                expired.push((room.clone(), name.clone(), lease, next));
+            }
+        }
+        self.locks.retain(|_, lock| !lock.is_idle());
+        for (room, name, lease, next) in expired {
+            info!(
+                "Lease {} of lock {}/{} held by {} ran out",
+                lease.token, room, name, lease.holder
+            );
+            let notice = ServerOperation::LockExpired {
+                room: room.clone(),
+                name: name.clone(),
+                token: lease.token,
+            };
+            if let Some(client) = self.clients.get(&lease.holder) {
+                if let Err(e) = client.send(&notice) {
+                    warn!(
+                        "Failed to tell client {} its lease ran out: {}",
+                        lease.holder, e
+                    );
+                }
+            }
+            if let Some(next) = next {
+                self.save_lock_token(&room, next.token);
+                self.tell_granted(&room, &name, next);
+            }
+        }
+        self.locks
+            .values()
+            .filter_map(|lock| lock.lease.map(|lease| lease.expires))
+            .min()
+    }
+
+    /// Everything that falls due by `now`, returns when the next thing does
+    pub fn expire_deadlines(&mut self, now: Instant) -> Option<Instant> {
+        let leases = self.expire_leases(now);
+        let barriers = self.expire_barriers(now);
+        let polls = self.poll_stragglers(now);
+        leases.into_iter().chain(barriers).chain(polls).min()
+    }
+
+    /// Counts a client in at a barrier, releasing it if that was the last one missing
+    pub fn wait_at_barrier(&mut self, room: &Room, name: &str, clientId: ClientId, who: Recipient) {
+        let key = (room.clone(), name.to_string());
+        let Some(barrier) = self.barriers.get_mut(&key) else {
+            return;
+        };
+        barrier.arrive(clientId, who);
+        if barrier.is_complete() {
+            let barrier = self.barriers.remove(&key).unwrap_or_else(|| unreachable!());
+            info!(
+                "Barrier {}/{} released with {} participants",
+                room,
+                name,
+                barrier.needed()
+            );
+            let released = ServerOperation::BarrierReleased {
+                room: room.clone(),
+                name: name.to_string(),
+                arrived: barrier.arrived(),
+            };
+            self.send_to_all(&barrier.waiters(), &released);
+        } else {
+            let waiting = ServerOperation::BarrierWaiting {
+                room: room.clone(),
+                name: name.to_string(),
+                arrived: barrier.arrived().len(),
+                needed: barrier.needed(),
+            };
+            self.send_to_all(&[clientId], &waiting);
+        }
+    }
+
+    /// Takes back a client's arrivals at the barriers of `room`, or of every room
+    pub fn leave_barriers(&mut self, clientId: &ClientId, room: Option<&Room>) {
+        self.barriers.retain(|(barrier_room, _), barrier| {
+            if room.is_some_and(|room| room != barrier_room) {
+                return true;
+            }
+            barrier.leave(clientId);
+            !barrier.is_empty()
+        });
+    }
+
+    /// Gives up on the barriers whose timeout passed and tells who waited who is missing
+    fn expire_barriers(&mut self, now: Instant) -> Option<Instant> {
+        let timed_out: Vec<(Room, String)> = self
+            .barriers
+            .iter()
+            .filter(|(_, barrier)| barrier.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in timed_out {
+            let Some(barrier) = self.barriers.remove(&key) else {
+                continue;
+            };
+            let (room, name) = key;
+            let present = self.members_of(&room).into_iter().map(|(_, who)| who);
+            let missing = barrier.missing(present.collect());
+            info!(
+                "Barrier {}/{} timed out missing {} participants",
+                room,
+                name,
+                missing.len()
+            );
+            let timed_out = ServerOperation::BarrierTimedOut {
+                room,
+                name,
+                arrived: barrier.arrived(),
+                missing,
+            };
+            self.send_to_all(&barrier.waiters(), &timed_out);
+        }
+        self.barriers.values().map(|barrier| barrier.deadline).min()
+    }
+
+    /// Defines a room's task, or starts it over, and shows the room its steps
+    pub fn define_task(&mut self, room: &Room, name: &str, steps: Vec<String>) {
+        info!("Task {}/{} defined with {} steps", room, name, steps.len());
+        let key = (room.clone(), name.to_string());
+        self.tasks.insert(key, Task::new(steps, Instant::now()));
+        self.deadlines_changed.notify_one();
+        if let Some(progress) = self.task_progress(room, name) {
+            let members: Vec<ClientId> =
+                self.members_of(room).into_iter().map(|(c, _)| c).collect();
+            self.send_to_all(&members, &progress);
+        }
+    }
+
+    pub fn report_task_step(&mut self, room: &Room, name: &str, who: Recipient, step: usize) {
+        let Some(task) = self.tasks.get_mut(&(room.clone(), name.to_string())) else {
+            return;
+        };
+        debug!("{:?} is on step {} of task {}/{}", who, step, room, name);
+        task.report(who, step, Instant::now());
+        self.deadlines_changed.notify_one();
+        self.advance_tasks(Some(room));
+    }
+
+    /// Tells rooms whose members all got to a further step of a task, for the tasks of `room` or
+    /// of every room
+    pub fn advance_tasks(&mut self, room: Option<&Room>) {
+        let keys: Vec<(Room, String)> = self
+            .tasks
+            .keys()
+            .filter(|(task_room, _)| room.is_none_or(|room| room == task_room))
+            .cloned()
+            .collect();
+        for (room, name) in keys {
+            let members = self.members_of(&room);
+            let present: Vec<Recipient> = members.iter().map(|(_, who)| who.clone()).collect();
+            let Some(task) = self.tasks.get_mut(&(room.clone(), name.clone())) else {
+                continue;
+            };
+            let Some(step) = task.reach(&present) else {
+                continue;
+            };
+            info!("Room {} got to step {} of task {}", room, step, name);
+            let reached = ServerOperation::TaskStepReached { room, name, step };
+            let clientIds: Vec<ClientId> = members.into_iter().map(|(c, _)| c).collect();
+            self.send_to_all(&clientIds, &reached);
+        }
+    }
+
+    /// Who in the room is on which step of the task, None when there is no such task
+    pub fn task_progress(&self, room: &Room, name: &str) -> Option<ServerOperation> {
+        let task = self.tasks.get(&(room.clone(), name.to_string()))?;
+        let mut members: Vec<TaskMember> = Vec::new();
+        for (_, who) in self.members_of(room) {
+            if !members.iter().any(|member| member.who == who) {
+                let step = task.step_of(&who);
+                members.push(TaskMember { who, step });
+            }
+        }
+        Some(ServerOperation::TaskProgress {
+            room: room.clone(),
+            name: name.to_string(),
+            steps: task.steps.clone(),
+            members,
+        })
+    }
+
+    /// Asks the members behind on a task where they are once they were quiet for
+    /// tasks.poll_after_ms. Returns when the next one is due
+    fn poll_stragglers(&mut self, now: Instant) -> Option<Instant> {
+        let poll_after = Duration::from_millis(self.config.tasks.poll_after_ms);
+        if poll_after.is_zero() {
+            return None;
+        }
+        let mut next: Option<Instant> = None;
+        let mut polls = Vec::new();
+        let keys: Vec<(Room, String)> = self.tasks.keys().cloned().collect();
+        for (room, name) in keys {
+            let members = self.members_of(&room);
+            let present: Vec<Recipient> = members.iter().map(|(_, who)| who.clone()).collect();
+            let Some(task) = self.tasks.get_mut(&(room.clone(), name.clone())) else {
+                continue;
+            };
+            // every connection of a straggler is asked, so it is only due once
+            let mut asked: Vec<Recipient> = Vec::new();
+            for (clientId, who) in members {
+                if !task.is_behind(&who, &present) {
+                    continue;
+                }
+                if asked.contains(&who) || task.poll_due(&who, poll_after) <= now {
+                    polls.push((clientId, room.clone(), name.clone()));
+                    asked.push(who.clone());
+                    task.polled(who.clone(), now);
+                }
+                let due = task.poll_due(&who, poll_after);
+                next = next.into_iter().chain([due]).min();
+            }
+        }
+        for (clientId, room, name) in polls {
+            debug!("Asking {} where it is in task {}/{}", clientId, room, name);
+            self.send_to_all(
+                &[clientId],
+                &ServerOperation::RequestCurrentTaskStep { room, name },
+            );
+        }
+        next
+    }
+
+    /// Counts a new member in the room's election, if it has one. The member is told who leads,
+    /// or everyone is when it took over
+    pub fn join_election(&mut self, room: &Room, clientId: ClientId) {
+        let identity = self
+            .clients
+            .get(&clientId)
+            .and_then(|client| client.identity.clone());
+        let Some(election) = self.elections.get_mut(room) else {
+            return;
+        };
+        if election.join(clientId, identity.as_ref()).is_some() {
+            self.announce_leader(room);
+        } else if let Some(notice) = self.leader_notice(room) {
+            self.send_to_all(&[clientId], &notice);
+        }
+    }
+
+    /// Takes a client out of the elections of `room`, or of every room, electing the next
+    /// leader where it led
+    pub fn leave_elections(&mut self, clientId: &ClientId, room: Option<&Room>) {
+        let changed: Vec<Room> = self
+            .elections
+            .iter_mut()
+            .filter(|(election_room, _)| room.is_none_or(|room| room == *election_room))
+            .filter_map(|(election_room, election)| {
+                election.leave(clientId).map(|_| election_room.clone())
+            })
+            .collect();
+        for room in changed {
+            self.announce_leader(&room);
+        }
+    }
+
+    fn announce_leader(&self, room: &Room) {
+        let Some(notice) = self.leader_notice(room) else {
+            return;
+        };
+        if let ServerOperation::LeaderChanged { leader, term, .. } = &notice {
+            info!("Client {} leads room {} for term {}", leader, room, term);
+        }
+        let members: Vec<ClientId> = self.members_of(room).into_iter().map(|(c, _)| c).collect();
+        self.send_to_all(&members, &notice);
+    }
+
+    fn leader_notice(&self, room: &Room) -> Option<ServerOperation> {
+        let election = self.elections.get(room)?;
+        let leader = election.leader?;
+        Some(ServerOperation::LeaderChanged {
+            room: room.clone(),
+            leader,
+            identity: self
+                .clients
+                .get(&leader)
+                .and_then(|client| client.identity.clone()),
+            term: election.term,
+        })
+    }
+
+    /// The connections in a room and who each counts as
+    pub fn members_of(&self, room: &Room) -> Vec<(ClientId, Recipient)> {
+        self.rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .map(|clientId| {
+                let identity = self
+                    .clients
+                    .get(clientId)
+                    .and_then(|client| client.identity.as_ref());
+                (*clientId, Recipient::of(*clientId, identity))
+            })
+            .collect()
+    }
+
+    fn send_to_all(&self, clientIds: &[ClientId], operation: &ServerOperation) {
+        for clientId in clientIds {
+            if let Some(client) = self.clients.get(clientId) {
+                if let Err(e) = client.send(operation) {
+                    warn!("Failed to send to client {}: {}", clientId, e);
+                }
+            }
+        }
+    }
+
+    /// Writes the last token granted in a room through to the store
+    fn save_lock_token(&self, room: &Room, token: u64) {
+        let room = room.clone();
+        self.persist("lock token", move |store| {
+            store.save_lock_token(&room, token)
+        });
+    }
+
+    fn tell_granted(&self, room: &Room, name: &str, lease: Lease) {
+        debug!(
+            "Lock {}/{} granted to {} with token {}",
+            room, name, lease.holder, lease.token
+        );
+        let granted = ServerOperation::LockGranted {
+            room: room.clone(),
+            name: name.to_string(),
+            token: lease.token,
+            ttl_ms: lease.ttl.as_millis() as u64,
+        };
+        if let Some(client) = self.clients.get(&lease.holder) {
+            if let Err(e) = client.send(&granted) {
+                warn!("Failed to grant a lock to client {}: {}", lease.holder, e);
+            }
+        }
+    }
+
+    /// Whether a sender already used a message id within the dedup window
+    pub fn is_duplicate(&mut self, sender: &Recipient, id: &str) -> bool {
+        if self.config.dedup.window_secs == 0 {
+            return false;
+        }
+        let window = Duration::from_secs(self.config.dedup.window_secs);
+        let Some(seen) = self.seen_ids.get_mut(sender) else {
+            return false;
+        };
+        let duplicate = seen.seen(id, Instant::now(), window);
+        if duplicate {
+            self.metrics.duplicate();
+        }
+        duplicate
+    }
+
+    /// Notes a message id a sender used, once its message was relayed
+    pub fn remember_id(&mut self, sender: &Recipient, id: &str) {
+        if self.config.dedup.window_secs == 0 {
+            return;
+        }
+        self.seen_ids.entry(sender.clone()).or_default().remember(
+            id,
+            Instant::now(),
+            self.config.dedup.max_ids,
+        );
+    }
+
+    /// The connections a recipient is reachable on right now
+    fn connections_of(&self, recipient: &Recipient) -> Vec<ClientId> {
+        match recipient {
+            Recipient::Client(clientId) if self.clients.contains_key(clientId) => vec![*clientId],
+            Recipient::Client(_) => Vec::new(),
+            Recipient::Identity(identity) => self
+                .clients
+                .values()
+                .filter(|client| client.identity.as_ref() == Some(identity))
+                .map(|client| client.clientId)
+                .collect(),
+        }
+    }
+
+    /// Whether a recipient already owes qos.max_pending Acks
+    pub fn unacked_full(&self, recipient: &Recipient) -> bool {
+        self.unacked
+            .get(recipient)
+            .is_some_and(|unacked| unacked.pending.len() >= self.config.qos.max_pending)
+    }
+
+    /// Queues an AtLeastOnce message for a recipient and sends it to every connection it has,
+    /// returns how many that was
+    pub fn send_reliable(
+        &mut self,
+        recipient: Recipient,
+        operation: ServerOperation,
+        sender: ClientId,
+        delivery: Delivery,
+    ) -> usize {
+        let seq = self
+            .unacked
+            .entry(recipient.clone())
+            .or_default()
+            .push(Pending {
+                operation,
+                sender,
+                priority: delivery.priority,
+                expires: delivery.expires,
+                attempts: 0,
+                due: None,
+            });
+        self.attempt(&recipient, seq, Instant::now())
+    }
+
+    /// broadcast, with every member owed an Ack. Members connected as the same identity share one
+    pub fn broadcast_reliable(
+        &mut self,
+        room: &Room,
+        operation: &ServerOperation,
+        sender: ClientId,
+        delivery: Delivery,
+    ) -> usize {
+        let started = Instant::now();
+        let recipients: HashSet<Recipient> = self
+            .rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .filter_map(|clientId| self.clients.get(clientId))
+            .map(|client| Recipient::of(client.clientId, client.identity.as_ref()))
+            .collect();
+        let delivered = recipients
+            .into_iter()
+            .map(|recipient| self.send_reliable(recipient, operation.clone(), sender, delivery))
+            .sum();
+        self.metrics.fanout(started.elapsed());
+        delivered
+    }
+
+    /// Sends a pending message wrapped in Reliable to the recipient's connections
+    fn attempt(&mut self, recipient: &Recipient, seq: u64, now: Instant) -> usize {
+        let connections = self.connections_of(recipient);
+        let retry_interval = Duration::from_millis(self.config.qos.retry_interval_ms);
+        let Some(pending) = self
+            .unacked
+            .get_mut(recipient)
+            .and_then(|unacked| unacked.pending.get_mut(&seq))
+        else {
+            return 0;
+        };
+        // counted while offline as well, or an identity that never comes back is owed forever
+        pending.attempts += 1;
+        pending.due = Some(now + retry_interval);
+        if connections.is_empty() {
+            return 0;
+        }
+        if pending.attempts > 1 {
+            self.metrics.redelivered();
+        }
+        let frame = ServerOperation::Reliable {
+            seq,
+            operation: Box::new(pending.operation.clone()),
+        };
+        let delivery = Delivery {
+            priority: pending.priority,
+            expires: pending.expires,
+            ..Default::default()
+        };
+        let mut sent = 0;
+        for clientId in connections {
+            match self.clients[&clientId].send_with(&frame, delivery) {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    error!("Failed to send to client {}: {}", clientId, e);
+                    self.metrics.dropped();
+                }
+            }
+        }
+        sent
+    }
+
+    /// Removes an acknowledged message, false when there was none with that seq
+    pub fn ack(&mut self, recipient: &Recipient, seq: u64) -> bool {
+        self.unacked
+            .get_mut(recipient)
+            .and_then(|unacked| unacked.ack(seq))
+            .is_some()
+    }
+
+    /// Resends what is due, gives up on what ran out of attempts or time
+    pub fn redeliver(&mut self, now: Instant) {
+        let due: Vec<(Recipient, u64)> = self
+            .unacked
+            .iter()
+            .flat_map(|(recipient, unacked)| {
+                unacked
+                    .pending
+                    .iter()
+                    .filter(|(_, pending)| pending.is_due(now))
+                    .map(move |(seq, _)| (recipient.clone(), *seq))
+            })
+            .collect();
+        for (recipient, seq) in due {
+            self.retry(&recipient, seq, now);
+        }
+    }
+
+    /// Resends everything a recipient is owed, whether it is due or not, e.g. after it reconnected
+    pub fn redeliver_to(&mut self, recipient: &Recipient, now: Instant) {
+        let seqs: Vec<u64> = self
+            .unacked
+            .get(recipient)
+            .map(|unacked| unacked.pending.keys().copied().collect())
+            .unwrap_or_default();
+        for seq in seqs {
+            self.retry(recipient, seq, now);
+        }
+    }
+
+    fn retry(&mut self, recipient: &Recipient, seq: u64, now: Instant) {
+        let max_attempts = self.config.qos.max_attempts;
+        let Some(pending) = self
+            .unacked
+            .get(recipient)
+            .and_then(|unacked| unacked.pending.get(&seq))
+        else {
+            return;
+        };
+        if pending.expired(now) || pending.attempts >= max_attempts {
+            self.give_up(recipient, seq);
+        } else {
+            self.attempt(recipient, seq, now);
+        }
+    }
+
+    /// Drops a pending message and tells its sender, if it is still around
+    fn give_up(&mut self, recipient: &Recipient, seq: u64) {
+        let Some(pending) = self
+            .unacked
+            .get_mut(recipient)
+            .and_then(|unacked| unacked.ack(seq))
+        else {
+            return;
+        };
+        warn!(
+            "Giving up on message {} for {:?} after {} attempts",
+            seq, recipient, pending.attempts
+        );
+        self.metrics.delivery_failed();
+        if let Some(sender) = self.clients.get(&pending.sender) {
+            let notice = ServerOperation::DeliveryFailed {
+                to: recipient.clone(),
+                attempts: pending.attempts,
+                operation: Box::new(pending.operation),
+            };
+            if let Err(e) = sender.send(&notice) {
+                warn!(
+                    "Failed to tell client {} of a failed delivery: {}",
+                    pending.sender, e
+                );
+            }
+        }
+    }
+
+    /// The ban keeping an identity out, expired bans are dropped on the way
+    pub fn ban_of(&mut self, identity: &Identity) -> Option<Ban> {
+        let ban = *self.bans.get(identity)?;
+        if ban.active(unix_now()) {
+            Some(ban)
+        } else {
+            self.unban(identity);
+            None
+        }
+    }
+
+    /// Tells the client why, then closes its connection once that notice is written
+    pub fn kick(&mut self, clientId: &ClientId, notice: &ServerOperation) -> bool {
+        let Some(client) = self.clients.get(clientId) else {
+            return false;
+        };
+        if let Err(e) = client.send(notice) {
+            warn!("Failed to notify kicked client {}: {}", clientId, e);
+        }
+        client.closed.cancel();
+        self.remove_client(clientId);
+        true
+    }
+
+    /// Sends every member RoomClosed and forgets the room along with its acl and history
+    pub fn close_room(&mut self, room: &Room) -> bool {
+        let Some(members) = self.rooms.remove(room) else {
+            return false;
+        };
+        let notice = ServerOperation::RoomClosed(room.clone());
+        for clientId in &members {
+            if let Some(client) = self.clients.get(clientId) {
+                if let Err(e) = client.send(&notice) {
+                    warn!(
+                        "Failed to tell client {} room {} closed: {}",
+                        clientId, room, e
+                    );
+                }
+            }
+        }
+        // a room from the config file goes back to the acl written there
+        let configured = self
+            .config
+            .rooms
+            .iter()
+            .find(|configured| configured.name == room.0)
+            .and_then(RoomAcl::from_config);
+        match configured {
+            Some(acl) => {
+                self.acls.insert(room.clone(), acl);
+            }
+            None => {
+                self.acls.remove(room);
+            }
+        }
+        self.history.remove(room);
+        self.sequences.retain(|(closed, _), _| closed != room);
+        self.muted.remove(room);
+        self.state.remove(room);
+        self.locks.retain(|(closed, _), _| closed != room);
+        // lock_tokens stay, a room reopened under the name must not hand out old tokens
+        self.barriers.retain(|(closed, _), _| closed != room);
+        self.tasks.retain(|(closed, _), _| closed != room);
+        if let Some(election) = self.elections.get_mut(room) {
+            election.clear();
+        }
+        let closed = room.clone();
+        self.persist("room removal", move |store| store.delete_room(&closed));
+        true
+    }
+}
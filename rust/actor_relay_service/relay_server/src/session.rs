@@ -0,0 +1,981 @@
+use crate::auth;
+use crate::barriers::Barrier;
+use crate::compression::{CompressionError, FrameCodec};
+use crate::error::OperationError;
+use crate::locks::Released;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::outbox::{self, Delivery};
+use crate::protocol::{
+    BatchResult, ClientId, ClientMessage, ClientOperation, Compression, Credentials, Identity,
+    Participants, Qos, Recipient, Room, ServerOperation,
+};
+use crate::room::RoomAcl;
+use crate::server::{unix_now, Ban, Client, Server};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn, Span};
+
+/// What the transport should do with the connection after a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Close,
+}
+
+/* The protocol side of a single connection, independent of how frames get in and out.
+    Transports read whole frames, hand them to handle_frame and write whatever shows up on the
+    receiver returned by open.
+*/
+pub struct Session {
+    pub clientId: ClientId,
+    // the authenticated principal, from credentials or the transport
+    pub identity: Option<Identity>,
+    pub authenticated: bool,
+    // nonce and claimed identity of an AuthChallenge awaiting its AuthResponse
+    challenge: Option<(String, Identity)>,
+    // transports stop reading once this is cancelled, by shutdown or by a kick
+    pub closed: CancellationToken,
+    pub metrics: Arc<Metrics>,
+    // transports run everything for this connection inside it
+    pub span: Span,
+    // transports encode outgoing frames with it, see compression.rs
+    pub codec: FrameCodec,
+    // codecs from ConnectAttempt, negotiated once the client is approved
+    offered: Vec<Compression>,
+    log_payloads: bool,
+    server: Arc<Mutex<Server>>,
+    tx: outbox::Sender,
+}
+
+impl Session {
+    /// Registers a new client, frames for it are queued on the returned receiver
+    pub async fn open(
+        server: Arc<Mutex<Server>>,
+        identity: Option<Identity>,
+    ) -> (Self, outbox::Receiver) {
+        let (tx, rx) = outbox::channel();
+        let clientId = ClientId::new();
+        let (closed, metrics, log_payloads, codec) = {
+            let mut guard = server.lock().await;
+            let closed = guard.shutdown.stop_reading.child_token();
+            guard.clients.insert(
+                clientId,
+                Client::new(clientId, tx.clone(), identity.clone(), closed.clone()),
+            );
+            let codec = FrameCodec::new(
+                &guard.config.compression,
+                guard.config.limits.max_frame_bytes,
+                Arc::clone(&guard.metrics),
+            );
+            (
+                closed,
+                Arc::clone(&guard.metrics),
+                guard.config.logging.payloads,
+                codec,
+            )
+        };
+        let rx = rx.with_metrics(Arc::clone(&metrics));
+        let span = logging::client_span(clientId, identity.as_ref());
+        (
+            Self {
+                clientId,
+                identity,
+                authenticated: false,
+                challenge: None,
+                closed,
+                metrics,
+                span,
+                codec,
+                offered: Vec::new(),
+                log_payloads,
+                server,
+                tx,
+            },
+            rx,
+        )
+    }
+
+    pub async fn handle_frame(&mut self, frame: &str) -> Flow {
+        self.metrics.received(frame.len());
+        let frame = match self.codec.decode(frame) {
+            Ok(frame) => frame,
+            Err(err @ CompressionError::NotNegotiated(_)) => {
+                warn!("Refused frame from client {}: {}", self.clientId, err);
+                self.send(&ServerOperation::Nack {
+                    reason: err.to_string(),
+                })
+                .await;
+                return Flow::Continue;
+            }
+            Err(err) => {
+                error!("Failed to decompress the message: {}", err);
+                self.metrics.parse_error();
+                return Flow::Close;
+            }
+        };
+        let json_message = frame.trim().trim_end_matches('\n');
+        if self.log_payloads {
+            debug!(frame = json_message, "Received frame");
+        }
+        let message: Result<ClientMessage, _> = serde_json::from_str(json_message);
+        match message {
+            Ok(ClientMessage {
+                clientOperation: ClientOperation::Batch(operations),
+                ..
+            }) => self.handle_batch(operations).await,
+            Ok(client_message) => match self.handle_operation(client_message.clientOperation).await
+            {
+                Ok(flow) => flow,
+                Err(err) => {
+                    warn!("Refused operation from client {}: {}", self.clientId, err);
+                    self.send(&ServerOperation::Nack {
+                        reason: err.to_string(),
+                    })
+                    .await;
+                    Flow::Continue
+                }
+            },
+            Err(err) => {
+                error!("Failed to parse the message: {}", err);
+                self.metrics.parse_error();
+                Flow::Close
+            }
+        }
+    }
+
+    /// Queues an operation for this session's own client
+    async fn send(&self, operation: &ServerOperation) {
+        let server = self.server.lock().await;
+        if let Some(client) = server.clients.get(&self.clientId) {
+            if let Err(e) = client.send(operation) {
+                error!("Failed to send to client {}: {}", self.clientId, e);
+                self.metrics.dropped();
+            }
+        }
+    }
+
+    /// Applies a Batch in order, an operation that closes the connection skips the rest
+    async fn handle_batch(&mut self, operations: Vec<ClientOperation>) -> Flow {
+        let mut flow = Flow::Continue;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            if flow == Flow::Close {
+                results.push(BatchResult::Skipped);
+                continue;
+            }
+            match self.handle_operation(operation).await {
+                Ok(next) => {
+                    flow = next;
+                    results.push(BatchResult::Ok);
+                }
+                Err(err) => {
+                    warn!(
+                        "Refused batched operation from client {}: {}",
+                        self.clientId, err
+                    );
+                    results.push(BatchResult::Failed {
+                        reason: err.to_string(),
+                    });
+                }
+            }
+        }
+        self.send(&ServerOperation::BatchResults(results)).await;
+        flow
+    }
+
+    async fn handle_operation(
+        &mut self,
+        operation: ClientOperation,
+    ) -> Result<Flow, OperationError> {
+        let clientId = self.clientId;
+        let allowed_before_auth = matches!(
+            operation,
+            ClientOperation::ConnectAttempt { .. }
+                | ClientOperation::AuthResponse { .. }
+                | ClientOperation::Disconnect
+        );
+        if !self.authenticated && !allowed_before_auth && self.auth_required().await {
+            return Err(OperationError::NotAuthenticated);
+        }
+
+        match operation {
+            ClientOperation::Batch(_) => return Err(OperationError::NestedBatch),
+            ClientOperation::Message {
+                room,
+                channel,
+                message,
+                priority,
+                ttl_ms,
+                qos,
+                id,
+            } => {
+                if self.log_payloads {
+                    debug!(%room, %channel, %message, "Received client message");
+                } else {
+                    debug!(%room, %channel, "Received client message");
+                }
+                // We need to send this client message out to every single stream in all the tokio spawns
+                let mut server_guard = self.server.lock().await;
+                // nobody would hear it, and sequencing it would keep the name around for good
+                if !server_guard.rooms.contains_key(&room) {
+                    return Err(OperationError::NoSuchRoom(room));
+                }
+                if let Some(acl) = server_guard.acls.get(&room) {
+                    let member = server_guard
+                        .rooms
+                        .get(&room)
+                        .is_some_and(|members| members.contains(&clientId));
+                    if !member {
+                        return Err(OperationError::NotMember(room));
+                    }
+                    if !acl.can_send(self.identity.as_ref()) {
+                        return Err(OperationError::ReadOnly(room));
+                    }
+                }
+                let muted = server_guard
+                    .muted
+                    .get(&room)
+                    .is_some_and(|muted| muted.contains(&clientId));
+                if muted {
+                    return Err(OperationError::Muted(room));
+                }
+                if self.answer_duplicate(&mut server_guard, id.as_deref()) {
+                    return Ok(Flow::Continue);
+                }
+                if qos == Qos::AtLeastOnce {
+                    let full = server_guard
+                        .members_of(&room)
+                        .into_iter()
+                        .map(|(_, member)| member)
+                        .find(|member| server_guard.unacked_full(member));
+                    if let Some(member) = full {
+                        let max_pending = server_guard.config.qos.max_pending;
+                        return Err(OperationError::TooManyPending(member, max_pending));
+                    }
+                }
+                let seq = server_guard.next_seq(&room, &channel);
+                let operation = ServerOperation::RoomMessage {
+                    sender: clientId,
+                    room: room.clone(),
+                    channel,
+                    message,
+                    seq,
+                };
+                server_guard.record_history(&room, &operation);
+                let delivery = Delivery::new(priority.for_client(), ttl_ms);
+                match qos {
+                    Qos::AtMostOnce => server_guard.broadcast(&room, &operation, delivery),
+                    Qos::AtLeastOnce => {
+                        server_guard.broadcast_reliable(&room, &operation, clientId, delivery)
+                    }
+                };
+                if let Some(id) = &id {
+                    server_guard.remember_id(&self.publisher(), id);
+                }
+            }
+            ClientOperation::DirectMessage {
+                to,
+                channel,
+                message,
+                priority,
+                ttl_ms,
+                qos,
+                id,
+            } => {
+                if self.log_payloads {
+                    debug!(%to, %channel, %message, "Received direct message");
+                } else {
+                    debug!(%to, %channel, "Received direct message");
+                }
+                let operation = ServerOperation::DirectMessage {
+                    sender: clientId,
+                    from: self.identity.clone(),
+                    channel,
+                    message,
+                    queued_at: None,
+                };
+                let priority = priority.for_client();
+                let mut server = self.server.lock().await;
+                if self.answer_duplicate(&mut server, id.as_deref()) {
+                    return Ok(Flow::Continue);
+                }
+                let delivery = Delivery::new(priority, ttl_ms);
+                if qos == Qos::AtLeastOnce {
+                    let online = server
+                        .clients
+                        .values()
+                        .any(|client| client.identity.as_ref() == Some(&to));
+                    if !online && !server.is_registered(&to) {
+                        return Err(OperationError::NoMailbox(to));
+                    }
+                    let recipient = Recipient::Identity(to);
+                    if server.unacked_full(&recipient) {
+                        let max_pending = server.config.qos.max_pending;
+                        return Err(OperationError::TooManyPending(recipient, max_pending));
+                    }
+                    server.send_reliable(recipient, operation, clientId, delivery);
+                } else if server.send_to_identity(&to, &operation, delivery) == 0 {
+                    if !server.post(&to, operation, priority, ttl_ms) {
+                        return Err(OperationError::NoMailbox(to));
+                    }
+                    debug!("{} is offline, queued the direct message", to);
+                }
+                if let Some(id) = &id {
+                    server.remember_id(&self.publisher(), id);
+                }
+            }
+            ClientOperation::Ack(seq) => {
+                let recipient = Recipient::of(clientId, self.identity.as_ref());
+                // redeliveries make late and repeated acks normal
+                if !self.server.lock().await.ack(&recipient, seq) {
+                    debug!("Ack for {} which is not pending", seq);
+                }
+            }
+            ClientOperation::Retransmit {
+                room,
+                channel,
+                from,
+                to,
+            } => {
+                if from == 0 || from > to {
+                    return Err(OperationError::BadRange(from, to));
+                }
+                let server = self.server.lock().await;
+                if server.config.history.room_messages == 0 {
+                    return Err(OperationError::NoHistory);
+                }
+                if server.acls.contains_key(&room) {
+                    let member = server
+                        .rooms
+                        .get(&room)
+                        .is_some_and(|members| members.contains(&clientId));
+                    if !member {
+                        return Err(OperationError::NotMember(room));
+                    }
+                }
+                let (found, gap) = server.retransmit(&room, &channel, from, to);
+                debug!(
+                    "Retransmitting {} messages of {}/{} to client {}, missing {:?}",
+                    found.len(),
+                    room,
+                    channel,
+                    clientId,
+                    gap
+                );
+                let Some(client) = server.clients.get(&clientId) else {
+                    return Ok(Flow::Continue);
+                };
+                // the gap first, it is older than anything that follows
+                let gap = gap.map(|(from, to)| ServerOperation::HistoryGap {
+                    room,
+                    channel,
+                    from,
+                    to,
+                });
+                for operation in gap.iter().chain(&found) {
+                    if let Err(e) = client.send(operation) {
+                        error!("Failed to retransmit to client {}: {}", clientId, e);
+                    }
+                }
+            }
+            ClientOperation::Disconnect => {
+                info!("The client has terminated the connection.");
+                return Ok(Flow::Close);
+            }
+            ClientOperation::ConnectAttempt {
+                credentials,
+                compression,
+            } => {
+                debug!("In ClientConnectAttempt");
+                self.offered = compression;
+                return Ok(self.connect(credentials).await);
+            }
+            ClientOperation::AuthResponse { mac } => {
+                let (nonce, identity) = self.challenge.take().ok_or(OperationError::NoChallenge)?;
+                let secret = self.server.lock().await.config.auth.secret.clone();
+                return Ok(match secret {
+                    Some(secret) if auth::verify(&secret, &nonce, &identity, &mac) => {
+                        self.approve(Some(identity)).await
+                    }
+                    _ => self.reject("challenge response did not match").await,
+                });
+            }
+            ClientOperation::Kick(target) => {
+                let by = self.require_admin().await?;
+                let mut server = self.server.lock().await;
+                if !server.kick(&target, &ServerOperation::Kicked { by: by.clone() }) {
+                    return Err(OperationError::NoSuchClient(target));
+                }
+                warn!("{} kicked client {}", by, target);
+            }
+            ClientOperation::Ban {
+                identity,
+                duration_secs,
+            } => {
+                let by = self.require_admin().await?;
+                let ban = Ban {
+                    until: duration_secs.map(|secs| unix_now().saturating_add(secs)),
+                };
+                let mut server = self.server.lock().await;
+                warn!("{} banned {} until {:?}", by, identity, ban.until);
+                server.ban(identity.clone(), ban);
+                let banned: Vec<ClientId> = server
+                    .clients
+                    .values()
+                    .filter(|client| client.identity.as_ref() == Some(&identity))
+                    .map(|client| client.clientId)
+                    .collect();
+                for target in banned {
+                    server.kick(&target, &ServerOperation::Banned { until: ban.until });
+                }
+            }
+            ClientOperation::Unban(identity) => {
+                let by = self.require_admin().await?;
+                info!("{} unbanned {}", by, identity);
+                self.server.lock().await.unban(&identity);
+            }
+            ClientOperation::Mute { room, client } => {
+                let by = self.require_admin().await?;
+                let mut server = self.server.lock().await;
+                let target = server
+                    .clients
+                    .get(&client)
+                    .ok_or(OperationError::NoSuchClient(client))?;
+                if let Err(e) = target.send(&ServerOperation::Muted { room: room.clone() }) {
+                    warn!("Failed to notify muted client {}: {}", client, e);
+                }
+                info!("{} muted client {} in room {}", by, client, room);
+                server.muted.entry(room).or_default().insert(client);
+            }
+            ClientOperation::Unmute { room, client } => {
+                let by = self.require_admin().await?;
+                let mut server = self.server.lock().await;
+                let target = server
+                    .clients
+                    .get(&client)
+                    .ok_or(OperationError::NoSuchClient(client))?;
+                if let Err(e) = target.send(&ServerOperation::Unmuted { room: room.clone() }) {
+                    warn!("Failed to notify unmuted client {}: {}", client, e);
+                }
+                info!("{} unmuted client {} in room {}", by, client, room);
+                if let Some(muted) = server.muted.get_mut(&room) {
+                    muted.remove(&client);
+                }
+            }
+            ClientOperation::CloseRoom(room) => {
+                let by = self.require_admin().await?;
+                if !self.server.lock().await.close_room(&room) {
+                    return Err(OperationError::NoSuchRoom(room));
+                }
+                warn!("{} closed room {}", by, room);
+            }
+            ClientOperation::RoomJoin(room) => self.join(room, None).await?,
+            ClientOperation::RoomJoinWithPassword { room, password } => {
+                self.join(room, Some(&password)).await?
+            }
+            ClientOperation::RoomCreate {
+                room,
+                password,
+                invite_only,
+            } => {
+                let owner = self
+                    .identity
+                    .clone()
+                    .ok_or(OperationError::IdentityRequired)?;
+                {
+                    let mut server = self.server.lock().await;
+                    if server.room_exists(&room) {
+                        return Err(OperationError::RoomExists(room));
+                    }
+                    // the creator joins right away, so do not leave an acl behind it can not join
+                    let max_rooms = server.config.limits.max_rooms_per_client;
+                    if server.rooms_of(&clientId) >= max_rooms {
+                        return Err(OperationError::TooManyRooms(max_rooms));
+                    }
+                    info!(
+                        "Client {} created room {} owned by {}",
+                        clientId, room, owner
+                    );
+                    server.acls.insert(
+                        room.clone(),
+                        RoomAcl {
+                            owner: Some(owner),
+                            password,
+                            invite_only,
+                            ..Default::default()
+                        },
+                    );
+                    server.save_acl(&room);
+                }
+                self.join(room, None).await?;
+            }
+            ClientOperation::RoomInvite { room, identity } => {
+                let mut server = self.server.lock().await;
+                let acl = server
+                    .acls
+                    .get_mut(&room)
+                    .ok_or_else(|| OperationError::NotOwner(room.clone()))?;
+                acl.check_owner(&room, self.identity.as_ref())?;
+                info!("{} invited to room {}", identity, room);
+                acl.invited.insert(identity);
+                server.save_acl(&room);
+            }
+            ClientOperation::RoomSetReadOnly {
+                room,
+                identity,
+                read_only,
+            } => {
+                let mut server = self.server.lock().await;
+                let acl = server
+                    .acls
+                    .get_mut(&room)
+                    .ok_or_else(|| OperationError::NotOwner(room.clone()))?;
+                acl.check_owner(&room, self.identity.as_ref())?;
+                info!("{} read only in room {}: {}", identity, room, read_only);
+                if read_only {
+                    acl.read_only.insert(identity);
+                } else {
+                    acl.read_only.remove(&identity);
+                }
+                server.save_acl(&room);
+            }
+            ClientOperation::RoomLeave(room) => {
+                debug!("Client {} leaving room {}", clientId, room);
+                let mut server = self.server.lock().await;
+                if let Some(clients_in_room) = server.rooms.get_mut(&room) {
+                    clients_in_room.remove(&clientId);
+                    info!("Client {} left room {}", clientId, room);
+                }
+                // leaving also ends watching the room's state and waiting at its barriers
+                if let Some(state) = server.state.get_mut(&room) {
+                    state.unwatch(&clientId, None);
+                }
+                server.leave_barriers(&clientId, Some(&room));
+                // and holding the rest back in its tasks
+                server.advance_tasks(Some(&room));
+                server.leave_elections(&clientId, Some(&room));
+                server.prune_room(&room);
+            }
+            ClientOperation::StateSet {
+                room,
+                key,
+                value,
+                if_version,
+            } => {
+                let mut server = self.server.lock().await;
+                self.check_room_access(&server, &room, true)?;
+                let limits = server.config.state.clone();
+                if value.len() > limits.max_value_bytes {
+                    return Err(OperationError::ValueTooLarge(limits.max_value_bytes));
+                }
+                let (actual, keys) = server.state.get(&room).map_or((0, 0), |state| {
+                    (state.version_of(&key), state.entries.len())
+                });
+                check_version(&key, if_version, actual)?;
+                if actual == 0 && keys >= limits.max_keys_per_room {
+                    return Err(OperationError::TooManyKeys(room, limits.max_keys_per_room));
+                }
+                let version = server.set_state(&room, &key, value, clientId);
+                debug!(
+                    "Client {} set {}/{} to version {}",
+                    clientId, room, key, version
+                );
+            }
+            ClientOperation::StateGet { room, key } => {
+                let server = self.server.lock().await;
+                self.check_room_access(&server, &room, false)?;
+                let entry = server
+                    .state
+                    .get(&room)
+                    .and_then(|state| state.entries.get(&key));
+                let reply = ServerOperation::StateValue {
+                    room: room.clone(),
+                    key: key.clone(),
+                    value: entry.map(|entry| entry.value.clone()),
+                    version: entry.map_or(0, |entry| entry.version),
+                };
+                drop(server);
+                self.send(&reply).await;
+            }
+            ClientOperation::StateDelete {
+                room,
+                key,
+                if_version,
+            } => {
+                let mut server = self.server.lock().await;
+                self.check_room_access(&server, &room, true)?;
+                let actual = server
+                    .state
+                    .get(&room)
+                    .map_or(0, |state| state.version_of(&key));
+                check_version(&key, if_version, actual)?;
+                if let Some(version) = server.delete_state(&room, &key, clientId) {
+                    debug!(
+                        "Client {} deleted {}/{} at version {}",
+                        clientId, room, key, version
+                    );
+                }
+            }
+            ClientOperation::StateWatch { room, key } => {
+                let mut server = self.server.lock().await;
+                self.check_room_access(&server, &room, false)?;
+                let state = server.state.entry(room.clone()).or_default();
+                state.watch(clientId, key.clone());
+                let snapshot = state.snapshot(key.as_deref());
+                let Some(client) = server.clients.get(&clientId) else {
+                    return Ok(Flow::Continue);
+                };
+                for (key, entry) in snapshot {
+                    let current = ServerOperation::StateChanged {
+                        room: room.clone(),
+                        key,
+                        value: Some(entry.value),
+                        version: entry.version,
+                        by: None,
+                    };
+                    if let Err(e) = client.send(&current) {
+                        error!("Failed to send state to client {}: {}", clientId, e);
+                    }
+                }
+            }
+            ClientOperation::LockAcquire { room, name, ttl_ms } => {
+                let mut server = self.server.lock().await;
+                self.check_room_access(&server, &room, true)?;
+                let max_ttl_ms = server.config.locks.max_ttl_ms;
+                if ttl_ms == 0 || ttl_ms > max_ttl_ms {
+                    return Err(OperationError::BadTtl(max_ttl_ms));
+                }
+                server.acquire_lock(&room, &name, clientId, Duration::from_millis(ttl_ms));
+            }
+            ClientOperation::BarrierWait {
+                room,
+                name,
+                count_or_members,
+                timeout_ms,
+            } => {
+                let mut server = self.server.lock().await;
+                self.check_room_access(&server, &room, false)?;
+                let max_timeout_ms = server.config.barriers.max_timeout_ms;
+                if timeout_ms == 0 || timeout_ms > max_timeout_ms {
+                    return Err(OperationError::BadTimeout(max_timeout_ms));
+                }
+                let empty = match &count_or_members {
+                    Participants::Count(count) => *count == 0,
+                    Participants::Members(members) => members.is_empty(),
+                };
+                if empty {
+                    return Err(OperationError::NoParticipants);
+                }
+                let who = self.publisher();
+                let key = (room.clone(), name.clone());
+                match server.barriers.get(&key) {
+                    Some(barrier) if barrier.participants != count_or_members => {
+                        return Err(OperationError::BarrierMismatch(name));
+                    }
+                    Some(barrier) if !barrier.expects(&who) => {
+                        return Err(OperationError::NotParticipant(name));
+                    }
+                    Some(_) => {}
+                    None => {
+                        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+                        let barrier = Barrier::new(count_or_members, deadline);
+                        if !barrier.expects(&who) {
+                            return Err(OperationError::NotParticipant(name));
+                        }
+                        server.barriers.insert(key, barrier);
+                        server.deadlines_changed.notify_one();
+                    }
+                }
+                server.wait_at_barrier(&room, &name, clientId, who);
+            }
+            ClientOperation::TaskDefine { room, name, steps } => {
+                let mut server = self.server.lock().await;
+                self.check_room_access(&server, &room, true)?;
+                let max_steps = server.config.tasks.max_steps;
+                if steps.is_empty() {
+                    return Err(OperationError::NoSteps);
+                }
+                if steps.len() > max_steps {
+                    return Err(OperationError::TooManySteps(max_steps));
+                }
+                server.define_task(&room, &name, steps);
+            }
+            ClientOperation::TaskReport { room, name, step } => {
+                let mut server = self.server.lock().await;
+                self.check_room_access(&server, &room, false)?;
+                let member = server
+                    .rooms
+                    .get(&room)
+                    .is_some_and(|members| members.contains(&clientId));
+                if !member {
+                    return Err(OperationError::NotMember(room));
+                }
+                let Some(task) = server.tasks.get(&(room.clone(), name.clone())) else {
+                    return Err(OperationError::NoSuchTask(name));
+                };
+                if step > task.steps.len() {
+                    return Err(OperationError::NoSuchStep(name, task.steps.len()));
+                }
+                server.report_task_step(&room, &name, self.publisher(), step);
+            }
+            ClientOperation::TaskQuery { room, name } => {
+                let server = self.server.lock().await;
+                self.check_room_access(&server, &room, false)?;
+                let Some(progress) = server.task_progress(&room, &name) else {
+                    return Err(OperationError::NoSuchTask(name));
+                };
+                drop(server);
+                self.send(&progress).await;
+            }
+            ClientOperation::LockRelease { room, name } => {
+                let mut server = self.server.lock().await;
+                if server.release_lock(&room, &name, clientId) == Released::NotHeld {
+                    return Err(OperationError::NotLockHolder(name));
+                }
+            }
+            ClientOperation::StateUnwatch { room, key } => {
+                let mut server = self.server.lock().await;
+                if let Some(state) = server.state.get_mut(&room) {
+                    state.unwatch(&clientId, key.as_deref());
+                }
+                server.prune_room(&room);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// A room's state and locks are open like the room: private rooms share them with their
+    /// members and only those who can send there change state or take locks
+    fn check_room_access(
+        &self,
+        server: &Server,
+        room: &Room,
+        write: bool,
+    ) -> Result<(), OperationError> {
+        if !server.rooms.contains_key(room) {
+            return Err(OperationError::NoSuchRoom(room.clone()));
+        }
+        if let Some(acl) = server.acls.get(room) {
+            let member = server
+                .rooms
+                .get(room)
+                .is_some_and(|members| members.contains(&self.clientId));
+            if !member {
+                return Err(OperationError::NotMember(room.clone()));
+            }
+            if write && !acl.can_send(self.identity.as_ref()) {
+                return Err(OperationError::ReadOnly(room.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Who this client counts as for dedup, its identity when it has one
+    fn publisher(&self) -> Recipient {
+        Recipient::of(self.clientId, self.identity.as_ref())
+    }
+
+    /// Tells the client when it already sent a message with this id, true if it did
+    fn answer_duplicate(&self, server: &mut Server, id: Option<&str>) -> bool {
+        let Some(id) = id else {
+            return false;
+        };
+        if !server.is_duplicate(&self.publisher(), id) {
+            return false;
+        }
+        debug!(
+            "Dropping duplicate message {} from client {}",
+            id, self.clientId
+        );
+        if let Some(client) = server.clients.get(&self.clientId) {
+            if let Err(e) = client.send(&ServerOperation::Duplicate { id: id.to_string() }) {
+                error!("Failed to send to client {}: {}", self.clientId, e);
+            }
+        }
+        true
+    }
+
+    /// The caller's identity if it is listed in auth.admins
+    async fn require_admin(&self) -> Result<Identity, OperationError> {
+        let identity = self.identity.clone().ok_or(OperationError::NotAdmin)?;
+        let server = self.server.lock().await;
+        if server
+            .config
+            .auth
+            .admins
+            .iter()
+            .any(|admin| **admin == *identity)
+        {
+            Ok(identity)
+        } else {
+            Err(OperationError::NotAdmin)
+        }
+    }
+
+    async fn auth_required(&self) -> bool {
+        self.server.lock().await.config.auth.required
+    }
+
+    async fn connect(&mut self, credentials: Option<Credentials>) -> Flow {
+        let auth = self.server.lock().await.config.auth.clone();
+        match credentials {
+            Some(Credentials::Token(token)) => match auth::identity_for_token(&auth, &token) {
+                Some(identity) => self.approve(Some(identity)).await,
+                None => self.reject("unknown token").await,
+            },
+            Some(Credentials::Hmac { identity }) => {
+                if auth.secret.is_none() {
+                    return self.reject("hmac authentication is not enabled").await;
+                }
+                let nonce = auth::new_nonce();
+                self.challenge = Some((nonce.clone(), identity));
+                self.send(&ServerOperation::AuthChallenge { nonce }).await;
+                Flow::Continue
+            }
+            // a client certificate already said who this is
+            None if self.identity.is_some() => self.approve(None).await,
+            None if auth.required => self.reject("credentials required").await,
+            None => self.approve(None).await,
+        }
+    }
+
+    /// Marks the session authenticated, as `identity` when given, and hands out the client id
+    async fn approve(&mut self, identity: Option<Identity>) -> Flow {
+        let clientId = self.clientId;
+        if identity.is_some() {
+            self.identity = identity;
+        }
+        self.authenticated = true;
+        let mut server = self.server.lock().await;
+        if let Some(identity) = &self.identity {
+            if let Some(ban) = server.ban_of(identity) {
+                drop(server);
+                let reason = match ban.until {
+                    Some(until) => format!("banned until {}", until),
+                    None => "banned".to_string(),
+                };
+                return self.reject(&reason).await;
+            }
+            self.span.record("identity", identity.as_str());
+            server.saw_identity(identity);
+            info!("Client {} authenticated as {}", clientId, identity);
+        }
+
+        let mail = match &self.identity {
+            Some(identity) => server.take_mail(identity),
+            None => Vec::new(),
+        };
+        let client = server
+            .clients
+            .entry(clientId)
+            .or_insert_with(|| Client::new(clientId, self.tx.clone(), None, self.closed.clone()));
+        client.identity = self.identity.clone();
+
+        // send client its new ID back
+        match client.send(&ServerOperation::ClientConnectApproved(clientId)) {
+            Ok(()) => debug!("Sent client response"),
+            Err(e) => error!("Failed to send approval to client {}: {}", clientId, e),
+        }
+        if let Some(codec) = self.codec.negotiate(&self.offered) {
+            debug!("Compressing frames to client {} with {:?}", clientId, codec);
+            let enabled = ServerOperation::CompressionEnabled {
+                codec,
+                min_bytes: self.codec.min_bytes(),
+            };
+            if let Err(e) = client.send(&enabled) {
+                error!("Failed to send compression to client {}: {}", clientId, e);
+            }
+        }
+        // then whatever was sent to it while it was away
+        if !mail.is_empty() {
+            info!(
+                "Delivering {} queued direct messages to {}",
+                mail.len(),
+                clientId
+            );
+        }
+        for (operation, delivery) in &mail {
+            if let Err(e) = client.send_with(operation, *delivery) {
+                error!("Failed to deliver mail to client {}: {}", clientId, e);
+            }
+        }
+        // and anything it had not acknowledged before it went away
+        if let Some(identity) = self.identity.clone() {
+            server.redeliver_to(&Recipient::Identity(identity), Instant::now());
+        }
+        Flow::Continue
+    }
+
+    async fn reject(&mut self, reason: &str) -> Flow {
+        warn!("Rejecting client {}: {}", self.clientId, reason);
+        self.send(&ServerOperation::ConnectRejected {
+            reason: reason.to_string(),
+        })
+        .await;
+        Flow::Close
+    }
+
+    async fn join(&mut self, room: Room, password: Option<&str>) -> Result<(), OperationError> {
+        let clientId = self.clientId;
+        debug!("Client {} joining room {}", clientId, room);
+        let mut server = self.server.lock().await;
+        let already_member = server
+            .rooms
+            .get(&room)
+            .is_some_and(|members| members.contains(&clientId));
+        if already_member {
+            return Ok(());
+        }
+        if let Some(acl) = server.acls.get(&room) {
+            acl.check_join(&room, self.identity.as_ref(), password)?;
+        }
+        let max_rooms = server.config.limits.max_rooms_per_client;
+        if server.rooms_of(&clientId) >= max_rooms {
+            warn!(
+                "Client {} is already in {} rooms, not joining {}",
+                clientId, max_rooms, room
+            );
+            return Err(OperationError::TooManyRooms(max_rooms));
+        }
+        server
+            .rooms
+            .entry(room.clone())
+            .or_default()
+            .insert(clientId);
+        info!("Client {} joined room {}", clientId, room);
+
+        // catch the new member up on what it missed
+        if let (Some(history), Some(client)) =
+            (server.history.get(&room), server.clients.get(&clientId))
+        {
+            for operation in history {
+                if let Err(e) = client.send(operation) {
+                    error!("Failed to replay history to client {}: {}", clientId, e);
+                }
+            }
+        }
+        server.join_election(&room, clientId);
+        Ok(())
+    }
+
+    /// Removes the client from the server, its writer finishes once the queue is empty
+    pub async fn close(self) {
+        self.server.lock().await.remove_client(&self.clientId);
+        warn!("Client {} removed", self.clientId);
+    }
+}
+
+/// Refuses a conditional write when the key moved on from the version the client expected
+fn check_version(key: &str, expected: Option<u64>, actual: u64) -> Result<(), OperationError> {
+    match expected {
+        Some(expected) if expected != actual => Err(OperationError::VersionMismatch {
+            key: key.to_string(),
+            expected,
+            actual,
+        }),
+        _ => Ok(()),
+    }
+}
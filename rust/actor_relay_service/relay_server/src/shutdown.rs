@@ -0,0 +1,105 @@
+use crate::protocol::ServerOperation;
+use crate::server::Server;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+/* Shared by the accept loop and every connection task.
+    Shutting down happens in stages so clients hear about it before their sockets close:
+    stop accepting, tell everyone, stop reading, then let the writers drain what is queued and
+    the store catch up.
+*/
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    pub stop_accepting: CancellationToken,
+    pub stop_reading: CancellationToken,
+    pub tasks: TaskTracker,
+}
+
+/// Resolves on ctrl-c, or on SIGTERM where there is such a thing
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received ctrl-c"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+/// Runs the shutdown stages, returns false if connections were still draining at the timeout
+pub async fn graceful(server: &Arc<Mutex<Server>>) -> bool {
+    let (shutdown, reconnect_after, drain_timeout) = {
+        let server = server.lock().await;
+        (
+            server.shutdown.clone(),
+            server.config.shutdown.reconnect_after_secs,
+            Duration::from_secs(server.config.shutdown.drain_timeout_secs),
+        )
+    };
+
+    shutdown.stop_accepting.cancel();
+
+    {
+        let server = server.lock().await;
+        info!(
+            "Shutting down, telling {} clients to reconnect after {}s",
+            server.clients.len(),
+            reconnect_after
+        );
+        let notice = ServerOperation::ServerShuttingDown { reconnect_after };
+        for (clientId, client) in &server.clients {
+            if let Err(e) = client.send(&notice) {
+                warn!("Could not notify client {} of shutdown: {}", clientId, e);
+            }
+        }
+    }
+
+    // readers drop their clients on the way out, which lets each writer finish its queue and close
+    shutdown.stop_reading.cancel();
+    shutdown.tasks.close();
+
+    let drained = match tokio::time::timeout(drain_timeout, shutdown.tasks.wait()).await {
+        Ok(()) => {
+            info!("All connections drained");
+            true
+        }
+        Err(_) => {
+            warn!(
+                "{} connection tasks still running after {:?}, exiting anyway",
+                shutdown.tasks.len(),
+                drain_timeout
+            );
+            false
+        }
+    };
+
+    // whatever changed last is still on its way to the store
+    let store = server.lock().await.store.clone();
+    if let Some(store) = store {
+        store.flush().await;
+    }
+    drained
+}
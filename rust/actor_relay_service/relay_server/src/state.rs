@@ -0,0 +1,120 @@
+use crate::protocol::ClientId;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/* Shared key/value state per room.
+    Facts a group agrees on, like the current pull target, live in the relay instead of being
+    re-broadcast over and over. Every change gets the room's next version, so versions only grow
+    within a room and StateSet or StateDelete can be made conditional on the version a client
+    last saw. Clients that watch a key, or the whole room, get StateChanged for every change.
+    Values are kept in the store when there is one; watches only last as long as the connection.
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: String,
+    pub version: u64,
+}
+
+/// What a client watches in one room
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Watch {
+    Room,
+    Keys(HashSet<String>),
+}
+
+impl Watch {
+    fn covers(&self, key: &str) -> bool {
+        match self {
+            Watch::Room => true,
+            Watch::Keys(keys) => keys.contains(key),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoomState {
+    pub entries: BTreeMap<String, Entry>,
+    // version of the last change, deletes included
+    pub version: u64,
+    pub watchers: HashMap<ClientId, Watch>,
+}
+
+impl RoomState {
+    /// The version a conditional write has to match, 0 for a key that is not set
+    pub fn version_of(&self, key: &str) -> u64 {
+        self.entries.get(key).map_or(0, |entry| entry.version)
+    }
+
+    pub fn set(&mut self, key: &str, value: String) -> u64 {
+        self.version += 1;
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                value,
+                version: self.version,
+            },
+        );
+        self.version
+    }
+
+    /// The version of the delete, None when the key was not set
+    pub fn delete(&mut self, key: &str) -> Option<u64> {
+        self.entries.remove(key)?;
+        self.version += 1;
+        Some(self.version)
+    }
+
+    /// Never written to and watched by nobody, a room that only has this has no state to keep
+    pub fn is_unused(&self) -> bool {
+        self.version == 0 && self.watchers.is_empty()
+    }
+
+    /// Watches `key`, or every key when None. Watching the room covers the single keys
+    pub fn watch(&mut self, clientId: ClientId, key: Option<String>) {
+        let watch = self
+            .watchers
+            .entry(clientId)
+            .or_insert_with(|| Watch::Keys(HashSet::new()));
+        match (key, watch) {
+            (None, watch) => *watch = Watch::Room,
+            (Some(key), Watch::Keys(keys)) => {
+                keys.insert(key);
+            }
+            (Some(_), Watch::Room) => {}
+        }
+    }
+
+    /// Stops watching `key`, or everything when None
+    pub fn unwatch(&mut self, clientId: &ClientId, key: Option<&str>) {
+        match (key, self.watchers.get_mut(clientId)) {
+            (Some(key), Some(Watch::Keys(keys))) => {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.watchers.remove(clientId);
+                }
+            }
+            // a room watch has no single keys to drop
+            (Some(_), _) => {}
+            (None, _) => {
+                self.watchers.remove(clientId);
+            }
+        }
+    }
+
+    pub fn watchers_of(&self, key: &str) -> Vec<ClientId> {
+        self.watchers
+            .iter()
+            .filter(|(_, watch)| watch.covers(key))
+            .map(|(clientId, _)| *clientId)
+            .collect()
+    }
+
+    /// The entries a new watch starts from
+    pub fn snapshot(&self, key: Option<&str>) -> Vec<(String, Entry)> {
+        self.entries
+            .iter()
+            .filter(|(k, _)| key.is_none_or(|key| key == k.as_str()))
+            .map(|(k, entry)| (k.clone(), entry.clone()))
+            .collect()
+    }
+}
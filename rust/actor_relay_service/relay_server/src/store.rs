@@ -0,0 +1,498 @@
+use crate::config::{MailboxConfig, StoreConfig};
+use crate::protocol::{Identity, Priority, Room, ServerOperation};
+use crate::room::RoomAcl;
+use crate::server::{Ban, Mail};
+use crate::state::{Entry, RoomState};
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc;
+use tokio::sync::oneshot;
+use tracing::error;
+
+/* SQLite backed copy of the state worth keeping across restarts: rooms with an acl, bans, known
+    identities, room history, mailboxes, shared room state and fencing tokens. The Server stays the
+    source of truth while running and writes every change through as it happens, the store is only
+    read back at startup.
+    Writes go through a StoreWriter, which applies them in order on a thread of its own so a slow
+    disk never holds the Server lock.
+*/
+
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS rooms (
+        name TEXT PRIMARY KEY,
+        acl TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS bans (
+        identity TEXT PRIMARY KEY,
+        until INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS identities (
+        identity TEXT PRIMARY KEY,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        room TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        operation TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_by_room ON history (room, id);
+    CREATE TABLE IF NOT EXISTS mail (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        identity TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        operation TEXT NOT NULL,
+        priority INTEGER NOT NULL DEFAULT 2,
+        expires_ms INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS mail_by_identity ON mail (identity, id);
+    CREATE TABLE IF NOT EXISTS state (
+        room TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        deleted INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (room, key)
+    );
+    CREATE TABLE IF NOT EXISTS lock_tokens (
+        room TEXT PRIMARY KEY,
+        token INTEGER NOT NULL
+    );
+";
+
+/// SQLite integers are signed, a time past what they hold is as good as never
+fn sql_time(at: u64) -> i64 {
+    i64::try_from(at).unwrap_or(i64::MAX)
+}
+
+/// When an identity first and last authenticated, in unix seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IdentityRecord {
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// What compaction removed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Compacted {
+    pub history: usize,
+    pub bans: usize,
+    pub mail: usize,
+}
+
+pub struct Store {
+    conn: Connection,
+    retention: StoreConfig,
+}
+
+impl Store {
+    pub fn open(config: &StoreConfig) -> anyhow::Result<Self> {
+        let path = config.path.as_deref().context("store.path is not set")?;
+        let conn = Connection::open(path)
+            .with_context(|| format!("could not open store {}", path.display()))?;
+        // WAL keeps the relay's small writes cheap and readers out of the way
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            anyhow::bail!(
+                "store {} was written by a newer relay (schema {})",
+                path.display(),
+                version
+            );
+        }
+        conn.execute_batch(SCHEMA)
+            .with_context(|| format!("could not set up store {}", path.display()))?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(Self {
+            conn,
+            retention: config.clone(),
+        })
+    }
+
+    pub fn save_acl(&self, room: &Room, acl: &RoomAcl) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO rooms (name, acl) VALUES (?1, ?2)
+                ON CONFLICT (name) DO UPDATE SET acl = excluded.acl",
+            params![room.as_str(), serde_json::to_string(acl)?],
+        )?;
+        Ok(())
+    }
+
+    /// Forgets a room's acl, its history and its shared state. Its last fencing token stays, so
+    /// locks of a room by the same name never hand out an older one
+    pub fn delete_room(&self, room: &Room) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM rooms WHERE name = ?1", params![room.as_str()])?;
+        self.conn.execute(
+            "DELETE FROM history WHERE room = ?1",
+            params![room.as_str()],
+        )?;
+        self.conn
+            .execute("DELETE FROM state WHERE room = ?1", params![room.as_str()])?;
+        Ok(())
+    }
+
+    pub fn acls(&self) -> anyhow::Result<HashMap<Room, RoomAcl>> {
+        let mut statement = self.conn.prepare("SELECT name, acl FROM rooms")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut acls = HashMap::new();
+        for row in rows {
+            let (name, acl) = row?;
+            let acl = serde_json::from_str(&acl)
+                .with_context(|| format!("bad acl stored for room {}", name))?;
+            acls.insert(Room(name), acl);
+        }
+        Ok(acls)
+    }
+
+    pub fn save_ban(&self, identity: &Identity, ban: Ban) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO bans (identity, until) VALUES (?1, ?2)
+                ON CONFLICT (identity) DO UPDATE SET until = excluded.until",
+            params![identity.as_str(), ban.until.map(sql_time)],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_ban(&self, identity: &Identity) -> anyhow::Result<()> {
+        self.conn.execute(
+            "DELETE FROM bans WHERE identity = ?1",
+            params![identity.as_str()],
+        )?;
+        Ok(())
+    }
+
+    pub fn bans(&self) -> anyhow::Result<HashMap<Identity, Ban>> {
+        let mut statement = self.conn.prepare("SELECT identity, until FROM bans")?;
+        let rows = statement.query_map([], |row| {
+            Ok((Identity(row.get(0)?), Ban { until: row.get(1)? }))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Records that an identity authenticated at `now`
+    pub fn touch_identity(&self, identity: &Identity, now: u64) -> anyhow::Result<IdentityRecord> {
+        self.conn.execute(
+            "INSERT INTO identities (identity, first_seen, last_seen) VALUES (?1, ?2, ?2)
+                ON CONFLICT (identity) DO UPDATE SET last_seen = excluded.last_seen",
+            params![identity.as_str(), now],
+        )?;
+        let record = self
+            .conn
+            .query_row(
+                "SELECT first_seen, last_seen FROM identities WHERE identity = ?1",
+                params![identity.as_str()],
+                |row| {
+                    Ok(IdentityRecord {
+                        first_seen: row.get(0)?,
+                        last_seen: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?
+            .context("identity vanished while recording it")?;
+        Ok(record)
+    }
+
+    pub fn identities(&self) -> anyhow::Result<HashMap<Identity, IdentityRecord>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT identity, first_seen, last_seen FROM identities")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                Identity(row.get(0)?),
+                IdentityRecord {
+                    first_seen: row.get(1)?,
+                    last_seen: row.get(2)?,
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Appends to a room's history, dropping whatever falls past store.history_per_room
+    pub fn append_history(
+        &self,
+        room: &Room,
+        operation: &ServerOperation,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO history (room, at, operation) VALUES (?1, ?2, ?3)",
+            params![room.as_str(), now, serde_json::to_string(operation)?],
+        )?;
+        if self.retention.history_per_room > 0 {
+            self.conn.execute(
+                "DELETE FROM history WHERE room = ?1 AND id <= (
+                    SELECT id FROM history WHERE room = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2
+                )",
+                params![room.as_str(), self.retention.history_per_room],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The newest `limit` operations of every room, oldest first
+    pub fn history(
+        &self,
+        limit: usize,
+    ) -> anyhow::Result<HashMap<Room, VecDeque<ServerOperation>>> {
+        let mut statement = self.conn.prepare(
+            "SELECT room, operation FROM (
+                SELECT room, operation, id,
+                    ROW_NUMBER() OVER (PARTITION BY room ORDER BY id DESC) AS newest
+                FROM history
+            ) WHERE newest <= ?1 ORDER BY id",
+        )?;
+        let rows = statement.query_map(params![limit], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut history: HashMap<Room, VecDeque<ServerOperation>> = HashMap::new();
+        for row in rows {
+            let (room, operation) = row?;
+            let operation = serde_json::from_str(&operation)
+                .with_context(|| format!("bad history stored for room {}", room))?;
+            history.entry(Room(room)).or_default().push_back(operation);
+        }
+        Ok(history)
+    }
+
+    /// Adds to an identity's mailbox, dropping the oldest past `max_messages`
+    pub fn push_mail(
+        &self,
+        identity: &Identity,
+        mail: &Mail,
+        max_messages: usize,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO mail (identity, at, operation, priority, expires_ms)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                identity.as_str(),
+                mail.at,
+                serde_json::to_string(&mail.operation)?,
+                mail.priority.lane(),
+                mail.expires_ms.map(sql_time)
+            ],
+        )?;
+        self.conn.execute(
+            "DELETE FROM mail WHERE identity = ?1 AND id <= (
+                SELECT id FROM mail WHERE identity = ?1 ORDER BY id DESC LIMIT 1 OFFSET ?2
+            )",
+            params![identity.as_str(), max_messages],
+        )?;
+        Ok(())
+    }
+
+    /// Empties an identity's mailbox once it was delivered
+    pub fn delete_mail(&self, identity: &Identity) -> anyhow::Result<()> {
+        self.conn.execute(
+            "DELETE FROM mail WHERE identity = ?1",
+            params![identity.as_str()],
+        )?;
+        Ok(())
+    }
+
+    /// Every mailbox, oldest first
+    pub fn mailboxes(&self) -> anyhow::Result<HashMap<Identity, VecDeque<Mail>>> {
+        let mut statement = self.conn.prepare(
+            "SELECT identity, at, operation, priority, expires_ms FROM mail ORDER BY id",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, usize>(3)?,
+                row.get::<_, Option<u64>>(4)?,
+            ))
+        })?;
+        let mut mailboxes: HashMap<Identity, VecDeque<Mail>> = HashMap::new();
+        for row in rows {
+            let (identity, at, operation, lane, expires_ms) = row?;
+            let operation = serde_json::from_str(&operation)
+                .with_context(|| format!("bad mail stored for {}", identity))?;
+            let priority = Priority::from_lane(lane)
+                .with_context(|| format!("bad mail priority stored for {}", identity))?;
+            mailboxes
+                .entry(Identity(identity))
+                .or_default()
+                .push_back(Mail {
+                    at,
+                    operation,
+                    priority,
+                    expires_ms,
+                });
+        }
+        Ok(mailboxes)
+    }
+
+    pub fn save_state(&self, room: &Room, key: &str, entry: &Entry) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO state (room, key, value, version, deleted) VALUES (?1, ?2, ?3, ?4, 0)
+                ON CONFLICT (room, key) DO UPDATE
+                SET value = excluded.value, version = excluded.version, deleted = 0",
+            params![room.as_str(), key, entry.value, entry.version],
+        )?;
+        Ok(())
+    }
+
+    /// Leaves a tombstone with the version of the delete, so the room's versions carry on past it
+    /// after a restart
+    pub fn delete_state(&self, room: &Room, key: &str, version: u64) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO state (room, key, value, version, deleted) VALUES (?1, ?2, '', ?3, 1)
+                ON CONFLICT (room, key) DO UPDATE
+                SET value = '', version = excluded.version, deleted = 1",
+            params![room.as_str(), key, version],
+        )?;
+        Ok(())
+    }
+
+    /// Remembers the last fencing token granted in a room. Tokens only grow, so an older one
+    /// written late never replaces a newer one
+    pub fn save_lock_token(&self, room: &Room, token: u64) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO lock_tokens (room, token) VALUES (?1, ?2)
+                ON CONFLICT (room) DO UPDATE SET token = MAX(token, excluded.token)",
+            params![room.as_str(), token],
+        )?;
+        Ok(())
+    }
+
+    /// The last fencing token granted in every room
+    pub fn lock_tokens(&self) -> anyhow::Result<HashMap<Room, u64>> {
+        let mut statement = self.conn.prepare("SELECT room, token FROM lock_tokens")?;
+        let rows = statement.query_map([], |row| Ok((Room(row.get(0)?), row.get(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Every room's shared state, counting on from the highest stored version, tombstones included
+    pub fn states(&self) -> anyhow::Result<HashMap<Room, RoomState>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT room, key, value, version, deleted FROM state")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, u64>(3)?,
+                row.get::<_, bool>(4)?,
+            ))
+        })?;
+        let mut states: HashMap<Room, RoomState> = HashMap::new();
+        for row in rows {
+            let (room, key, value, version, deleted) = row?;
+            let state = states.entry(Room(room)).or_default();
+            state.version = state.version.max(version);
+            if !deleted {
+                state.entries.insert(key, Entry { value, version });
+            }
+        }
+        Ok(states)
+    }
+
+    /// Applies the retention settings to everything stored and gives the space back
+    pub fn compact(&self, now: u64, mailbox: &MailboxConfig) -> anyhow::Result<Compacted> {
+        let mut compacted = Compacted::default();
+        if self.retention.history_max_age_secs > 0 {
+            let cutoff = now.saturating_sub(self.retention.history_max_age_secs);
+            compacted.history += self
+                .conn
+                .execute("DELETE FROM history WHERE at < ?1", params![cutoff])?;
+        }
+        if self.retention.history_per_room > 0 {
+            compacted.history += self.conn.execute(
+                "DELETE FROM history WHERE id IN (
+                    SELECT id FROM (
+                        SELECT id, ROW_NUMBER() OVER (PARTITION BY room ORDER BY id DESC) AS newest
+                        FROM history
+                    ) WHERE newest > ?1
+                )",
+                params![self.retention.history_per_room],
+            )?;
+        }
+        compacted.bans = self.conn.execute(
+            "DELETE FROM bans WHERE until IS NOT NULL AND until <= ?1",
+            params![now],
+        )?;
+        compacted.mail = self.conn.execute(
+            "DELETE FROM mail WHERE expires_ms <= ?1",
+            params![now.saturating_mul(1000)],
+        )?;
+        if mailbox.max_age_secs > 0 {
+            let cutoff = now.saturating_sub(mailbox.max_age_secs);
+            compacted.mail += self
+                .conn
+                .execute("DELETE FROM mail WHERE at < ?1", params![cutoff])?;
+        }
+        self.conn.execute_batch("VACUUM")?;
+        Ok(compacted)
+    }
+}
+
+type Write = Box<dyn FnOnce(&Store) -> anyhow::Result<()> + Send>;
+
+enum Job {
+    Write(&'static str, Write),
+    Flush(oneshot::Sender<()>),
+}
+
+/// The Server's handle on the store, writes are queued for the writer thread and applied in the
+/// order they were made. The thread stops once every handle is gone and the queue is done
+#[derive(Clone)]
+pub struct StoreWriter {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl StoreWriter {
+    pub fn spawn(store: Store) -> anyhow::Result<Self> {
+        let (jobs, queue) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("store-writer".to_string())
+            .spawn(move || {
+                for job in queue {
+                    match job {
+                        Job::Write(what, write) => {
+                            if let Err(e) = write(&store) {
+                                error!("Could not store {}: {:#}", what, e);
+                            }
+                        }
+                        Job::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .context("could not start the store writer")?;
+        Ok(Self { jobs })
+    }
+
+    /// Queues a write. A failed write is logged and otherwise ignored, the relay keeps working
+    /// from memory
+    pub fn write(
+        &self,
+        what: &'static str,
+        write: impl FnOnce(&Store) -> anyhow::Result<()> + Send + 'static,
+    ) {
+        if self.jobs.send(Job::Write(what, Box::new(write))).is_err() {
+            error!("Could not store {}: the store writer stopped", what);
+        }
+    }
+
+    /// Resolves once every write queued before it is applied
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.jobs.send(Job::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::protocol::Recipient;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/* Multi-step tasks per room, a quest or a raid script.
+    TaskDefine names the steps and starts the task over. Members report the step they are on with
+    TaskReport, steps.len() meaning they are done, and TaskQuery answers who is on which step. Once
+    every member of the room got to a step the room is told with TaskStepReached, the last one
+    being everybody done.
+    A member behind the furthest one is a straggler. The relay asks it where it is with
+    RequestCurrentTaskStep once it has not been heard from for tasks.poll_after_ms, in case its
+    report got lost or it is stuck, and answers come back as TaskReport.
+    Members count as their identity, or their connection without one, like at barriers. Only the
+    members in the room right now count, someone who left no longer holds the rest back. Tasks
+    only live in memory.
+*/
+
+#[derive(Debug, Clone)]
+pub struct Task {
+    pub steps: Vec<String>,
+    // the step every member that reported is on
+    reports: HashMap<Recipient, usize>,
+    // when a member last reported or was asked to
+    heard: HashMap<Recipient, Instant>,
+    // the step everybody had got to when last checked, it never goes back
+    reached: usize,
+    defined: Instant,
+}
+
+impl Task {
+    pub fn new(steps: Vec<String>, now: Instant) -> Self {
+        Self {
+            steps,
+            reports: HashMap::new(),
+            heard: HashMap::new(),
+            reached: 0,
+            defined: now,
+        }
+    }
+
+    pub fn report(&mut self, who: Recipient, step: usize, now: Instant) {
+        self.heard.insert(who.clone(), now);
+        self.reports.insert(who, step);
+    }
+
+    pub fn step_of(&self, who: &Recipient) -> Option<usize> {
+        self.reports.get(who).copied()
+    }
+
+    pub fn forget(&mut self, who: &Recipient) {
+        self.reports.remove(who);
+        self.heard.remove(who);
+    }
+
+    /// Whether `who` is behind the furthest of the `present` members, not reporting at all counts
+    pub fn is_behind(&self, who: &Recipient, present: &[Recipient]) -> bool {
+        let furthest = present
+            .iter()
+            .filter_map(|member| self.step_of(member))
+            .max();
+        furthest.is_some_and(|furthest| self.step_of(who).is_none_or(|step| step < furthest))
+    }
+
+    /// The step all of `present` got to when that moved on since the last call
+    pub fn reach(&mut self, present: &[Recipient]) -> Option<usize> {
+        let slowest = present
+            .iter()
+            .map(|member| self.step_of(member).unwrap_or(0))
+            .min()?;
+        (slowest > self.reached).then(|| {
+            self.reached = slowest;
+            slowest
+        })
+    }
+
+    /// When to ask `who` where it is if it stays behind
+    pub fn poll_due(&self, who: &Recipient, poll_after: Duration) -> Instant {
+        *self.heard.get(who).unwrap_or(&self.defined) + poll_after
+    }
+
+    pub fn polled(&mut self, who: Recipient, now: Instant) {
+        self.heard.insert(who, now);
+    }
+}
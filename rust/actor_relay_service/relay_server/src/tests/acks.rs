@@ -0,0 +1,224 @@
+use super::lua::operation;
+use super::{identities, FakeClient, TestRelay};
+use crate::config::Config;
+use crate::error::OperationError;
+use crate::protocol::{Identity, Recipient, ServerOperation};
+use serde_json::json;
+use std::time::Duration;
+
+/// Retries every 50ms, three sends at most
+fn config() -> Config {
+    let mut config = identities(&["leader", "healer"]);
+    config.qos.retry_interval_ms = 50;
+    config.qos.max_attempts = 3;
+    config
+}
+
+async fn say_reliably(client: &mut FakeClient, message: &str) {
+    client
+        .send_raw(&operation(json!({ "Message": {
+            "room": "testRoom", "channel": "cmd", "message": message, "qos": "AtLeastOnce",
+        } })))
+        .await;
+}
+
+/// The seq and message text of a Reliable wrapping a RoomMessage or DirectMessage
+fn reliable(operation: ServerOperation) -> (u64, String) {
+    match operation {
+        ServerOperation::Reliable { seq, operation } => match *operation {
+            ServerOperation::RoomMessage { message, .. }
+            | ServerOperation::DirectMessage { message, .. } => (seq, message.0),
+            other => panic!("unexpected {:?} in Reliable", other),
+        },
+        other => panic!("expected Reliable, got {:?}", other),
+    }
+}
+
+async fn ack(client: &mut FakeClient, seq: u64) {
+    client.send_raw(&operation(json!({ "Ack": seq }))).await;
+}
+
+#[tokio::test]
+async fn acked_messages_are_not_resent() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut leader = relay.login("leader").await;
+    let mut healer = relay.login("healer").await;
+    healer.join(&relay, "testRoom").await;
+
+    say_reliably(&mut leader, "stop attacking").await;
+    assert_eq!(
+        reliable(healer.recv().await),
+        (1, "stop attacking".to_string())
+    );
+    ack(&mut healer, 1).await;
+    relay
+        .wait_until(|server| {
+            server
+                .unacked
+                .values()
+                .all(|unacked| unacked.pending.is_empty())
+        })
+        .await;
+    healer.expect_silence().await;
+    leader.expect_silence().await;
+}
+
+#[tokio::test]
+async fn unacked_messages_are_resent_with_the_same_seq() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut leader = relay.login("leader").await;
+    let mut healer = relay.login("healer").await;
+    healer.join(&relay, "testRoom").await;
+
+    say_reliably(&mut leader, "one").await;
+    say_reliably(&mut leader, "two").await;
+    assert_eq!(reliable(healer.recv().await), (1, "one".to_string()));
+    assert_eq!(reliable(healer.recv().await), (2, "two".to_string()));
+    ack(&mut healer, 2).await;
+    assert_eq!(reliable(healer.recv().await), (1, "one".to_string()));
+    ack(&mut healer, 1).await;
+    healer.expect_silence().await;
+}
+
+#[tokio::test]
+async fn sender_hears_when_the_relay_gives_up() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut leader = relay.login("leader").await;
+    let mut healer = relay.login("healer").await;
+    healer.join(&relay, "testRoom").await;
+
+    say_reliably(&mut leader, "stop attacking").await;
+    for _ in 0..3 {
+        assert_eq!(reliable(healer.recv().await).0, 1);
+    }
+    match leader.recv().await {
+        ServerOperation::DeliveryFailed {
+            to,
+            attempts,
+            operation,
+        } => {
+            assert_eq!(to, Recipient::Identity(Identity("healer".to_string())));
+            assert_eq!(attempts, 3);
+            assert!(matches!(*operation, ServerOperation::RoomMessage { .. }));
+        }
+        other => panic!("expected DeliveryFailed, got {:?}", other),
+    }
+    healer.expect_silence().await;
+}
+
+#[tokio::test]
+async fn identities_get_what_they_missed_when_they_reconnect() {
+    let mut config = config();
+    // long enough that only the reconnect resends
+    config.qos.retry_interval_ms = 60_000;
+    let relay = TestRelay::start_with(config).await;
+    let mut leader = relay.login("leader").await;
+    let healer = relay.login("healer").await;
+    let healer_id = healer.id();
+    drop(healer);
+    relay
+        .wait_until(|server| !server.clients.contains_key(&healer_id))
+        .await;
+
+    leader
+        .send_raw(&operation(json!({ "DirectMessage": {
+            "to": "healer", "channel": "assign", "message": "heal the tank", "qos": "AtLeastOnce",
+        } })))
+        .await;
+    relay.wait_until(|server| !server.unacked.is_empty()).await;
+
+    let mut healer = relay.login("healer").await;
+    assert_eq!(
+        reliable(healer.recv().await),
+        (1, "heal the tank".to_string())
+    );
+    ack(&mut healer, 1).await;
+    healer.expect_silence().await;
+    assert!(relay.server.lock().await.mailboxes.is_empty());
+}
+
+/// A DirectMessage with qos AtLeastOnce
+async fn direct_reliably(client: &mut FakeClient, to: &str, message: &str) {
+    client
+        .send_raw(&operation(json!({ "DirectMessage": {
+            "to": to, "channel": "assign", "message": message, "qos": "AtLeastOnce",
+        } })))
+        .await;
+}
+
+#[tokio::test]
+async fn identities_that_stay_away_use_up_their_attempts() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut leader = relay.login("leader").await;
+    direct_reliably(&mut leader, "healer", "heal the tank").await;
+
+    match leader.recv().await {
+        ServerOperation::DeliveryFailed { to, attempts, .. } => {
+            assert_eq!(to, Recipient::Identity(Identity("healer".to_string())));
+            assert_eq!(attempts, 3);
+        }
+        other => panic!("expected DeliveryFailed, got {:?}", other),
+    }
+    let server = relay.server.lock().await;
+    assert!(server
+        .unacked
+        .values()
+        .all(|unacked| unacked.pending.is_empty()));
+}
+
+#[tokio::test]
+async fn senders_are_refused_once_a_recipient_owes_too_many_acks() {
+    let mut config = config();
+    config.qos.retry_interval_ms = 60_000;
+    config.qos.max_pending = 2;
+    let relay = TestRelay::start_with(config).await;
+    let mut leader = relay.login("leader").await;
+    for message in ["one", "two", "three"] {
+        direct_reliably(&mut leader, "healer", message).await;
+    }
+    let full =
+        OperationError::TooManyPending(Recipient::Identity(Identity("healer".to_string())), 2);
+    leader.expect_nack(full.clone()).await;
+
+    // the room refuses as well while one of its members is full
+    let mut healer = relay.login("healer").await;
+    healer.join(&relay, "testRoom").await;
+    assert_eq!(reliable(healer.recv().await).1, "one");
+    assert_eq!(reliable(healer.recv().await).1, "two");
+    say_reliably(&mut leader, "pull").await;
+    leader.expect_nack(full).await;
+    healer.expect_silence().await;
+}
+
+#[tokio::test]
+async fn anonymous_recipients_that_leave_fail_at_once() {
+    let mut config = config();
+    config.qos.retry_interval_ms = 60_000;
+    let relay = TestRelay::start_with(config).await;
+    let mut leader = relay.login("leader").await;
+    let mut anonymous = relay.connect().await;
+    anonymous.join(&relay, "testRoom").await;
+
+    say_reliably(&mut leader, "hello").await;
+    assert_eq!(reliable(anonymous.recv().await).0, 1);
+    let anonymous_id = anonymous.id();
+    drop(anonymous);
+
+    match tokio::time::timeout(Duration::from_secs(2), leader.recv()).await {
+        Ok(ServerOperation::DeliveryFailed { to, attempts, .. }) => {
+            assert_eq!(to, Recipient::Client(anonymous_id));
+            assert_eq!(attempts, 1);
+        }
+        other => panic!("expected DeliveryFailed, got {:?}", other),
+    }
+}
+
+#[test]
+fn retries_need_a_sane_interval_and_at_least_one_attempt() {
+    let mut config = Config::default();
+    config.qos.retry_interval_ms = 0;
+    config.qos.max_attempts = 0;
+    let problems = config.validate().unwrap_err().to_string();
+    assert!(problems.contains("qos.retry_interval_ms"), "{}", problems);
+    assert!(problems.contains("qos.max_attempts"), "{}", problems);
+}
@@ -0,0 +1,252 @@
+use super::lua::operation;
+use super::{identities, lua, TestRelay};
+use crate::config::RoomConfig;
+use crate::error::OperationError;
+use crate::protocol::{ClientId, Identity, Room, ServerOperation};
+use serde_json::json;
+
+/// A relay where "gm" is an admin
+async fn relay() -> TestRelay {
+    let mut config = identities(&["gm", "griefer", "bystander"]);
+    config.auth.admins = vec!["gm".to_string()];
+    TestRelay::start_with(config).await
+}
+
+fn gm() -> Identity {
+    Identity("gm".to_string())
+}
+
+#[tokio::test]
+async fn kicked_clients_are_told_and_disconnected() {
+    let relay = relay().await;
+    let mut admin = relay.login("gm").await;
+    let mut griefer = relay.login("griefer").await;
+    let gone = griefer.id();
+
+    admin
+        .send_raw(&operation(json!({ "Kick": gone.to_string() })))
+        .await;
+
+    assert_eq!(griefer.recv().await, ServerOperation::Kicked { by: gm() });
+    griefer.expect_closed().await;
+    relay
+        .wait_until(|server| !server.clients.contains_key(&gone))
+        .await;
+}
+
+#[tokio::test]
+async fn non_admins_can_not_moderate() {
+    let relay = relay().await;
+    let mut griefer = relay.login("griefer").await;
+    let bystander = relay.login("bystander").await;
+
+    griefer
+        .send_raw(&operation(json!({ "Kick": bystander.id().to_string() })))
+        .await;
+    assert_eq!(
+        griefer.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NotAdmin.to_string()
+        }
+    );
+    assert!(relay
+        .server
+        .lock()
+        .await
+        .clients
+        .contains_key(&bystander.id()));
+}
+
+#[tokio::test]
+async fn kicking_an_unknown_client_is_nacked() {
+    let relay = relay().await;
+    let mut admin = relay.login("gm").await;
+    let nobody = ClientId::new();
+
+    admin
+        .send_raw(&operation(json!({ "Kick": nobody.to_string() })))
+        .await;
+    assert_eq!(
+        admin.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NoSuchClient(nobody).to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn banned_identities_are_disconnected_and_kept_out() {
+    let relay = relay().await;
+    let mut admin = relay.login("gm").await;
+    let mut griefer = relay.login("griefer").await;
+
+    admin
+        .send_raw(&operation(json!({ "Ban": { "identity": "griefer" } })))
+        .await;
+    assert_eq!(
+        griefer.recv().await,
+        ServerOperation::Banned { until: None }
+    );
+    griefer.expect_closed().await;
+
+    let mut again = relay.open().await;
+    again.send_raw(&lua::connect_with_token("griefer")).await;
+    assert_eq!(
+        again.recv().await,
+        ServerOperation::ConnectRejected {
+            reason: "banned".to_string()
+        }
+    );
+    again.expect_closed().await;
+
+    admin
+        .send_raw(&operation(json!({ "Unban": "griefer" })))
+        .await;
+    relay.wait_until(|server| server.bans.is_empty()).await;
+    relay.login("griefer").await;
+}
+
+#[tokio::test]
+async fn timed_bans_expire() {
+    let relay = relay().await;
+    let mut admin = relay.login("gm").await;
+
+    admin
+        .send_raw(&operation(
+            json!({ "Ban": { "identity": "griefer", "duration_secs": 0 } }),
+        ))
+        .await;
+    relay.wait_until(|server| !server.bans.is_empty()).await;
+    relay.login("griefer").await;
+    assert!(relay.server.lock().await.bans.is_empty());
+}
+
+#[tokio::test]
+async fn huge_ban_durations_last_forever_instead_of_overflowing() {
+    let relay = relay().await;
+    let mut admin = relay.login("gm").await;
+    let mut griefer = relay.login("griefer").await;
+
+    admin
+        .send_raw(&operation(
+            json!({ "Ban": { "identity": "griefer", "duration_secs": u64::MAX } }),
+        ))
+        .await;
+    assert_eq!(
+        griefer.recv().await,
+        ServerOperation::Banned {
+            until: Some(u64::MAX)
+        }
+    );
+    griefer.expect_closed().await;
+}
+
+#[tokio::test]
+async fn muted_clients_receive_but_can_not_send() {
+    let relay = relay().await;
+    let mut admin = relay.login("gm").await;
+    let mut griefer = relay.login("griefer").await;
+    admin.join(&relay, "testRoom").await;
+    griefer.join(&relay, "testRoom").await;
+
+    let mute = json!({ "room": "testRoom", "client": griefer.id().to_string() });
+    admin
+        .send_raw(&operation(json!({ "Mute": mute.clone() })))
+        .await;
+    let room = Room("testRoom".to_string());
+    assert_eq!(
+        griefer.recv().await,
+        ServerOperation::Muted { room: room.clone() }
+    );
+
+    griefer.say("testRoom", "chat", "spam").await;
+    assert_eq!(
+        griefer.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::Muted(room.clone()).to_string()
+        }
+    );
+    admin.expect_silence().await;
+
+    admin.say("testRoom", "chat", "behave").await;
+    assert!(matches!(
+        griefer.recv().await,
+        ServerOperation::RoomMessage { .. }
+    ));
+    assert!(matches!(
+        admin.recv().await,
+        ServerOperation::RoomMessage { .. }
+    ));
+
+    admin.send_raw(&operation(json!({ "Unmute": mute }))).await;
+    assert_eq!(griefer.recv().await, ServerOperation::Unmuted { room });
+    griefer.say("testRoom", "chat", "sorry").await;
+    assert!(matches!(
+        admin.recv().await,
+        ServerOperation::RoomMessage { .. }
+    ));
+}
+
+#[tokio::test]
+async fn closing_a_room_notifies_and_removes_its_members() {
+    let relay = relay().await;
+    let mut admin = relay.login("gm").await;
+    let mut bystander = relay.login("bystander").await;
+    bystander.join(&relay, "testRoom").await;
+
+    admin
+        .send_raw(&operation(json!({ "CloseRoom": "testRoom" })))
+        .await;
+    let room = Room("testRoom".to_string());
+    assert_eq!(
+        bystander.recv().await,
+        ServerOperation::RoomClosed(room.clone())
+    );
+    assert!(!relay.server.lock().await.rooms.contains_key(&room));
+
+    admin
+        .send_raw(&operation(json!({ "CloseRoom": "testRoom" })))
+        .await;
+    assert_eq!(
+        admin.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NoSuchRoom(room).to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn closed_configured_rooms_keep_their_password() {
+    let mut config = identities(&["gm", "bystander"]);
+    config.auth.admins = vec!["gm".to_string()];
+    config.rooms = vec![RoomConfig {
+        name: "raid".to_string(),
+        password: Some("hunter2".to_string()),
+        ..Default::default()
+    }];
+    let relay = TestRelay::start_with(config).await;
+    let mut admin = relay.login("gm").await;
+    let mut bystander = relay.login("bystander").await;
+    bystander
+        .send_raw(&operation(
+            json!({ "RoomJoinWithPassword": { "room": "raid", "password": "hunter2" } }),
+        ))
+        .await;
+    relay.wait_for_member("raid", bystander.id(), true).await;
+
+    admin
+        .send_raw(&operation(json!({ "CloseRoom": "raid" })))
+        .await;
+    let room = Room("raid".to_string());
+    assert_eq!(
+        bystander.recv().await,
+        ServerOperation::RoomClosed(room.clone())
+    );
+
+    bystander
+        .send_raw(&operation(json!({ "RoomJoin": "raid" })))
+        .await;
+    bystander
+        .expect_nack(OperationError::WrongPassword(room))
+        .await;
+}
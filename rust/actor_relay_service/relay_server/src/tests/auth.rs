@@ -0,0 +1,185 @@
+use super::{lua, FakeClient, TestRelay};
+use crate::auth;
+use crate::config::Config;
+use crate::error::OperationError;
+use crate::protocol::{Identity, ServerOperation};
+use serde_json::json;
+
+const SECRET: &str = "sixteen chars or more";
+
+fn auth_config(required: bool) -> Config {
+    let mut config = Config::default();
+    config.auth.required = required;
+    config.auth.secret = Some(SECRET.to_string());
+    config
+        .auth
+        .tokens
+        .insert("letmein".to_string(), "healer".to_string());
+    config
+}
+
+fn connect_with(credentials: serde_json::Value) -> String {
+    json!({
+        "clientId": null,
+        "clientOperation": { "ConnectAttempt": { "credentials": credentials } },
+    })
+    .to_string()
+}
+
+fn auth_response(mac: &str) -> String {
+    json!({ "clientId": null, "clientOperation": { "AuthResponse": { "mac": mac } } }).to_string()
+}
+
+async fn identity_of(relay: &TestRelay, client: &FakeClient) -> Option<Identity> {
+    let server = relay.server.lock().await;
+    server.clients[&client.id()].identity.clone()
+}
+
+async fn expect_rejected(client: &mut FakeClient) -> String {
+    match client.recv().await {
+        ServerOperation::ConnectRejected { reason } => {
+            client.expect_closed().await;
+            reason
+        }
+        other => panic!("expected ConnectRejected, got {:?}", other),
+    }
+}
+
+async fn challenge(client: &mut FakeClient, identity: &str) -> String {
+    client
+        .send_raw(&connect_with(json!({ "Hmac": { "identity": identity } })))
+        .await;
+    match client.recv().await {
+        ServerOperation::AuthChallenge { nonce } => nonce,
+        other => panic!("expected AuthChallenge, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn token_authenticates_as_its_identity() {
+    let relay = TestRelay::start_with(auth_config(true)).await;
+    let mut client = relay.open().await;
+    client
+        .send_raw(&connect_with(json!({ "Token": "letmein" })))
+        .await;
+    client.expect_approval().await;
+
+    assert_eq!(
+        identity_of(&relay, &client).await,
+        Some(Identity("healer".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn unknown_token_is_rejected_and_closed() {
+    let relay = TestRelay::start_with(auth_config(false)).await;
+    let mut client = relay.open().await;
+    client
+        .send_raw(&connect_with(json!({ "Token": "guess" })))
+        .await;
+
+    assert_eq!(expect_rejected(&mut client).await, "unknown token");
+    relay.wait_until(|server| server.clients.is_empty()).await;
+}
+
+#[tokio::test]
+async fn missing_credentials_are_rejected_when_required() {
+    let relay = TestRelay::start_with(auth_config(true)).await;
+    let mut client = relay.open().await;
+    client.send_raw(&lua::connect_request()).await;
+
+    assert_eq!(expect_rejected(&mut client).await, "credentials required");
+}
+
+#[tokio::test]
+async fn legacy_connect_attempt_still_works_when_auth_is_optional() {
+    let relay = TestRelay::start_with(auth_config(false)).await;
+    let mut client = relay.open().await;
+    client.connect_attempt().await;
+
+    assert_eq!(identity_of(&relay, &client).await, None);
+}
+
+#[tokio::test]
+async fn hmac_challenge_response_authenticates() {
+    let relay = TestRelay::start_with(auth_config(true)).await;
+    let mut client = relay.open().await;
+    let nonce = challenge(&mut client, "tank").await;
+
+    let mac = auth::sign(SECRET, &nonce, &Identity("tank".to_string()));
+    client.send_raw(&auth_response(&mac)).await;
+    client.expect_approval().await;
+
+    assert_eq!(
+        identity_of(&relay, &client).await,
+        Some(Identity("tank".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn wrong_hmac_is_rejected() {
+    let relay = TestRelay::start_with(auth_config(true)).await;
+    let mut client = relay.open().await;
+    let nonce = challenge(&mut client, "tank").await;
+
+    let mac = auth::sign("some other secret!", &nonce, &Identity("tank".to_string()));
+    client.send_raw(&auth_response(&mac)).await;
+
+    assert_eq!(
+        expect_rejected(&mut client).await,
+        "challenge response did not match"
+    );
+}
+
+#[tokio::test]
+async fn operations_before_authenticating_are_nacked() {
+    let relay = TestRelay::start_with(auth_config(true)).await;
+    let mut client = relay.open().await;
+    client.send_raw(&lua::room_join(None, "testRoom")).await;
+
+    assert_eq!(
+        client.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NotAuthenticated.to_string()
+        }
+    );
+    let server = relay.server.lock().await;
+    assert!(server.rooms.values().all(|members| members.is_empty()));
+    drop(server);
+
+    // the connection stays usable
+    client
+        .send_raw(&connect_with(json!({ "Token": "letmein" })))
+        .await;
+    client.expect_approval().await;
+    client.join(&relay, "testRoom").await;
+}
+
+#[tokio::test]
+async fn auth_response_without_a_challenge_is_nacked() {
+    let relay = TestRelay::start_with(auth_config(false)).await;
+    let mut client = relay.connect().await;
+    client.send_raw(&auth_response("00")).await;
+
+    assert_eq!(
+        client.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NoChallenge.to_string()
+        }
+    );
+}
+
+#[test]
+fn signatures_depend_on_every_input() {
+    let tank = Identity("tank".to_string());
+    let mac = auth::sign(SECRET, "nonce", &tank);
+    assert!(auth::verify(SECRET, "nonce", &tank, &mac));
+    assert!(!auth::verify(SECRET, "other", &tank, &mac));
+    assert!(!auth::verify(
+        SECRET,
+        "nonce",
+        &Identity("healer".to_string()),
+        &mac
+    ));
+    assert!(!auth::verify(SECRET, "nonce", &tank, "not hex"));
+}
@@ -0,0 +1,189 @@
+use super::lua::operation;
+use super::{identities, FakeClient, TestRelay};
+use crate::config::Config;
+use crate::error::OperationError;
+use crate::protocol::{Identity, Recipient, Room, ServerOperation};
+use serde_json::json;
+use std::collections::HashSet;
+
+async fn relay() -> TestRelay {
+    TestRelay::start_with(identities(&["healer", "tank", "friend"])).await
+}
+
+/// Logs in as `name` and joins testRoom
+async fn member(relay: &TestRelay, name: &str) -> FakeClient {
+    let mut client = relay.login(name).await;
+    client.join(relay, "testRoom").await;
+    client
+}
+
+async fn wait(client: &mut FakeClient, participants: serde_json::Value, timeout_ms: u64) {
+    client
+        .send_raw(&operation(json!({ "BarrierWait": {
+            "room": "testRoom",
+            "name": "zoned_in",
+            "count_or_members": participants,
+            "timeout_ms": timeout_ms,
+        } })))
+        .await;
+}
+
+fn waiting(arrived: usize, needed: usize) -> ServerOperation {
+    ServerOperation::BarrierWaiting {
+        room: Room("testRoom".to_string()),
+        name: "zoned_in".to_string(),
+        arrived,
+        needed,
+    }
+}
+
+fn identity(name: &str) -> Recipient {
+    Recipient::Identity(Identity(name.to_string()))
+}
+
+#[tokio::test]
+async fn everyone_is_released_once_the_count_is_reached() {
+    let relay = relay().await;
+    let mut group = Vec::new();
+    for _ in 0..3 {
+        let mut client = relay.connect().await;
+        client.join(&relay, "testRoom").await;
+        group.push(client);
+    }
+
+    for (arrived, client) in group[..2].iter_mut().enumerate() {
+        wait(client, json!(3), 10_000).await;
+        assert_eq!(client.recv().await, waiting(arrived + 1, 3));
+    }
+    wait(&mut group[2], json!(3), 10_000).await;
+    let arrived: Vec<Recipient> = group.iter().map(|c| Recipient::Client(c.id())).collect();
+    for client in &mut group {
+        assert_eq!(
+            client.recv().await,
+            ServerOperation::BarrierReleased {
+                room: Room("testRoom".to_string()),
+                name: "zoned_in".to_string(),
+                arrived: arrived.clone(),
+            }
+        );
+    }
+    relay.wait_until(|server| server.barriers.is_empty()).await;
+
+    // the same name can be waited on again
+    wait(&mut group[0], json!(2), 10_000).await;
+    assert_eq!(group[0].recv().await, waiting(1, 2));
+}
+
+#[tokio::test]
+async fn a_member_list_only_lets_its_members_in() {
+    let relay = relay().await;
+    let mut healer = member(&relay, "healer").await;
+    let mut tank = member(&relay, "tank").await;
+    let mut friend = member(&relay, "friend").await;
+    let mut anonymous = relay.connect().await;
+    anonymous.join(&relay, "testRoom").await;
+    let members = json!(["healer", "tank"]);
+
+    wait(&mut healer, members.clone(), 10_000).await;
+    assert_eq!(healer.recv().await, waiting(1, 2));
+    wait(&mut friend, members.clone(), 10_000).await;
+    friend
+        .expect_nack(OperationError::NotParticipant("zoned_in".to_string()))
+        .await;
+    wait(&mut anonymous, members.clone(), 10_000).await;
+    anonymous
+        .expect_nack(OperationError::NotParticipant("zoned_in".to_string()))
+        .await;
+    wait(&mut tank, json!(2), 10_000).await;
+    tank.expect_nack(OperationError::BarrierMismatch("zoned_in".to_string()))
+        .await;
+
+    wait(&mut tank, members, 10_000).await;
+    for client in [&mut healer, &mut tank] {
+        assert!(matches!(
+            client.recv().await,
+            ServerOperation::BarrierReleased { arrived, .. }
+                if arrived == vec![identity("healer"), identity("tank")]
+        ));
+    }
+}
+
+#[tokio::test]
+async fn a_timeout_names_the_missing_members() {
+    let relay = relay().await;
+    let mut healer = member(&relay, "healer").await;
+
+    wait(&mut healer, json!(["healer", "tank"]), 150).await;
+    assert_eq!(healer.recv().await, waiting(1, 2));
+    assert_eq!(
+        healer.recv().await,
+        ServerOperation::BarrierTimedOut {
+            room: Room("testRoom".to_string()),
+            name: "zoned_in".to_string(),
+            arrived: vec![identity("healer")],
+            missing: vec![identity("tank")],
+        }
+    );
+    relay.wait_until(|server| server.barriers.is_empty()).await;
+}
+
+#[tokio::test]
+async fn a_count_timeout_names_room_members_that_did_not_wait() {
+    let relay = relay().await;
+    let mut healer = member(&relay, "healer").await;
+    let _tank = member(&relay, "tank").await;
+    let mut anonymous = relay.connect().await;
+    anonymous.join(&relay, "testRoom").await;
+
+    wait(&mut healer, json!(3), 150).await;
+    healer.recv().await;
+    match healer.recv().await {
+        ServerOperation::BarrierTimedOut {
+            arrived, missing, ..
+        } => {
+            assert_eq!(arrived, vec![identity("healer")]);
+            assert_eq!(
+                missing.into_iter().collect::<HashSet<_>>(),
+                HashSet::from([identity("tank"), Recipient::Client(anonymous.id())])
+            );
+        }
+        other => panic!("expected BarrierTimedOut, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn leaving_takes_back_an_arrival() {
+    let relay = relay().await;
+    let mut first = relay.connect().await;
+    let mut second = relay.connect().await;
+    first.join(&relay, "testRoom").await;
+    second.join(&relay, "testRoom").await;
+
+    wait(&mut first, json!(2), 10_000).await;
+    first.recv().await;
+    first.leave(&relay, "testRoom").await;
+    relay.wait_until(|server| server.barriers.is_empty()).await;
+
+    wait(&mut second, json!(2), 10_000).await;
+    assert_eq!(second.recv().await, waiting(1, 2));
+    drop(second);
+    relay.wait_until(|server| server.barriers.is_empty()).await;
+}
+
+#[tokio::test]
+async fn refused_waits() {
+    let mut config = Config::default();
+    config.barriers.max_timeout_ms = 1000;
+    let relay = TestRelay::start_with(config).await;
+    let mut client = relay.connect().await;
+    client.join(&relay, "testRoom").await;
+
+    for timeout_ms in [0, 1001] {
+        wait(&mut client, json!(2), timeout_ms).await;
+        client.expect_nack(OperationError::BadTimeout(1000)).await;
+    }
+    for participants in [json!(0), json!([])] {
+        wait(&mut client, participants, 500).await;
+        client.expect_nack(OperationError::NoParticipants).await;
+    }
+}
@@ -0,0 +1,167 @@
+use super::{identities, FakeClient, TestRelay};
+use crate::config::{Config, RoomConfig};
+use crate::error::OperationError;
+use crate::protocol::{BatchResult, Room, ServerOperation};
+use serde_json::json;
+
+fn batch(operations: serde_json::Value) -> String {
+    json!({ "clientId": null, "clientOperation": { "Batch": operations } }).to_string()
+}
+
+/// A relay with a password on "raid"
+async fn relay() -> TestRelay {
+    let mut config = identities(&["leader", "healer"]);
+    config.rooms.push(RoomConfig {
+        name: "raid".to_string(),
+        owner: Some("leader".to_string()),
+        password: Some("hunter2".to_string()),
+        ..Default::default()
+    });
+    TestRelay::start_with(config).await
+}
+
+async fn expect_results(client: &mut FakeClient, results: Vec<BatchResult>) {
+    assert_eq!(client.recv().await, ServerOperation::BatchResults(results));
+}
+
+#[tokio::test]
+async fn a_box_starts_up_in_one_frame() {
+    let relay = relay().await;
+    let mut listener = relay.connect().await;
+    listener.join(&relay, "testRoom").await;
+
+    let mut client = relay.open().await;
+    client
+        .send_raw(&batch(json!([
+            { "ConnectAttempt": { "credentials": { "Token": "healer" } } },
+            { "RoomJoin": "testRoom" },
+            { "RoomJoin": "group" },
+            { "Message": { "room": "testRoom", "channel": "state", "message": "hp 100" } },
+        ])))
+        .await;
+    client.expect_approval().await;
+
+    // the echo of the message and the results are in different lanes
+    let mut results = None;
+    let mut echoed = false;
+    for _ in 0..2 {
+        match client.recv().await {
+            ServerOperation::BatchResults(r) => results = Some(r),
+            ServerOperation::RoomMessage { .. } => echoed = true,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert_eq!(results, Some(vec![BatchResult::Ok; 4]));
+    assert!(echoed);
+    assert!(relay.is_member("group", client.id()).await);
+    assert!(matches!(
+        listener.recv().await,
+        ServerOperation::RoomMessage { .. }
+    ));
+}
+
+#[tokio::test]
+async fn each_operation_gets_its_own_result() {
+    let relay = relay().await;
+    let mut client = relay.connect().await;
+
+    client
+        .send_raw(&batch(json!([
+            { "RoomJoin": "testRoom" },
+            { "RoomJoin": "raid" },
+            { "RoomJoin": "group" },
+        ])))
+        .await;
+    expect_results(
+        &mut client,
+        vec![
+            BatchResult::Ok,
+            BatchResult::Failed {
+                reason: OperationError::WrongPassword(Room("raid".to_string())).to_string(),
+            },
+            BatchResult::Ok,
+        ],
+    )
+    .await;
+    // failures are only reported in the results, not with a Nack of their own
+    client.expect_silence().await;
+    assert!(relay.is_member("testRoom", client.id()).await);
+    assert!(relay.is_member("group", client.id()).await);
+}
+
+#[tokio::test]
+async fn batches_do_not_nest() {
+    let relay = relay().await;
+    let mut client = relay.connect().await;
+
+    client
+        .send_raw(&batch(json!([
+            { "Batch": [{ "RoomJoin": "testRoom" }] },
+            { "RoomJoin": "group" },
+        ])))
+        .await;
+    expect_results(
+        &mut client,
+        vec![
+            BatchResult::Failed {
+                reason: OperationError::NestedBatch.to_string(),
+            },
+            BatchResult::Ok,
+        ],
+    )
+    .await;
+    assert!(!relay.is_member("testRoom", client.id()).await);
+}
+
+#[tokio::test]
+async fn a_disconnect_skips_the_rest() {
+    let relay = relay().await;
+    let mut client = relay.connect().await;
+
+    client
+        .send_raw(&batch(json!([
+            { "RoomJoin": "testRoom" },
+            "Disconnect",
+            { "RoomJoin": "group" },
+        ])))
+        .await;
+    expect_results(
+        &mut client,
+        vec![BatchResult::Ok, BatchResult::Ok, BatchResult::Skipped],
+    )
+    .await;
+    client.expect_closed().await;
+}
+
+#[tokio::test]
+async fn every_operation_needs_authentication() {
+    let mut config = Config::default();
+    config.auth.required = true;
+    config
+        .auth
+        .tokens
+        .insert("healer".to_string(), "healer".to_string());
+    let relay = TestRelay::start_with(config).await;
+    let mut client = relay.open().await;
+
+    client
+        .send_raw(&batch(json!([
+            { "RoomJoin": "testRoom" },
+            { "ConnectAttempt": { "credentials": { "Token": "healer" } } },
+            { "RoomJoin": "testRoom" },
+        ])))
+        .await;
+    client.expect_approval().await;
+    expect_results(
+        &mut client,
+        vec![
+            BatchResult::Failed {
+                reason: OperationError::NotAuthenticated.to_string(),
+            },
+            BatchResult::Ok,
+            BatchResult::Ok,
+        ],
+    )
+    .await;
+    relay.wait_for_member("testRoom", client.id(), true).await;
+}
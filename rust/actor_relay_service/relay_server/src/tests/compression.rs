@@ -0,0 +1,223 @@
+use super::{lua, FakeClient, TestRelay};
+use crate::compression::CompressionError;
+use crate::config::Config;
+use crate::protocol::{Compression, ServerOperation};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::json;
+use std::io::{Read, Write};
+
+async fn connect_offering(relay: &TestRelay, codecs: &[&str]) -> FakeClient {
+    let mut client = relay.open().await;
+    client
+        .send_raw(
+            &json!({
+                "clientId": null,
+                "clientOperation": { "ConnectAttempt": { "compression": codecs } },
+            })
+            .to_string(),
+        )
+        .await;
+    client.expect_approval().await;
+    client
+}
+
+/// An inventory big enough to be worth compressing
+fn inventory() -> String {
+    (0..200)
+        .map(|slot| format!("slot {}: Distillate of Divine Healing XIII", slot))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Wraps a frame the way a client that negotiated `codec` sends it
+fn pack(codec: Compression, frame: &str) -> String {
+    let compressed = match codec {
+        Compression::Zstd => zstd::encode_all(frame.as_bytes(), 3).unwrap(),
+        Compression::Deflate => {
+            let mut encoder =
+                flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(frame.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        }
+    };
+    json!({ "Compressed": { "codec": codec, "data": BASE64.encode(compressed) } }).to_string()
+}
+
+/// Reads a frame that must have come compressed, returning its codec and what it held
+async fn recv_packed(client: &mut FakeClient) -> (Compression, ServerOperation) {
+    let line = client
+        .recv_line()
+        .await
+        .expect("relay closed the connection");
+    assert!(line.ends_with('\n'), "frame is not newline terminated");
+    let envelope: serde_json::Value = serde_json::from_str(&line).unwrap();
+    let envelope = &envelope["Compressed"];
+    let codec: Compression = serde_json::from_value(envelope["codec"].clone())
+        .unwrap_or_else(|_| panic!("expected a compressed frame, got {:?}", line));
+    let data = BASE64.decode(envelope["data"].as_str().unwrap()).unwrap();
+    let mut frame = String::new();
+    match codec {
+        Compression::Zstd => zstd::stream::read::Decoder::new(&data[..])
+            .unwrap()
+            .read_to_string(&mut frame),
+        Compression::Deflate => {
+            flate2::read::DeflateDecoder::new(&data[..]).read_to_string(&mut frame)
+        }
+    }
+    .unwrap();
+    (codec, serde_json::from_str(&frame).unwrap())
+}
+
+fn text_of(operation: ServerOperation) -> String {
+    match operation {
+        ServerOperation::RoomMessage { message, .. } => message.0,
+        other => panic!("expected RoomMessage, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn the_clients_first_enabled_codec_is_announced() {
+    let relay = TestRelay::start().await;
+    let mut client = connect_offering(&relay, &["Deflate", "Zstd"]).await;
+    assert_eq!(
+        client.recv().await,
+        ServerOperation::CompressionEnabled {
+            codec: Compression::Deflate,
+            min_bytes: 1024
+        }
+    );
+
+    let mut config = Config::default();
+    config.compression.codecs = vec![Compression::Zstd];
+    let relay = TestRelay::start_with(config).await;
+    let mut client = connect_offering(&relay, &["Deflate", "Zstd"]).await;
+    assert!(matches!(
+        client.recv().await,
+        ServerOperation::CompressionEnabled {
+            codec: Compression::Zstd,
+            ..
+        }
+    ));
+}
+
+#[tokio::test]
+async fn a_room_mixes_compressed_and_plain_clients() {
+    let relay = TestRelay::start().await;
+    let mut zstd = connect_offering(&relay, &["Zstd"]).await;
+    zstd.recv().await;
+    let mut deflate = connect_offering(&relay, &["Deflate"]).await;
+    deflate.recv().await;
+    let mut plain = relay.connect().await;
+    for client in [&mut zstd, &mut deflate, &mut plain] {
+        client.join(&relay, "testRoom").await;
+    }
+
+    plain.say("testRoom", "inventory", &inventory()).await;
+    let (codec, operation) = recv_packed(&mut zstd).await;
+    assert_eq!(codec, Compression::Zstd);
+    assert_eq!(text_of(operation), inventory());
+    let (codec, operation) = recv_packed(&mut deflate).await;
+    assert_eq!(codec, Compression::Deflate);
+    assert_eq!(text_of(operation), inventory());
+    assert_eq!(text_of(plain.recv().await), inventory());
+}
+
+#[tokio::test]
+async fn small_frames_stay_plain() {
+    let relay = TestRelay::start().await;
+    let mut client = connect_offering(&relay, &["Zstd"]).await;
+    client.recv().await;
+    client.join(&relay, "testRoom").await;
+
+    client.say("testRoom", "chat", "inc").await;
+    assert_eq!(text_of(client.recv().await), "inc");
+}
+
+#[tokio::test]
+async fn clients_can_send_compressed_frames() {
+    let relay = TestRelay::start().await;
+    let mut sender = connect_offering(&relay, &["Deflate"]).await;
+    sender.recv().await;
+    let mut plain = relay.connect().await;
+    sender.join(&relay, "testRoom").await;
+    plain.join(&relay, "testRoom").await;
+
+    let frame = json!({
+        "clientId": sender.id(),
+        "clientOperation": { "Message": {
+            "room": "testRoom", "channel": "inventory", "message": inventory(),
+        } },
+    })
+    .to_string();
+    sender.send_raw(&pack(Compression::Deflate, &frame)).await;
+    assert_eq!(text_of(plain.recv().await), inventory());
+}
+
+#[tokio::test]
+async fn frames_that_inflate_past_the_limit_close_the_connection() {
+    let relay = TestRelay::start().await;
+    let mut client = connect_offering(&relay, &["Zstd"]).await;
+    client.recv().await;
+
+    // compresses to a few hundred bytes and decompresses to a megabyte
+    let bomb = " ".repeat(1024 * 1024);
+    client.send_raw(&pack(Compression::Zstd, &bomb)).await;
+    assert_eq!(client.recv_line().await, None);
+}
+
+#[tokio::test]
+async fn no_codecs_turns_compression_off() {
+    let mut config = Config::default();
+    config.compression.codecs.clear();
+    let relay = TestRelay::start_with(config).await;
+    let mut client = connect_offering(&relay, &["Zstd", "Deflate"]).await;
+    client.join(&relay, "testRoom").await;
+
+    client.say("testRoom", "inventory", &inventory()).await;
+    assert_eq!(text_of(client.recv().await), inventory());
+
+    client
+        .send_raw(&pack(Compression::Zstd, &super::lua::connect_request()))
+        .await;
+    assert_eq!(
+        client.recv().await,
+        ServerOperation::Nack {
+            reason: CompressionError::NotNegotiated(Compression::Zstd).to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn only_the_negotiated_codec_is_accepted() {
+    let relay = TestRelay::start().await;
+    let mut plain = relay.connect().await;
+    plain.join(&relay, "testRoom").await;
+    let frame = lua::actor_message(plain.clientId, "testRoom", "chat", "sneaky");
+    plain.send_raw(&pack(Compression::Zstd, &frame)).await;
+    assert_eq!(
+        plain.recv().await,
+        ServerOperation::Nack {
+            reason: CompressionError::NotNegotiated(Compression::Zstd).to_string()
+        }
+    );
+
+    let mut deflate = connect_offering(&relay, &["Deflate"]).await;
+    deflate.recv().await;
+    deflate.send_raw(&pack(Compression::Zstd, &frame)).await;
+    assert_eq!(
+        deflate.recv().await,
+        ServerOperation::Nack {
+            reason: CompressionError::NotNegotiated(Compression::Zstd).to_string()
+        }
+    );
+    plain.expect_silence().await;
+}
+
+#[test]
+fn the_threshold_has_to_fit_in_a_frame() {
+    let mut config = Config::default();
+    config.compression.min_bytes = config.limits.max_frame_bytes;
+    let problems = config.validate().unwrap_err().to_string();
+    assert!(problems.contains("compression.min_bytes"), "{}", problems);
+}
@@ -0,0 +1,194 @@
+use super::{lua, TestRelay};
+use crate::config::{Cli, Config, ConfigError, RoomConfig};
+use crate::error::OperationError;
+use crate::protocol::{Room, ServerOperation};
+use clap::Parser;
+use std::io::Write;
+
+fn cli(args: &[&str]) -> Cli {
+    Cli::try_parse_from(std::iter::once("relay_server").chain(args.iter().copied())).unwrap()
+}
+
+fn problems(config: &Config) -> Vec<String> {
+    match config.validate() {
+        Err(ConfigError::Invalid(problems)) => problems,
+        other => panic!("expected validation problems, got {:?}", other),
+    }
+}
+
+#[test]
+fn example_config_parses_and_validates() {
+    let config: Config = toml::from_str(include_str!("../../relay.example.toml")).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.server.tcp.unwrap().port(), 8080);
+    assert_eq!(config.rooms[0].name, "testRoom");
+}
+
+#[test]
+fn defaults_match_the_old_hardcoded_address() {
+    let config = Config::load(&cli(&[])).unwrap();
+    assert_eq!(config.server.tcp.unwrap().to_string(), "0.0.0.0:8080");
+}
+
+#[test]
+fn unknown_keys_are_rejected() {
+    let err = toml::from_str::<Config>("[limits]\nmax_clientz = 3\n").unwrap_err();
+    assert!(err.to_string().contains("max_clientz"), "{}", err);
+}
+
+#[test]
+fn validation_reports_every_problem() {
+    let mut config = Config::default();
+    config.limits.max_clients = 0;
+    config.logging.level = "loud".to_string();
+    config.auth.secret = Some("short".to_string());
+    config.rooms = vec![
+        RoomConfig {
+            name: "raid".to_string(),
+            ..Default::default()
+        },
+        RoomConfig {
+            name: "raid".to_string(),
+            ..Default::default()
+        },
+    ];
+
+    let problems = problems(&config);
+    assert_eq!(problems.len(), 4, "{:?}", problems);
+    let message = ConfigError::Invalid(problems).to_string();
+    assert!(message.contains("limits.max_clients"));
+    assert!(message.contains("'loud'"));
+    assert!(message.contains("auth.secret"));
+    assert!(message.contains("'raid' is configured more than once"));
+}
+
+#[test]
+fn required_auth_needs_something_to_authenticate_with() {
+    let mut config = Config::default();
+    config.auth.required = true;
+    assert!(problems(&config)[0].contains("auth.required"));
+
+    config.auth.secret = Some("sixteen chars or more".to_string());
+    config.validate().unwrap();
+}
+
+#[test]
+fn flags_override_the_file() {
+    let file = tempfile("[server]\ntcp = \"127.0.0.1:9000\"\n[history]\nroom_messages = 5\n");
+    let path = file.path.to_str().unwrap().to_string();
+    let config = Config::load(&cli(&["--config", &path, "--history", "7"])).unwrap();
+
+    assert_eq!(config.server.tcp.unwrap().to_string(), "127.0.0.1:9000");
+    assert_eq!(config.history.room_messages, 7);
+}
+
+#[test]
+fn missing_file_names_the_path() {
+    let err = Config::load(&cli(&["--config", "/nonexistent/relay.toml"])).unwrap_err();
+    assert!(err.to_string().contains("/nonexistent/relay.toml"));
+}
+
+#[test]
+fn invalid_flag_values_fail_validation() {
+    let err = Config::load(&cli(&["--max-frame-bytes", "3"])).unwrap_err();
+    assert!(err.to_string().contains("limits.max_frame_bytes"));
+}
+
+struct TempFile {
+    path: std::path::PathBuf,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn tempfile(contents: &str) -> TempFile {
+    let path = std::env::temp_dir().join(format!("relay-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::File::create(&path)
+        .unwrap()
+        .write_all(contents.as_bytes())
+        .unwrap();
+    TempFile { path }
+}
+
+#[tokio::test]
+async fn preconfigured_rooms_exist_at_startup() {
+    let mut config = Config::default();
+    config.rooms.push(RoomConfig {
+        name: "raid".to_string(),
+        ..Default::default()
+    });
+    let relay = TestRelay::start_with(config).await;
+
+    let server = relay.server.lock().await;
+    assert!(server.rooms.contains_key(&Room("raid".to_string())));
+}
+
+#[tokio::test]
+async fn oversized_frame_closes_the_connection() {
+    let mut config = Config::default();
+    config.limits.max_frame_bytes = 128;
+    let relay = TestRelay::start_with(config).await;
+    let mut client = relay.connect().await;
+
+    client
+        .say("testRoom", "testChannel", &"x".repeat(200))
+        .await;
+    client.expect_closed().await;
+}
+
+#[tokio::test]
+async fn connections_over_the_client_limit_are_refused() {
+    let mut config = Config::default();
+    config.limits.max_clients = 1;
+    let relay = TestRelay::start_with(config).await;
+    let _first = relay.connect().await;
+
+    let mut second = relay.open().await;
+    second.send_raw(&lua::connect_request()).await;
+    second.expect_closed().await;
+}
+
+#[tokio::test]
+async fn joins_past_the_room_limit_are_refused() {
+    let mut config = Config::default();
+    config.limits.max_rooms_per_client = 1;
+    let relay = TestRelay::start_with(config).await;
+    let mut client = relay.connect().await;
+    client.join(&relay, "first").await;
+
+    client
+        .send_raw(&lua::room_join(client.clientId, "second"))
+        .await;
+    client.expect_nack(OperationError::TooManyRooms(1)).await;
+    assert!(!relay.is_member("second", client.id()).await);
+}
+
+#[tokio::test]
+async fn new_members_get_the_room_history() {
+    let mut config = Config::default();
+    config.history.room_messages = 2;
+    let relay = TestRelay::start_with(config).await;
+    let mut a = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    for i in 0..3 {
+        a.say("testRoom", "testChannel", &format!("message {}", i))
+            .await;
+        a.recv().await;
+    }
+
+    let mut late = relay.connect().await;
+    late.join(&relay, "testRoom").await;
+
+    for i in 1..3 {
+        match late.recv().await {
+            ServerOperation::RoomMessage { message, .. } => {
+                assert_eq!(message.0, format!("message {}", i))
+            }
+            other => panic!("expected history, got {:?}", other),
+        }
+    }
+    late.expect_silence().await;
+}
@@ -0,0 +1,124 @@
+use super::TestRelay;
+use crate::config::Config;
+use crate::dedup::SeenIds;
+use crate::protocol::ServerOperation;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+fn message_with_id(message: &str, id: &str) -> String {
+    json!({
+        "clientId": null,
+        "clientOperation": { "Message": {
+            "room": "testRoom", "channel": "cmd", "message": message, "id": id,
+        } },
+    })
+    .to_string()
+}
+
+fn text_of(operation: ServerOperation) -> String {
+    match operation {
+        ServerOperation::RoomMessage { message, .. } => message.0,
+        other => panic!("expected RoomMessage, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn a_retried_send_is_relayed_once() {
+    let relay = TestRelay::start().await;
+    let mut caster = relay.connect().await;
+    let mut target = relay.connect().await;
+    caster.join(&relay, "testRoom").await;
+    target.join(&relay, "testRoom").await;
+
+    caster.send_raw(&message_with_id("cast heal", "m-1")).await;
+    caster.send_raw(&message_with_id("cast heal", "m-1")).await;
+
+    assert_eq!(text_of(target.recv().await), "cast heal");
+    target.expect_silence().await;
+    // Duplicate goes in a more urgent lane than the echo, so either can come first
+    let frames = [caster.recv().await, caster.recv().await];
+    assert!(frames.contains(&ServerOperation::Duplicate {
+        id: "m-1".to_string()
+    }));
+    assert!(frames
+        .iter()
+        .any(|frame| matches!(frame, ServerOperation::RoomMessage { .. })));
+}
+
+#[tokio::test]
+async fn ids_are_per_sender() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    let mut target = relay.connect().await;
+    target.join(&relay, "testRoom").await;
+
+    a.send_raw(&message_with_id("from a", "1")).await;
+    assert_eq!(text_of(target.recv().await), "from a");
+    b.send_raw(&message_with_id("from b", "1")).await;
+    assert_eq!(text_of(target.recv().await), "from b");
+}
+
+#[tokio::test]
+async fn a_retry_after_reconnecting_is_still_a_duplicate() {
+    let mut config = Config::default();
+    config
+        .auth
+        .tokens
+        .insert("box1".to_string(), "box1".to_string());
+    let relay = TestRelay::start_with(config).await;
+    let mut target = relay.connect().await;
+    target.join(&relay, "testRoom").await;
+
+    let mut first = relay.login("box1").await;
+    first
+        .send_raw(&message_with_id("stop attacking", "7"))
+        .await;
+    assert_eq!(text_of(target.recv().await), "stop attacking");
+    drop(first);
+
+    let mut second = relay.login("box1").await;
+    second
+        .send_raw(&message_with_id("stop attacking", "7"))
+        .await;
+    assert_eq!(
+        second.recv().await,
+        ServerOperation::Duplicate {
+            id: "7".to_string()
+        }
+    );
+    target.expect_silence().await;
+}
+
+#[tokio::test]
+async fn a_zero_window_turns_dedup_off() {
+    let mut config = Config::default();
+    config.dedup.window_secs = 0;
+    let relay = TestRelay::start_with(config).await;
+    let mut a = relay.connect().await;
+    let mut target = relay.connect().await;
+    target.join(&relay, "testRoom").await;
+
+    a.send_raw(&message_with_id("again", "x")).await;
+    a.send_raw(&message_with_id("again", "x")).await;
+    assert_eq!(text_of(target.recv().await), "again");
+    assert_eq!(text_of(target.recv().await), "again");
+}
+
+#[test]
+fn ids_are_forgotten_past_the_window_or_the_limit() {
+    let start = Instant::now();
+    let window = Duration::from_secs(60);
+    let mut seen = SeenIds::default();
+    seen.remember("a", start, 2);
+    assert!(seen.seen("a", start + Duration::from_secs(59), window));
+    assert!(!seen.seen("a", start + Duration::from_secs(60), window));
+    assert!(seen.is_empty());
+
+    seen.remember("a", start, 2);
+    seen.remember("b", start, 2);
+    seen.remember("c", start, 2);
+    assert!(!seen.seen("a", start, window));
+    assert!(seen.seen("b", start, window));
+    assert!(seen.seen("c", start, window));
+}
@@ -0,0 +1,109 @@
+use super::{identities, TestRelay};
+use crate::config::{Config, ConfigError, RoomConfig};
+use crate::protocol::{ClientId, Identity, Room, ServerOperation};
+use std::collections::HashMap;
+
+/// A relay with a "raid" that elects its leader
+async fn relay_with(priorities: &[(&str, i64)]) -> TestRelay {
+    let mut config = identities(&["tank", "puller"]);
+    config.rooms.push(RoomConfig {
+        name: "raid".to_string(),
+        elect_leader: true,
+        leader_priority: priorities
+            .iter()
+            .map(|(identity, priority)| (identity.to_string(), *priority))
+            .collect(),
+        ..Default::default()
+    });
+    TestRelay::start_with(config).await
+}
+
+fn leader(leader: ClientId, identity: Option<&str>, term: u64) -> ServerOperation {
+    ServerOperation::LeaderChanged {
+        room: Room("raid".to_string()),
+        leader,
+        identity: identity.map(|identity| Identity(identity.to_string())),
+        term,
+    }
+}
+
+#[tokio::test]
+async fn the_first_to_join_leads_and_newcomers_are_told() {
+    let relay = relay_with(&[]).await;
+    let mut first = relay.connect().await;
+    first.join(&relay, "raid").await;
+    assert_eq!(first.recv().await, leader(first.id(), None, 1));
+
+    let mut second = relay.connect().await;
+    second.join(&relay, "raid").await;
+    assert_eq!(second.recv().await, leader(first.id(), None, 1));
+    first.expect_silence().await;
+}
+
+#[tokio::test]
+async fn a_higher_priority_takes_over() {
+    let relay = relay_with(&[("tank", 10), ("puller", 5)]).await;
+    let mut anonymous = relay.connect().await;
+    anonymous.join(&relay, "raid").await;
+    anonymous.recv().await;
+
+    let mut tank = relay.login("tank").await;
+    tank.join(&relay, "raid").await;
+    let took_over = leader(tank.id(), Some("tank"), 2);
+    for client in [&mut anonymous, &mut tank] {
+        assert_eq!(client.recv().await, took_over);
+    }
+
+    // outranked by the tank, so nothing changes
+    let mut puller = relay.login("puller").await;
+    puller.join(&relay, "raid").await;
+    assert_eq!(puller.recv().await, took_over);
+    tank.expect_silence().await;
+}
+
+#[tokio::test]
+async fn the_next_in_line_leads_when_the_leader_goes_away() {
+    let relay = relay_with(&[("puller", 5)]).await;
+    let mut boxes = Vec::new();
+    for _ in 0..2 {
+        let mut client = relay.connect().await;
+        client.join(&relay, "raid").await;
+        boxes.push(client);
+    }
+    let first = boxes[0].id();
+    for client in &mut boxes {
+        assert_eq!(client.recv().await, leader(first, None, 1));
+    }
+    let mut puller = relay.login("puller").await;
+    puller.join(&relay, "raid").await;
+    let took_over = leader(puller.id(), Some("puller"), 2);
+    for client in boxes.iter_mut().chain([&mut puller]) {
+        assert_eq!(client.recv().await, took_over);
+    }
+
+    // the puller's box crashes, the earliest of the rest is next
+    drop(puller);
+    for client in &mut boxes {
+        assert_eq!(client.recv().await, leader(first, None, 3));
+    }
+
+    boxes[0].leave(&relay, "raid").await;
+    assert_eq!(boxes[1].recv().await, leader(boxes[1].id(), None, 4));
+    boxes[0].expect_silence().await;
+}
+
+#[test]
+fn leader_priority_needs_elect_leader() {
+    let mut config = Config::default();
+    config.rooms.push(RoomConfig {
+        name: "raid".to_string(),
+        leader_priority: HashMap::from([("tank".to_string(), 10)]),
+        ..Default::default()
+    });
+    match config.validate() {
+        Err(ConfigError::Invalid(problems)) => {
+            assert!(problems[0].contains("leader_priority"), "{:?}", problems)
+        }
+        other => panic!("expected validation problems, got {:?}", other),
+    }
+}
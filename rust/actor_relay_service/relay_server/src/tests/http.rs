@@ -0,0 +1,214 @@
+use super::TestRelay;
+use crate::config::Config;
+use crate::http::{ClientInfo, RoomInfo};
+use crate::protocol::{ClientId, Identity, Room, ServerOperation};
+use serde_json::json;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Just enough HTTP/1.1 to talk to the admin API, gives back the status and the body
+pub async fn request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> (u16, String) {
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    );
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .unwrap_or_default();
+    (status, body)
+}
+
+#[tokio::test]
+async fn lists_clients_with_their_rooms_and_queues() {
+    let relay = TestRelay::start().await;
+    let http = relay.listen_http().await;
+    let mut a = relay.connect().await;
+    let b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+
+    let (status, body) = request(http, "GET", "/clients", None).await;
+    assert_eq!(status, 200);
+    let clients: Vec<ClientInfo> = serde_json::from_str(&body).unwrap();
+    assert_eq!(clients.len(), 2);
+    let a_info = clients
+        .iter()
+        .find(|client| client.clientId == a.id())
+        .unwrap();
+    assert_eq!(a_info.rooms, vec![Room("testRoom".to_string())]);
+    assert_eq!(a_info.identity, None);
+    assert_eq!(a_info.queued, 0);
+    let b_info = clients
+        .iter()
+        .find(|client| client.clientId == b.id())
+        .unwrap();
+    assert!(b_info.rooms.is_empty());
+}
+
+#[tokio::test]
+async fn queue_depth_counts_unwritten_frames() {
+    let (tx, mut rx) = crate::outbox::channel();
+    tx.send("one\n".to_string()).unwrap();
+    tx.send("two\n".to_string()).unwrap();
+    assert_eq!(tx.depth(), 2);
+    rx.recv().await.unwrap();
+    assert_eq!(tx.depth(), 1);
+}
+
+#[tokio::test]
+async fn lists_rooms_with_members() {
+    let mut config = Config::default();
+    config
+        .auth
+        .tokens
+        .insert("lead".to_string(), "lead".to_string());
+    let relay = TestRelay::start_with(config).await;
+    let http = relay.listen_http().await;
+    let mut a = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+
+    let mut owner = relay.open().await;
+    owner
+        .send_raw(
+            &json!({
+                "clientId": null,
+                "clientOperation": { "ConnectAttempt": { "credentials": { "Token": "lead" } } },
+            })
+            .to_string(),
+        )
+        .await;
+    owner.expect_approval().await;
+    owner
+        .send_raw(
+            &json!({ "clientId": null, "clientOperation": { "RoomCreate": { "room": "raid" } } })
+                .to_string(),
+        )
+        .await;
+    relay.wait_for_member("raid", owner.id(), true).await;
+
+    let (status, body) = request(http, "GET", "/rooms", None).await;
+    assert_eq!(status, 200);
+    let rooms: Vec<RoomInfo> = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        rooms,
+        vec![
+            RoomInfo {
+                room: Room("raid".to_string()),
+                members: vec![owner.id()],
+                owner: Some(Identity("lead".to_string())),
+                private: true,
+            },
+            RoomInfo {
+                room: Room("testRoom".to_string()),
+                members: vec![a.id()],
+                owner: None,
+                private: false,
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn kick_disconnects_the_client() {
+    let relay = TestRelay::start().await;
+    let http = relay.listen_http().await;
+    let mut victim = relay.connect().await;
+
+    let (status, _) = request(
+        http,
+        "POST",
+        &format!("/clients/{}/kick", victim.id()),
+        None,
+    )
+    .await;
+    assert_eq!(status, 204);
+    assert_eq!(
+        victim.recv().await,
+        ServerOperation::Kicked {
+            by: Identity("operator".to_string())
+        }
+    );
+    victim.expect_closed().await;
+
+    let (status, body) = request(
+        http,
+        "POST",
+        &format!("/clients/{}/kick", victim.id()),
+        None,
+    )
+    .await;
+    assert_eq!(status, 404);
+    assert!(body.contains("no client"), "{}", body);
+}
+
+#[tokio::test]
+async fn broadcast_reaches_every_member() {
+    let relay = TestRelay::start().await;
+    let http = relay.listen_http().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+
+    let (status, body) = request(
+        http,
+        "POST",
+        "/rooms/testRoom/broadcast",
+        Some(json!({ "channel": "ops", "message": "relay restarting soon" })),
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&body).unwrap()["delivered"],
+        2
+    );
+
+    for client in [&mut a, &mut b] {
+        match client.recv().await {
+            ServerOperation::RoomMessage {
+                sender, message, ..
+            } => {
+                assert_eq!(sender, ClientId::RELAY);
+                assert_eq!(message.0, "relay restarting soon");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    let (status, _) = request(
+        http,
+        "POST",
+        "/rooms/nowhere/broadcast",
+        Some(json!({ "channel": "ops", "message": "hello?" })),
+    )
+    .await;
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn remote_binds_need_allow_remote() {
+    let mut config = Config::default();
+    config.http.bind = Some("0.0.0.0:8082".parse().unwrap());
+    let problems = config.validate().unwrap_err().to_string();
+    assert!(problems.contains("http.allow_remote"), "{}", problems);
+
+    config.http.allow_remote = true;
+    config.validate().unwrap();
+}
@@ -0,0 +1,226 @@
+use super::TestRelay;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::outbox::{self, Delivery};
+use crate::protocol::{Identity, Priority, ServerOperation};
+use crate::server::Server;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn lane(priority: Priority) -> Delivery {
+    Delivery {
+        priority,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn urgent_lanes_are_written_first() {
+    let (tx, mut rx) = outbox::channel();
+    for n in 0..3 {
+        tx.send_with(format!("status {}", n), lane(Priority::Bulk))
+            .unwrap();
+    }
+    tx.send("chat".to_string()).unwrap();
+    tx.send_with("stop attacking".to_string(), lane(Priority::Control))
+        .unwrap();
+    tx.send_with("shutting down".to_string(), lane(Priority::System))
+        .unwrap();
+    assert_eq!(tx.depth(), 6);
+
+    let mut written = Vec::new();
+    for _ in 0..6 {
+        written.push(rx.recv().await.unwrap());
+    }
+    assert_eq!(
+        written,
+        vec![
+            "shutting down",
+            "stop attacking",
+            "chat",
+            "status 0",
+            "status 1",
+            "status 2"
+        ]
+    );
+    assert_eq!(tx.depth(), 0);
+}
+
+#[tokio::test]
+async fn a_stream_keeps_its_order_across_lanes() {
+    let (tx, mut rx) = outbox::channel();
+    let raid = |priority| lane(priority).in_stream("raid");
+    tx.send_with("raid 1".to_string(), raid(Priority::Bulk))
+        .unwrap();
+    tx.send_with("status".to_string(), lane(Priority::Bulk))
+        .unwrap();
+    tx.send_with("raid 2".to_string(), raid(Priority::Normal))
+        .unwrap();
+    tx.send_with("stop attacking".to_string(), lane(Priority::Control))
+        .unwrap();
+    tx.send_with("raid 3".to_string(), raid(Priority::Control))
+        .unwrap();
+    tx.send_with("raid 4".to_string(), raid(Priority::Bulk))
+        .unwrap();
+
+    let mut written = Vec::new();
+    for _ in 0..6 {
+        written.push(rx.recv().await.unwrap());
+    }
+    assert_eq!(
+        written,
+        vec![
+            "stop attacking",
+            "raid 1",
+            "raid 2",
+            "raid 3",
+            "status",
+            "raid 4"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn expired_frames_are_dropped_before_writing() {
+    let metrics = Arc::new(Metrics::new());
+    let (tx, rx) = outbox::channel();
+    let mut rx = rx.with_metrics(Arc::clone(&metrics));
+    tx.send_with(
+        "too late".to_string(),
+        Delivery {
+            priority: Priority::Control,
+            expires: Some(Instant::now() - Duration::from_millis(1)),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    tx.send_with(
+        "in time".to_string(),
+        Delivery::new(Priority::Normal, Some(60_000)),
+    )
+    .unwrap();
+
+    assert_eq!(rx.recv().await.unwrap(), "in time");
+    assert_eq!(tx.depth(), 0);
+    let text = metrics
+        .render(&Server::new(Arc::new(Config::default())))
+        .unwrap();
+    assert!(text.contains("relay_expired_messages_total 1"), "{}", text);
+}
+
+#[tokio::test]
+async fn queue_ends_with_its_last_sender() {
+    let (tx, mut rx) = outbox::channel();
+    let other = tx.clone();
+    tx.send("last words".to_string()).unwrap();
+    drop(tx);
+    drop(other);
+    assert_eq!(rx.recv().await.unwrap(), "last words");
+    assert_eq!(rx.recv().await, None);
+
+    let (tx, rx) = outbox::channel();
+    drop(rx);
+    assert!(tx.send("nobody listening".to_string()).is_err());
+}
+
+#[test]
+fn clients_can_not_claim_the_system_lane() {
+    assert_eq!(Priority::System.for_client(), Priority::Control);
+    assert_eq!(Priority::Bulk.for_client(), Priority::Bulk);
+    assert_eq!(Priority::default(), Priority::Normal);
+}
+
+#[tokio::test]
+async fn messages_carry_priority_and_ttl() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+
+    // a ttl of zero is over before any writer gets to it
+    a.send_raw(
+        &json!({
+            "clientId": a.id(),
+            "clientOperation": { "Message": {
+                "room": "testRoom", "channel": "status", "message": "hp 100%",
+                "priority": "Bulk", "ttl_ms": 0,
+            } },
+        })
+        .to_string(),
+    )
+    .await;
+    b.expect_silence().await;
+
+    a.send_raw(
+        &json!({
+            "clientId": a.id(),
+            "clientOperation": { "Message": {
+                "room": "testRoom", "channel": "cmd", "message": "stop attacking",
+                "priority": "Control", "ttl_ms": 60000,
+            } },
+        })
+        .to_string(),
+    )
+    .await;
+    match b.recv().await {
+        ServerOperation::RoomMessage { message, .. } => {
+            assert_eq!(message.0, "stop attacking")
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn mail_past_its_ttl_is_not_delivered() {
+    let mut config = Config::default();
+    config
+        .auth
+        .tokens
+        .insert("healer".to_string(), "healer".to_string());
+    let relay = TestRelay::start_with(config).await;
+    let mut sender = relay.connect().await;
+    for (message, ttl_ms) in [("expired", json!(0)), ("kept", json!(null))] {
+        sender
+            .send_raw(
+                &json!({
+                    "clientId": null,
+                    "clientOperation": { "DirectMessage": {
+                        "to": "healer", "channel": "assign", "message": message,
+                        "ttl_ms": ttl_ms,
+                    } },
+                })
+                .to_string(),
+            )
+            .await;
+    }
+    let healer = Identity("healer".to_string());
+    relay
+        .wait_until(|server| {
+            server
+                .mailboxes
+                .get(&healer)
+                .is_some_and(|mailbox| mailbox.len() == 2)
+        })
+        .await;
+
+    let mut client = relay.open().await;
+    client
+        .send_raw(
+            &json!({
+                "clientId": null,
+                "clientOperation": { "ConnectAttempt": { "credentials": { "Token": "healer" } } },
+            })
+            .to_string(),
+        )
+        .await;
+    client.expect_approval().await;
+    match client.recv().await {
+        ServerOperation::DirectMessage { message, .. } => {
+            assert_eq!(message.0, "kept")
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    client.expect_silence().await;
+}
@@ -0,0 +1,212 @@
+use super::lua::operation;
+use super::{FakeClient, TestRelay};
+use crate::config::{Config, RoomConfig};
+use crate::error::OperationError;
+use crate::protocol::{Room, ServerOperation};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+async fn acquire(client: &mut FakeClient, name: &str, ttl_ms: u64) {
+    client
+        .send_raw(&operation(json!({ "LockAcquire": {
+            "room": "testRoom", "name": name, "ttl_ms": ttl_ms,
+        } })))
+        .await;
+}
+
+async fn release(client: &mut FakeClient, name: &str) {
+    client
+        .send_raw(&operation(
+            json!({ "LockRelease": { "room": "testRoom", "name": name } }),
+        ))
+        .await;
+}
+
+fn granted(name: &str, token: u64, ttl_ms: u64) -> ServerOperation {
+    ServerOperation::LockGranted {
+        room: Room("testRoom".to_string()),
+        name: name.to_string(),
+        token,
+        ttl_ms,
+    }
+}
+
+fn queued(name: &str, position: usize) -> ServerOperation {
+    ServerOperation::LockQueued {
+        room: Room("testRoom".to_string()),
+        name: name.to_string(),
+        position,
+    }
+}
+
+async fn looters(relay: &TestRelay, count: usize) -> Vec<FakeClient> {
+    let mut clients = Vec::new();
+    for _ in 0..count {
+        let mut client = relay.connect().await;
+        client.join(relay, "testRoom").await;
+        clients.push(client);
+    }
+    clients
+}
+
+#[tokio::test]
+async fn one_looter_at_a_time_in_queue_order() {
+    let relay = TestRelay::start().await;
+    let mut boxes = looters(&relay, 3).await;
+
+    acquire(&mut boxes[0], "corpse", 10_000).await;
+    assert_eq!(boxes[0].recv().await, granted("corpse", 1, 10_000));
+    acquire(&mut boxes[1], "corpse", 5_000).await;
+    assert_eq!(boxes[1].recv().await, queued("corpse", 0));
+    acquire(&mut boxes[2], "corpse", 5_000).await;
+    assert_eq!(boxes[2].recv().await, queued("corpse", 1));
+
+    release(&mut boxes[0], "corpse").await;
+    assert_eq!(boxes[1].recv().await, granted("corpse", 2, 5_000));
+    boxes[2].expect_silence().await;
+    release(&mut boxes[1], "corpse").await;
+    assert_eq!(boxes[2].recv().await, granted("corpse", 3, 5_000));
+}
+
+#[tokio::test]
+async fn a_disconnected_holder_hands_the_lock_on() {
+    let relay = TestRelay::start().await;
+    let mut boxes = looters(&relay, 2).await;
+    acquire(&mut boxes[0], "door", 10_000).await;
+    boxes[0].recv().await;
+    acquire(&mut boxes[1], "door", 10_000).await;
+    boxes[1].recv().await;
+
+    boxes.remove(0);
+    assert_eq!(boxes[0].recv().await, granted("door", 2, 10_000));
+}
+
+#[tokio::test]
+async fn a_lease_that_runs_out_moves_on() {
+    let relay = TestRelay::start().await;
+    let mut boxes = looters(&relay, 2).await;
+    let started = Instant::now();
+    acquire(&mut boxes[0], "corpse", 150).await;
+    boxes[0].recv().await;
+    acquire(&mut boxes[1], "corpse", 10_000).await;
+    boxes[1].recv().await;
+
+    assert_eq!(
+        boxes[0].recv().await,
+        ServerOperation::LockExpired {
+            room: Room("testRoom".to_string()),
+            name: "corpse".to_string(),
+            token: 1,
+        }
+    );
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    assert_eq!(boxes[1].recv().await, granted("corpse", 2, 10_000));
+
+    // too late, the lock is someone else's now
+    release(&mut boxes[0], "corpse").await;
+    assert_eq!(
+        boxes[0].recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NotLockHolder("corpse".to_string()).to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn the_holder_renews_by_acquiring_again() {
+    let relay = TestRelay::start().await;
+    let mut boxes = looters(&relay, 1).await;
+    acquire(&mut boxes[0], "door", 200).await;
+    boxes[0].recv().await;
+    tokio::time::sleep(Duration::from_millis(120)).await;
+    acquire(&mut boxes[0], "door", 500).await;
+    assert_eq!(boxes[0].recv().await, granted("door", 1, 500));
+
+    // past the first lease but not the renewed one
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    boxes[0].expect_silence().await;
+    assert!(matches!(
+        boxes[0].recv().await,
+        ServerOperation::LockExpired { token: 1, .. }
+    ));
+}
+
+#[tokio::test]
+async fn waiters_can_leave_the_queue() {
+    let relay = TestRelay::start().await;
+    let mut boxes = looters(&relay, 3).await;
+    acquire(&mut boxes[0], "corpse", 10_000).await;
+    boxes[0].recv().await;
+    for looter in &mut boxes[1..] {
+        acquire(looter, "corpse", 10_000).await;
+        looter.recv().await;
+    }
+
+    release(&mut boxes[1], "corpse").await;
+    relay
+        .wait_until(|server| {
+            server.locks[&(Room("testRoom".to_string()), "corpse".to_string())]
+                .waiters
+                .len()
+                == 1
+        })
+        .await;
+    release(&mut boxes[0], "corpse").await;
+    assert_eq!(boxes[2].recv().await, granted("corpse", 2, 10_000));
+    boxes[1].expect_silence().await;
+}
+
+#[tokio::test]
+async fn refused_acquires() {
+    let mut config = Config::default();
+    config.locks.max_ttl_ms = 1000;
+    config.rooms.push(RoomConfig {
+        name: "raid".to_string(),
+        owner: Some("leader".to_string()),
+        ..Default::default()
+    });
+    let relay = TestRelay::start_with(config).await;
+    let mut boxes = looters(&relay, 1).await;
+
+    for ttl_ms in [0, 1001] {
+        acquire(&mut boxes[0], "door", ttl_ms).await;
+        assert_eq!(
+            boxes[0].recv().await,
+            ServerOperation::Nack {
+                reason: OperationError::BadTtl(1000).to_string()
+            }
+        );
+    }
+    boxes[0]
+        .send_raw(&operation(json!({ "LockAcquire": {
+            "room": "raid", "name": "door", "ttl_ms": 500,
+        } })))
+        .await;
+    assert_eq!(
+        boxes[0].recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NotMember(Room("raid".to_string())).to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn idle_locks_are_forgotten_but_their_tokens_are_not() {
+    let relay = TestRelay::start().await;
+    let mut boxes = looters(&relay, 1).await;
+    acquire(&mut boxes[0], "corpse", 10_000).await;
+    assert_eq!(boxes[0].recv().await, granted("corpse", 1, 10_000));
+    release(&mut boxes[0], "corpse").await;
+    relay.wait_until(|server| server.locks.is_empty()).await;
+
+    acquire(&mut boxes[0], "door", 100).await;
+    assert_eq!(boxes[0].recv().await, granted("door", 2, 100));
+    assert!(matches!(
+        boxes[0].recv().await,
+        ServerOperation::LockExpired { token: 2, .. }
+    ));
+    relay.wait_until(|server| server.locks.is_empty()).await;
+
+    acquire(&mut boxes[0], "corpse", 10_000).await;
+    assert_eq!(boxes[0].recv().await, granted("corpse", 3, 10_000));
+}
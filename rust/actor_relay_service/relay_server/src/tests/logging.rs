@@ -0,0 +1,138 @@
+use super::TestRelay;
+use crate::config::{Config, LogFormat, LoggingConfig};
+use crate::logging;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Collects everything logged while it is the default subscriber
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Captured;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Runs a client through a room message with logging set up like `logging`, returns the log.
+/// tokio::test runs on one thread, so the relay's tasks all log to the thread local subscriber
+async fn log_of_a_message(logging: LoggingConfig) -> (String, crate::protocol::ClientId) {
+    let captured = Captured::default();
+    let _guard =
+        tracing::subscriber::set_default(logging::subscriber(&logging, captured.clone(), false));
+    let config = Config {
+        logging,
+        ..Default::default()
+    };
+    let relay = TestRelay::start_with(config).await;
+    let mut client = relay.connect().await;
+    client.join(&relay, "testRoom").await;
+    client
+        .say("testRoom", "testChannel", "pull at 9 sharp")
+        .await;
+    client.recv().await;
+    (captured.text(), client.id())
+}
+
+#[tokio::test]
+async fn lines_carry_the_client_span() {
+    let (log, clientId) = log_of_a_message(LoggingConfig::default()).await;
+    let joined = log
+        .lines()
+        .find(|line| line.contains("joined room"))
+        .unwrap_or_else(|| panic!("no join in\n{}", log));
+    assert!(
+        joined.contains(&format!("client{{clientId={}}}", clientId)),
+        "{}",
+        joined
+    );
+}
+
+#[tokio::test]
+async fn payloads_stay_out_of_the_log_by_default() {
+    let (log, _) = log_of_a_message(LoggingConfig {
+        level: "debug".to_string(),
+        ..Default::default()
+    })
+    .await;
+    assert!(log.contains("Received client message"), "{}", log);
+    assert!(!log.contains("pull at 9 sharp"), "{}", log);
+}
+
+#[tokio::test]
+async fn payloads_can_be_turned_on() {
+    let (log, _) = log_of_a_message(LoggingConfig {
+        level: "debug".to_string(),
+        payloads: true,
+        ..Default::default()
+    })
+    .await;
+    assert!(log.contains("pull at 9 sharp"), "{}", log);
+}
+
+#[tokio::test]
+async fn per_module_levels_apply() {
+    let (log, _) = log_of_a_message(LoggingConfig {
+        level: "warn,relay_server::session=debug".to_string(),
+        ..Default::default()
+    })
+    .await;
+    assert!(log.contains("Received client message"), "{}", log);
+    // info from other modules is filtered out
+    assert!(!log.contains("Sent message to client"), "{}", log);
+    assert!(log
+        .lines()
+        .all(|line| !line.contains(" INFO ") || line.contains("relay_server::session")));
+}
+
+#[tokio::test]
+async fn json_lines_parse_and_name_the_client() {
+    let (log, clientId) = log_of_a_message(LoggingConfig {
+        format: LogFormat::Json,
+        ..Default::default()
+    })
+    .await;
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(lines.iter().any(|line| {
+        line["fields"]["message"]
+            .as_str()
+            .is_some_and(|message| message.contains("joined room"))
+            && line["span"]["clientId"] == clientId.to_string()
+    }));
+}
+
+#[test]
+fn levels_must_be_spelled_out() {
+    let mut config = Config::default();
+    for good in ["debug", "info,relay_server::session=debug", "WARN"] {
+        config.logging.level = good.to_string();
+        config.validate().unwrap();
+    }
+    for bad in ["inof", "relay_server::session", "", "info,=debug"] {
+        config.logging.level = bad.to_string();
+        assert!(config.validate().is_err(), "{:?} was accepted", bad);
+    }
+}
@@ -0,0 +1,192 @@
+use super::lua::operation;
+use super::{identities, FakeClient, TestRelay};
+use crate::config::Config;
+use crate::error::OperationError;
+use crate::protocol::{Channel, ClientId, Identity, Message, ServerOperation};
+use serde_json::json;
+
+fn config() -> Config {
+    identities(&["leader", "healer"])
+}
+
+async fn direct(client: &mut FakeClient, to: &str, message: &str) {
+    client
+        .send_raw(&operation(json!({
+            "DirectMessage": { "to": to, "channel": "assign", "message": message }
+        })))
+        .await;
+}
+
+async fn wait_for_mail(relay: &TestRelay, identity: &str, count: usize) {
+    let identity = Identity(identity.to_string());
+    relay
+        .wait_until(|server| {
+            server
+                .mailboxes
+                .get(&identity)
+                .is_some_and(|mailbox| mailbox.len() == count)
+        })
+        .await;
+}
+
+/// The message text and queued_at of a DirectMessage
+fn received(operation: ServerOperation) -> (String, Option<u64>) {
+    match operation {
+        ServerOperation::DirectMessage {
+            message, queued_at, ..
+        } => (message.0, queued_at),
+        other => panic!("expected DirectMessage, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn connected_identities_get_direct_messages_right_away() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut leader = relay.login("leader").await;
+    let mut healer = relay.login("healer").await;
+
+    direct(&mut leader, "healer", "heal the tank").await;
+    assert_eq!(
+        healer.recv().await,
+        ServerOperation::DirectMessage {
+            sender: leader.id(),
+            from: Some(Identity("leader".to_string())),
+            channel: Channel("assign".to_string()),
+            message: Message("heal the tank".to_string()),
+            queued_at: None,
+        }
+    );
+    leader.expect_silence().await;
+    assert!(relay.server.lock().await.mailboxes.is_empty());
+}
+
+#[tokio::test]
+async fn offline_identities_get_their_mail_in_order_on_connect() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut leader = relay.login("leader").await;
+    direct(&mut leader, "healer", "one").await;
+    direct(&mut leader, "healer", "two").await;
+    wait_for_mail(&relay, "healer", 2).await;
+
+    let mut healer = relay.login("healer").await;
+    let (first, queued_at) = received(healer.recv().await);
+    assert_eq!(first, "one");
+    assert!(queued_at.is_some());
+    assert_eq!(received(healer.recv().await).0, "two");
+    healer.expect_silence().await;
+    assert!(relay.server.lock().await.mailboxes.is_empty());
+
+    // delivered once only
+    drop(healer);
+    let mut healer = relay.login("healer").await;
+    healer.expect_silence().await;
+}
+
+#[tokio::test]
+async fn unknown_identities_have_no_mailbox() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut leader = relay.login("leader").await;
+    direct(&mut leader, "stranger", "hello?").await;
+    assert_eq!(
+        leader.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NoMailbox(Identity("stranger".to_string())).to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn full_mailboxes_drop_the_oldest() {
+    let mut config = config();
+    config.mailbox.max_messages = 2;
+    let relay = TestRelay::start_with(config).await;
+    let mut leader = relay.login("leader").await;
+    for message in ["one", "two", "three"] {
+        direct(&mut leader, "healer", message).await;
+    }
+    relay
+        .wait_until(|server| {
+            server
+                .mailboxes
+                .get(&Identity("healer".to_string()))
+                .and_then(|mailbox| mailbox.back())
+                .is_some_and(|mail| received(mail.operation.clone()).0 == "three")
+        })
+        .await;
+
+    let mut healer = relay.login("healer").await;
+    assert_eq!(received(healer.recv().await).0, "two");
+    assert_eq!(received(healer.recv().await).0, "three");
+    healer.expect_silence().await;
+}
+
+#[tokio::test]
+async fn expired_mail_is_not_delivered() {
+    let mut config = config();
+    config.mailbox.max_age_secs = 60;
+    let relay = TestRelay::start_with(config).await;
+    let mut leader = relay.login("leader").await;
+    direct(&mut leader, "healer", "stale").await;
+    direct(&mut leader, "healer", "fresh").await;
+    wait_for_mail(&relay, "healer", 2).await;
+    relay
+        .server
+        .lock()
+        .await
+        .mailboxes
+        .get_mut(&Identity("healer".to_string()))
+        .unwrap()[0]
+        .at -= 120;
+
+    let mut healer = relay.login("healer").await;
+    assert_eq!(received(healer.recv().await).0, "fresh");
+    healer.expect_silence().await;
+}
+
+#[tokio::test]
+async fn huge_ttls_keep_mail_instead_of_overflowing() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut leader = relay.login("leader").await;
+    leader
+        .send_raw(&operation(json!({
+            "DirectMessage": {
+                "to": "healer", "channel": "assign", "message": "whenever", "ttl_ms": u64::MAX,
+            }
+        })))
+        .await;
+    wait_for_mail(&relay, "healer", 1).await;
+
+    let mut healer = relay.login("healer").await;
+    assert_eq!(received(healer.recv().await).0, "whenever");
+}
+
+#[tokio::test]
+async fn mailboxes_can_be_turned_off() {
+    let mut config = config();
+    config.mailbox.max_messages = 0;
+    let relay = TestRelay::start_with(config).await;
+    let mut leader = relay.login("leader").await;
+    direct(&mut leader, "healer", "lost").await;
+    assert_eq!(
+        leader.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::NoMailbox(Identity("healer".to_string())).to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn anonymous_senders_are_marked_as_such() {
+    let relay = TestRelay::start_with(config()).await;
+    let mut anonymous = relay.connect().await;
+    let mut healer = relay.login("healer").await;
+    direct(&mut anonymous, "healer", "psst").await;
+    match healer.recv().await {
+        ServerOperation::DirectMessage { sender, from, .. } => {
+            assert_eq!(sender, anonymous.id());
+            assert_eq!(from, None);
+            assert_ne!(sender, ClientId::RELAY);
+        }
+        other => panic!("expected DirectMessage, got {:?}", other),
+    }
+}
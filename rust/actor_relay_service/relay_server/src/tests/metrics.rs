@@ -0,0 +1,107 @@
+use super::http::request;
+use super::TestRelay;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// The value of an unlabelled sample, or of the one with `labels` as written in the exposition
+fn sample(text: &str, name: &str) -> f64 {
+    text.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no sample {} in\n{}", name, text))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn reports_clients_rooms_and_members() {
+    let relay = TestRelay::start().await;
+    let http = relay.listen_http().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+    b.join(&relay, "otherRoom").await;
+
+    let (status, text) = request(http, "GET", "/metrics", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(sample(&text, "relay_clients"), 2.0);
+    assert_eq!(sample(&text, "relay_rooms"), 2.0);
+    assert_eq!(sample(&text, r#"relay_room_members{room="testRoom"}"#), 2.0);
+    assert_eq!(
+        sample(&text, r#"relay_room_members{room="otherRoom"}"#),
+        1.0
+    );
+}
+
+#[tokio::test]
+async fn empty_rooms_drop_out_of_the_metrics() {
+    let relay = TestRelay::start().await;
+    let http = relay.listen_http().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "otherRoom").await;
+    b.leave(&relay, "otherRoom").await;
+    let a_id = a.id();
+    drop(a);
+    relay
+        .wait_until(|server| !server.clients.contains_key(&a_id))
+        .await;
+
+    let (_, text) = request(http, "GET", "/metrics", None).await;
+    assert_eq!(sample(&text, "relay_rooms"), 0.0);
+    assert!(!text.contains("relay_room_members{"), "{}", text);
+}
+
+#[tokio::test]
+async fn counts_traffic_and_fanout() {
+    let relay = TestRelay::start().await;
+    let http = relay.listen_http().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+
+    a.say("testRoom", "testChannel", "one").await;
+    a.recv().await;
+    b.recv().await;
+
+    let (_, text) = request(http, "GET", "/metrics", None).await;
+    // two connects, two joins and the message
+    assert_eq!(sample(&text, "relay_messages_in_total"), 5.0);
+    // two approvals and the message to both members
+    assert_eq!(sample(&text, "relay_messages_out_total"), 4.0);
+    assert!(sample(&text, "relay_bytes_in_total") > 0.0);
+    assert!(sample(&text, "relay_bytes_out_total") > 0.0);
+    assert_eq!(sample(&text, "relay_fanout_seconds_count"), 1.0);
+    assert_eq!(sample(&text, "relay_parse_errors_total"), 0.0);
+}
+
+#[tokio::test]
+async fn counts_parse_errors() {
+    let relay = TestRelay::start().await;
+    let http = relay.listen_http().await;
+    let mut client = relay.connect().await;
+    client.send_raw("not json").await;
+    client.expect_closed().await;
+
+    let (_, text) = request(http, "GET", "/metrics", None).await;
+    assert_eq!(sample(&text, "relay_parse_errors_total"), 1.0);
+}
+
+#[tokio::test]
+async fn metrics_listener_serves_nothing_else() {
+    let relay = TestRelay::start().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(crate::http::serve_metrics(
+        listener,
+        Arc::clone(&relay.server),
+    ));
+
+    let (status, text) = request(addr, "GET", "/metrics", None).await;
+    assert_eq!(status, 200);
+    assert!(text.contains("# TYPE relay_fanout_seconds histogram"));
+    let (status, _) = request(addr, "GET", "/clients", None).await;
+    assert_eq!(status, 404);
+}
@@ -0,0 +1,363 @@
+//! End to end harness: starts the relay on an ephemeral port and drives it with
+//! fake clients that write the same JSON frames `zenactors/init.lua` does.
+
+mod acks;
+mod admin;
+mod auth;
+mod barriers;
+mod batch;
+mod compression;
+mod config;
+mod dedup;
+mod election;
+mod http;
+mod lanes;
+mod locks;
+mod logging;
+mod mailbox;
+mod metrics;
+mod rooms;
+mod sequences;
+mod session;
+mod shutdown;
+mod state;
+mod store;
+mod tasks;
+mod tls;
+#[cfg(unix)]
+mod unix;
+mod websocket;
+
+use crate::config::Config;
+use crate::connection;
+use crate::error::OperationError;
+use crate::protocol::{ClientId, ServerOperation};
+use crate::server::Server;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+/// How long a fake client waits for a frame before the test fails
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a fake client listens before deciding nothing else is coming
+pub const QUIET_PERIOD: Duration = Duration::from_millis(200);
+
+/// The frames init.lua builds with cjson.encode, one per helper there
+pub mod lua {
+    use crate::protocol::ClientId;
+
+    fn client_id(clientId: Option<ClientId>) -> String {
+        match clientId {
+            Some(id) => format!("\"{}\"", id),
+            None => "null".to_string(),
+        }
+    }
+
+    /// sendClientConnectRequest
+    pub fn connect_request() -> String {
+        r#"{"clientOperation":"ConnectAttempt"}"#.to_string()
+    }
+
+    /// sendRoomJoinRequest
+    pub fn room_join(clientId: Option<ClientId>, room: &str) -> String {
+        format!(
+            r#"{{"clientId":{},"clientOperation":{{"RoomJoin":"{}"}}}}"#,
+            client_id(clientId),
+            room
+        )
+    }
+
+    /// sendRoomLeaveRequest, which does not send a clientId
+    pub fn room_leave(room: &str) -> String {
+        format!(r#"{{"clientOperation":{{"RoomLeave":"{}"}}}}"#, room)
+    }
+
+    /// sendActorMessage
+    pub fn actor_message(
+        clientId: Option<ClientId>,
+        room: &str,
+        channel: &str,
+        message: &str,
+    ) -> String {
+        format!(
+            r#"{{"clientId":{},"clientOperation":{{"Message":{{"room":"{}","channel":"{}","message":"{}"}}}}}}"#,
+            client_id(clientId),
+            room,
+            channel,
+            message
+        )
+    }
+
+    pub fn disconnect(clientId: Option<ClientId>) -> String {
+        format!(
+            r#"{{"clientId":{},"clientOperation":"Disconnect"}}"#,
+            client_id(clientId)
+        )
+    }
+
+    /// Any other operation, without a clientId like the newer helpers send it
+    pub fn operation(operation: serde_json::Value) -> String {
+        serde_json::json!({ "clientId": null, "clientOperation": operation }).to_string()
+    }
+
+    /// ConnectAttempt with a static token from auth.tokens
+    pub fn connect_with_token(token: &str) -> String {
+        operation(serde_json::json!({ "ConnectAttempt": { "credentials": { "Token": token } } }))
+    }
+}
+
+/// A config where token "<name>" authenticates as identity "<name>", for each of `names`
+pub fn identities(names: &[&str]) -> Config {
+    let mut config = Config::default();
+    for name in names {
+        config
+            .auth
+            .tokens
+            .insert(name.to_string(), name.to_string());
+    }
+    config
+}
+
+pub struct TestRelay {
+    pub addr: SocketAddr,
+    pub server: Arc<Mutex<Server>>,
+}
+
+impl TestRelay {
+    pub async fn start() -> Self {
+        Self::start_with(Config::default()).await
+    }
+
+    pub async fn start_with(config: Config) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(Mutex::new(Server::open(Arc::new(config)).unwrap()));
+        tokio::spawn(connection::run(listener, Arc::clone(&server)));
+        tokio::spawn(crate::acks::run(Arc::clone(&server)));
+        tokio::spawn(crate::deadlines::run(Arc::clone(&server)));
+        Self { addr, server }
+    }
+
+    /// Runs the same shutdown sequence main does on SIGINT/SIGTERM
+    pub async fn shutdown(&self) -> bool {
+        crate::shutdown::graceful(&self.server).await
+    }
+
+    /// Opens a raw connection without sending anything
+    pub async fn open(&self) -> FakeClient {
+        FakeClient::over(TcpStream::connect(self.addr).await.unwrap())
+    }
+
+    /// Serves the relay on a unix socket as well, sharing this relay's state
+    #[cfg(unix)]
+    pub fn listen_unix(&self, path: &std::path::Path) {
+        let listener = connection::bind_unix(path).unwrap();
+        tokio::spawn(connection::run(listener, Arc::clone(&self.server)));
+    }
+
+    /// Serves the admin API from another ephemeral port
+    pub async fn listen_http(&self) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::http::serve(listener, Arc::clone(&self.server)));
+        addr
+    }
+
+    /// Serves WebSocket connections on `path` from a second ephemeral port
+    pub async fn listen_websocket(&self, path: &str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let path: Arc<str> = path.into();
+        tokio::spawn(connection::run_with(
+            listener,
+            Arc::clone(&self.server),
+            move |stream, server| {
+                crate::websocket::serve_websocket(stream, server, Arc::clone(&path), None)
+            },
+        ));
+        addr
+    }
+
+    /// Serves the line protocol behind TLS from a second ephemeral port
+    pub async fn listen_tls(&self, tls: crate::config::TlsConfig) -> SocketAddr {
+        let acceptor = crate::tls::acceptor(&tls).unwrap();
+        let tls = Arc::new(tls);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(connection::run_with(
+            listener,
+            Arc::clone(&self.server),
+            move |stream, server| {
+                crate::tls::serve_stream(acceptor.clone(), Arc::clone(&tls), stream, server)
+            },
+        ));
+        addr
+    }
+
+    #[cfg(unix)]
+    pub async fn open_unix(&self, path: &std::path::Path) -> FakeClient {
+        FakeClient::over(tokio::net::UnixStream::connect(path).await.unwrap())
+    }
+
+    /// Opens a connection and completes the ConnectAttempt handshake
+    pub async fn connect(&self) -> FakeClient {
+        let mut client = self.open().await;
+        client.connect_attempt().await;
+        client
+    }
+
+    /// Connects authenticated by `token`, see `identities`
+    pub async fn login(&self, token: &str) -> FakeClient {
+        let mut client = self.open().await;
+        client.send_raw(&lua::connect_with_token(token)).await;
+        client.expect_approval().await;
+        client
+    }
+
+    /// Polls the shared server state until `condition` holds, there are no join acks to wait on
+    pub async fn wait_until(&self, condition: impl Fn(&Server) -> bool) {
+        let poll = async {
+            loop {
+                if condition(&*self.server.lock().await) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        timeout(RECV_TIMEOUT, poll)
+            .await
+            .expect("relay state never reached the expected condition");
+    }
+
+    pub async fn is_member(&self, room: &str, clientId: ClientId) -> bool {
+        let server = self.server.lock().await;
+        server
+            .rooms
+            .iter()
+            .any(|(r, members)| r.0 == room && members.contains(&clientId))
+    }
+
+    pub async fn wait_for_member(&self, room: &str, clientId: ClientId, member: bool) {
+        self.wait_until(|server| {
+            server
+                .rooms
+                .iter()
+                .any(|(r, members)| r.0 == room && members.contains(&clientId))
+                == member
+        })
+        .await;
+    }
+}
+
+pub struct FakeClient {
+    reader: Box<dyn AsyncBufRead + Send + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    pub clientId: Option<ClientId>,
+}
+
+impl FakeClient {
+    pub fn over<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        Self {
+            reader: Box::new(BufReader::new(reader)),
+            writer: Box::new(writer),
+            clientId: None,
+        }
+    }
+
+    pub fn id(&self) -> ClientId {
+        self.clientId.expect("client has not been approved yet")
+    }
+
+    /// Writes one frame, appending the newline delimiter like init.lua does
+    pub async fn send_raw(&mut self, frame: &str) {
+        self.writer.write_all(frame.as_bytes()).await.unwrap();
+        self.writer.write_all(b"\n").await.unwrap();
+    }
+
+    pub async fn connect_attempt(&mut self) -> ClientId {
+        self.send_raw(&lua::connect_request()).await;
+        self.expect_approval().await
+    }
+
+    pub async fn expect_approval(&mut self) -> ClientId {
+        match self.recv().await {
+            ServerOperation::ClientConnectApproved(id) => {
+                self.clientId = Some(id);
+                id
+            }
+            other => panic!("expected ClientConnectApproved, got {:?}", other),
+        }
+    }
+
+    pub async fn join(&mut self, relay: &TestRelay, room: &str) {
+        self.send_raw(&lua::room_join(self.clientId, room)).await;
+        relay.wait_for_member(room, self.id(), true).await;
+    }
+
+    pub async fn leave(&mut self, relay: &TestRelay, room: &str) {
+        self.send_raw(&lua::room_leave(room)).await;
+        relay.wait_for_member(room, self.id(), false).await;
+    }
+
+    pub async fn say(&mut self, room: &str, channel: &str, message: &str) {
+        let frame = lua::actor_message(self.clientId, room, channel, message);
+        self.send_raw(&frame).await;
+    }
+
+    /// Reads the next raw line, None once the relay has closed the connection
+    pub async fn recv_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        let read = timeout(RECV_TIMEOUT, self.reader.read_line(&mut line))
+            .await
+            .expect("timed out waiting for a frame from the relay");
+        match read {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    }
+
+    pub async fn recv(&mut self) -> ServerOperation {
+        let line = self.recv_line().await.expect("relay closed the connection");
+        assert!(
+            line.ends_with('\n'),
+            "frame is not newline terminated: {:?}",
+            line
+        );
+        serde_json::from_str(&line)
+            .unwrap_or_else(|e| panic!("relay sent an unparseable frame {:?}: {}", line, e))
+    }
+
+    /// Asserts that nothing arrives within the quiet period
+    pub async fn expect_silence(&mut self) {
+        let mut line = String::new();
+        if let Ok(read) = timeout(QUIET_PERIOD, self.reader.read_line(&mut line)).await {
+            panic!("expected no frames, got {:?} ({:?})", line, read);
+        }
+    }
+
+    /// Asserts the next frame refuses an operation with `error`
+    pub async fn expect_nack(&mut self, error: OperationError) {
+        assert_eq!(
+            self.recv().await,
+            ServerOperation::Nack {
+                reason: error.to_string()
+            }
+        );
+    }
+
+    pub async fn expect_closed(&mut self) {
+        assert_eq!(
+            self.recv_line().await,
+            None,
+            "relay kept the connection open"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use super::lua::operation;
+use super::{identities, TestRelay};
+use crate::config::{Config, RoomConfig};
+use crate::error::OperationError;
+use crate::protocol::{Room, ServerOperation};
+use serde_json::json;
+
+async fn relay_with(rooms: Vec<RoomConfig>) -> TestRelay {
+    let mut config = identities(&["leader", "healer", "tank", "friend"]);
+    config.rooms = rooms;
+    TestRelay::start_with(config).await
+}
+
+fn raid(configure: impl FnOnce(&mut RoomConfig)) -> Vec<RoomConfig> {
+    let mut room = RoomConfig {
+        name: "raid".to_string(),
+        owner: Some("leader".to_string()),
+        ..Default::default()
+    };
+    configure(&mut room);
+    vec![room]
+}
+
+fn raid_room() -> Room {
+    Room("raid".to_string())
+}
+
+#[tokio::test]
+async fn password_protected_room_needs_the_password() {
+    let relay = relay_with(raid(|room| room.password = Some("hunter2".to_string()))).await;
+    let mut healer = relay.login("healer").await;
+
+    healer
+        .send_raw(&operation(json!({ "RoomJoin": "raid" })))
+        .await;
+    healer
+        .expect_nack(OperationError::WrongPassword(raid_room()))
+        .await;
+    healer
+        .send_raw(&operation(
+            json!({ "RoomJoinWithPassword": { "room": "raid", "password": "nope" } }),
+        ))
+        .await;
+    healer
+        .expect_nack(OperationError::WrongPassword(raid_room()))
+        .await;
+
+    healer
+        .send_raw(&operation(
+            json!({ "RoomJoinWithPassword": { "room": "raid", "password": "hunter2" } }),
+        ))
+        .await;
+    relay.wait_for_member("raid", healer.id(), true).await;
+}
+
+#[tokio::test]
+async fn owner_skips_the_password() {
+    let relay = relay_with(raid(|room| room.password = Some("hunter2".to_string()))).await;
+    let mut leader = relay.login("leader").await;
+    leader.join(&relay, "raid").await;
+}
+
+#[tokio::test]
+async fn invite_only_room_admits_invited_identities() {
+    let relay = relay_with(raid(|room| room.invite_only = true)).await;
+    let mut leader = relay.login("leader").await;
+    let mut tank = relay.login("tank").await;
+
+    tank.send_raw(&operation(json!({ "RoomJoin": "raid" })))
+        .await;
+    tank.expect_nack(OperationError::NotInvited(raid_room()))
+        .await;
+
+    leader
+        .send_raw(&operation(
+            json!({ "RoomInvite": { "room": "raid", "identity": "tank" } }),
+        ))
+        .await;
+    relay
+        .wait_until(|server| server.acls[&raid_room()].invited.len() == 1)
+        .await;
+    tank.join(&relay, "raid").await;
+}
+
+#[tokio::test]
+async fn only_the_owner_can_invite() {
+    let relay = relay_with(raid(|room| room.invite_only = true)).await;
+    let mut healer = relay.login("healer").await;
+
+    healer
+        .send_raw(&operation(
+            json!({ "RoomInvite": { "room": "raid", "identity": "healer" } }),
+        ))
+        .await;
+    healer
+        .expect_nack(OperationError::NotOwner(raid_room()))
+        .await;
+}
+
+#[tokio::test]
+async fn read_only_members_listen_but_can_not_send() {
+    let relay = relay_with(raid(|room| room.read_only = vec!["friend".to_string()])).await;
+    let mut leader = relay.login("leader").await;
+    let mut friend = relay.login("friend").await;
+    leader.join(&relay, "raid").await;
+    friend.join(&relay, "raid").await;
+
+    friend.say("raid", "chat", "can I talk?").await;
+    friend
+        .expect_nack(OperationError::ReadOnly(raid_room()))
+        .await;
+    leader.expect_silence().await;
+
+    leader.say("raid", "chat", "pull in 5").await;
+    for client in [&mut leader, &mut friend] {
+        assert!(matches!(
+            client.recv().await,
+            ServerOperation::RoomMessage { .. }
+        ));
+    }
+}
+
+#[tokio::test]
+async fn outsiders_can_not_send_into_a_private_room() {
+    let relay = relay_with(raid(|_| {})).await;
+    let mut leader = relay.login("leader").await;
+    let mut tank = relay.login("tank").await;
+    leader.join(&relay, "raid").await;
+
+    tank.say("raid", "chat", "hi from outside").await;
+    tank.expect_nack(OperationError::NotMember(raid_room()))
+        .await;
+    leader.expect_silence().await;
+}
+
+#[tokio::test]
+async fn created_rooms_are_owned_by_their_creator() {
+    let relay = relay_with(vec![]).await;
+    let mut friend = relay.login("friend").await;
+    let mut healer = relay.login("healer").await;
+
+    friend
+        .send_raw(&operation(
+            json!({ "RoomCreate": { "room": "team", "password": "ours" } }),
+        ))
+        .await;
+    relay.wait_for_member("team", friend.id(), true).await;
+
+    healer
+        .send_raw(&operation(json!({ "RoomCreate": { "room": "team" } })))
+        .await;
+    healer
+        .expect_nack(OperationError::RoomExists(Room("team".to_string())))
+        .await;
+    healer
+        .send_raw(&operation(json!({ "RoomJoin": "team" })))
+        .await;
+    healer
+        .expect_nack(OperationError::WrongPassword(Room("team".to_string())))
+        .await;
+}
+
+#[tokio::test]
+async fn anonymous_clients_can_not_create_rooms() {
+    let relay = relay_with(vec![]).await;
+    let mut client = relay.connect().await;
+
+    client
+        .send_raw(&operation(json!({ "RoomCreate": { "room": "team" } })))
+        .await;
+    client.expect_nack(OperationError::IdentityRequired).await;
+}
+
+#[tokio::test]
+async fn rooms_that_exist_can_not_be_created_again() {
+    let relay = relay_with(raid(|_| {})).await;
+    let mut client = relay.connect().await;
+    client.join(&relay, "open").await;
+    client.join(&relay, "notes").await;
+    client
+        .send_raw(&operation(json!({ "StateSet": {
+            "room": "notes", "key": "pull", "value": "8",
+        } })))
+        .await;
+    relay.wait_until(|server| !server.state.is_empty()).await;
+    client.leave(&relay, "notes").await;
+    let mut tank = relay.login("tank").await;
+
+    // configured, in use, and only holding state
+    for room in ["raid", "open", "notes"] {
+        tank.send_raw(&operation(json!({ "RoomCreate": { "room": room } })))
+            .await;
+        tank.expect_nack(OperationError::RoomExists(Room(room.to_string())))
+            .await;
+    }
+    assert_eq!(relay.server.lock().await.acls.len(), 1);
+}
+
+#[tokio::test]
+async fn open_configured_rooms_can_not_be_hijacked() {
+    let relay = relay_with(vec![RoomConfig {
+        name: "lobby".to_string(),
+        ..Default::default()
+    }])
+    .await;
+    let mut tank = relay.login("tank").await;
+    tank.send_raw(&operation(
+        json!({ "RoomCreate": { "room": "lobby", "password": "mine" } }),
+    ))
+    .await;
+    tank.expect_nack(OperationError::RoomExists(Room("lobby".to_string())))
+        .await;
+
+    let mut client = relay.connect().await;
+    client.join(&relay, "lobby").await;
+}
+
+#[tokio::test]
+async fn rooms_without_an_acl_stay_open() {
+    let relay = relay_with(vec![]).await;
+    let mut client = relay.connect().await;
+    client.join(&relay, "testRoom").await;
+    client.say("testRoom", "chat", "hello").await;
+    assert!(matches!(
+        client.recv().await,
+        ServerOperation::RoomMessage { .. }
+    ));
+}
+
+#[test]
+fn invite_only_rooms_need_someone_who_can_get_in() {
+    let config = Config {
+        rooms: vec![RoomConfig {
+            name: "raid".to_string(),
+            invite_only: true,
+            password: Some(String::new()),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let problems = config.validate().unwrap_err().to_string();
+    assert!(problems.contains("empty password"), "{}", problems);
+    assert!(problems.contains("invite_only"), "{}", problems);
+}
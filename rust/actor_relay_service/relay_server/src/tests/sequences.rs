@@ -0,0 +1,196 @@
+use super::{FakeClient, TestRelay};
+use crate::config::Config;
+use crate::error::OperationError;
+use crate::outbox::Delivery;
+use crate::protocol::{Channel, ClientId, Message, Priority, Room, ServerOperation};
+use serde_json::json;
+
+fn retransmit(room: &str, channel: &str, from: u64, to: u64) -> String {
+    json!({
+        "clientId": null,
+        "clientOperation": { "Retransmit": { "room": room, "channel": channel, "from": from, "to": to } },
+    })
+    .to_string()
+}
+
+/// The seq and message text of a RoomMessage
+async fn recv_seq(client: &mut FakeClient) -> (u64, String) {
+    match client.recv().await {
+        ServerOperation::RoomMessage { seq, message, .. } => (seq, message.0),
+        other => panic!("expected RoomMessage, got {:?}", other),
+    }
+}
+
+async fn relay_with_history(room_messages: usize) -> TestRelay {
+    let mut config = Config::default();
+    config.history.room_messages = room_messages;
+    TestRelay::start_with(config).await
+}
+
+#[tokio::test]
+async fn mixed_priorities_on_one_channel_keep_their_order() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    a.join(&relay, "otherRoom").await;
+
+    // queued in one go, before a's writer gets to any of it
+    {
+        let mut server = relay.server.lock().await;
+        for (room, channel, priority, message) in [
+            ("testRoom", "cmd", Priority::Bulk, "assist"),
+            ("otherRoom", "chat", Priority::Bulk, "hello"),
+            ("testRoom", "cmd", Priority::Normal, "burn"),
+            ("testRoom", "cmd", Priority::Control, "stop"),
+            ("testRoom", "cmd", Priority::Bulk, "loot"),
+        ] {
+            let (room, channel) = (Room(room.to_string()), Channel(channel.to_string()));
+            let seq = server.next_seq(&room, &channel);
+            let operation = ServerOperation::RoomMessage {
+                sender: ClientId::RELAY,
+                room: room.clone(),
+                channel,
+                message: Message(message.to_string()),
+                seq,
+            };
+            server.broadcast(&room, &operation, Delivery::new(priority, None));
+        }
+    }
+
+    // the Control message only jumps ahead of the other channel
+    for expected in [
+        (1, "assist"),
+        (2, "burn"),
+        (3, "stop"),
+        (1, "hello"),
+        (4, "loot"),
+    ] {
+        assert_eq!(recv_seq(&mut a).await, (expected.0, expected.1.to_string()));
+    }
+}
+
+#[tokio::test]
+async fn every_room_and_channel_counts_on_its_own() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    a.join(&relay, "otherRoom").await;
+
+    a.say("testRoom", "status", "s1").await;
+    a.say("testRoom", "cmd", "c1").await;
+    a.say("testRoom", "status", "s2").await;
+    a.say("otherRoom", "status", "o1").await;
+
+    assert_eq!(recv_seq(&mut a).await, (1, "s1".to_string()));
+    assert_eq!(recv_seq(&mut a).await, (1, "c1".to_string()));
+    assert_eq!(recv_seq(&mut a).await, (2, "s2".to_string()));
+    assert_eq!(recv_seq(&mut a).await, (1, "o1".to_string()));
+}
+
+#[tokio::test]
+async fn missed_ranges_are_resent_from_history() {
+    let relay = relay_with_history(10).await;
+    let mut a = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    for n in 1..=4 {
+        a.say("testRoom", "status", &format!("status {}", n)).await;
+        a.say("testRoom", "cmd", "noise").await;
+    }
+    for _ in 0..8 {
+        a.recv().await;
+    }
+
+    a.send_raw(&retransmit("testRoom", "status", 2, 3)).await;
+    assert_eq!(recv_seq(&mut a).await, (2, "status 2".to_string()));
+    assert_eq!(recv_seq(&mut a).await, (3, "status 3".to_string()));
+    a.expect_silence().await;
+
+    // asking past the end gets what exists
+    a.send_raw(&retransmit("testRoom", "status", 4, 100)).await;
+    assert_eq!(recv_seq(&mut a).await, (4, "status 4".to_string()));
+    a.expect_silence().await;
+}
+
+#[tokio::test]
+async fn ranges_older_than_the_history_are_reported_as_a_gap() {
+    let relay = relay_with_history(2).await;
+    let mut a = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    for n in 1..=5 {
+        a.say("testRoom", "status", &format!("status {}", n)).await;
+    }
+    for _ in 0..5 {
+        a.recv().await;
+    }
+
+    a.send_raw(&retransmit("testRoom", "status", 1, 5)).await;
+    assert_eq!(
+        a.recv().await,
+        ServerOperation::HistoryGap {
+            room: Room("testRoom".to_string()),
+            channel: Channel("status".to_string()),
+            from: 1,
+            to: 3,
+        }
+    );
+    assert_eq!(recv_seq(&mut a).await, (4, "status 4".to_string()));
+    assert_eq!(recv_seq(&mut a).await, (5, "status 5".to_string()));
+}
+
+#[tokio::test]
+async fn backwards_ranges_are_refused() {
+    let relay = relay_with_history(2).await;
+    let mut a = relay.connect().await;
+    a.send_raw(&retransmit("testRoom", "status", 5, 1)).await;
+    assert_eq!(
+        a.recv().await,
+        ServerOperation::Nack {
+            reason: OperationError::BadRange(5, 1).to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn retransmit_without_history_is_refused() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    a.send_raw(&retransmit("testRoom", "status", 1, 1)).await;
+    a.expect_nack(OperationError::NoHistory).await;
+}
+
+#[tokio::test]
+async fn closing_a_room_starts_its_count_over() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    a.join(&relay, "scratch").await;
+    a.say("scratch", "status", "first").await;
+    assert_eq!(recv_seq(&mut a).await.0, 1);
+
+    relay
+        .server
+        .lock()
+        .await
+        .close_room(&Room("scratch".to_string()));
+    assert_eq!(
+        a.recv().await,
+        ServerOperation::RoomClosed(Room("scratch".to_string()))
+    );
+    a.join(&relay, "scratch").await;
+    a.say("scratch", "status", "again").await;
+    assert_eq!(recv_seq(&mut a).await.0, 1);
+}
+
+#[tokio::test]
+async fn messages_to_rooms_that_do_not_exist_are_nacked_and_leave_nothing_behind() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+
+    a.say("nowhere", "chat", "anyone?").await;
+    a.expect_nack(OperationError::NoSuchRoom(Room("nowhere".to_string())))
+        .await;
+    let server = relay.server.lock().await;
+    assert!(server.sequences.is_empty());
+    assert!(server.history.is_empty());
+    assert!(!server.room_exists(&Room("nowhere".to_string())));
+}
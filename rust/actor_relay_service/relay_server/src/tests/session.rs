@@ -0,0 +1,205 @@
+use super::{lua, TestRelay};
+use crate::protocol::{Channel, Message, Room, ServerOperation};
+
+fn room_message(
+    seq: u64,
+    sender: crate::protocol::ClientId,
+    room: &str,
+    channel: &str,
+    message: &str,
+) -> ServerOperation {
+    ServerOperation::RoomMessage {
+        sender,
+        room: Room(room.to_string()),
+        channel: Channel(channel.to_string()),
+        message: Message(message.to_string()),
+        seq,
+    }
+}
+
+#[tokio::test]
+async fn connect_attempt_is_approved_with_a_fresh_id() {
+    let relay = TestRelay::start().await;
+    let a = relay.connect().await;
+    let b = relay.connect().await;
+
+    assert_ne!(a.id(), b.id());
+    let server = relay.server.lock().await;
+    assert!(server.clients.contains_key(&a.id()));
+    assert!(server.clients.contains_key(&b.id()));
+}
+
+#[tokio::test]
+async fn approval_frame_matches_what_init_lua_decodes() {
+    let relay = TestRelay::start().await;
+    let mut client = relay.open().await;
+    client.send_raw(&lua::connect_request()).await;
+
+    let line = client.recv_line().await.unwrap();
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    // init.lua does `Settings.ClientId = message.ClientConnectApproved`
+    assert!(value["ClientConnectApproved"].is_string());
+}
+
+#[tokio::test]
+async fn join_before_approval_is_processed() {
+    // init.lua sends the join straight after the connect request, while its ClientId is still null
+    let relay = TestRelay::start().await;
+    let mut client = relay.open().await;
+    client.send_raw(&lua::connect_request()).await;
+    client.send_raw(&lua::room_join(None, "testRoom")).await;
+    let id = client.expect_approval().await;
+
+    relay.wait_for_member("testRoom", id, true).await;
+}
+
+#[tokio::test]
+async fn message_fans_out_to_every_room_member() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    let mut c = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+    c.join(&relay, "testRoom").await;
+
+    a.say("testRoom", "testChannel", "Test message from A")
+        .await;
+
+    let expected = room_message(1, a.id(), "testRoom", "testChannel", "Test message from A");
+    assert_eq!(a.recv().await, expected);
+    assert_eq!(b.recv().await, expected);
+    assert_eq!(c.recv().await, expected);
+}
+
+#[tokio::test]
+async fn message_is_not_delivered_outside_the_room() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut outsider = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    outsider.join(&relay, "otherRoom").await;
+
+    a.say("testRoom", "testChannel", "hello").await;
+
+    assert_eq!(
+        a.recv().await,
+        room_message(1, a.id(), "testRoom", "testChannel", "hello")
+    );
+    outsider.expect_silence().await;
+}
+
+#[tokio::test]
+async fn messages_from_one_sender_arrive_in_order() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+
+    for i in 0..20 {
+        a.say("testRoom", "testChannel", &format!("message {}", i))
+            .await;
+    }
+
+    for i in 0..20 {
+        let expected = room_message(
+            i + 1,
+            a.id(),
+            "testRoom",
+            "testChannel",
+            &format!("message {}", i),
+        );
+        assert_eq!(b.recv().await, expected);
+    }
+}
+
+#[tokio::test]
+async fn leaving_a_room_stops_delivery() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+    b.leave(&relay, "testRoom").await;
+
+    a.say("testRoom", "testChannel", "after leave").await;
+
+    assert_eq!(
+        a.recv().await,
+        room_message(1, a.id(), "testRoom", "testChannel", "after leave")
+    );
+    b.expect_silence().await;
+}
+
+#[tokio::test]
+async fn disconnect_operation_closes_and_forgets_the_client() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+
+    let gone = b.id();
+    b.send_raw(&lua::disconnect(b.clientId)).await;
+    b.expect_closed().await;
+    relay
+        .wait_until(|server| !server.clients.contains_key(&gone))
+        .await;
+    assert!(!relay.is_member("testRoom", gone).await);
+
+    a.say("testRoom", "testChannel", "still here").await;
+    assert_eq!(
+        a.recv().await,
+        room_message(1, a.id(), "testRoom", "testChannel", "still here")
+    );
+}
+
+#[tokio::test]
+async fn dropped_socket_is_cleaned_up() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+
+    let gone = b.id();
+    drop(b);
+    relay.wait_for_member("testRoom", gone, false).await;
+
+    a.say("testRoom", "testChannel", "anyone?").await;
+    assert_eq!(
+        a.recv().await,
+        room_message(1, a.id(), "testRoom", "testChannel", "anyone?")
+    );
+}
+
+#[tokio::test]
+async fn malformed_input_closes_only_that_connection() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut bad = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    bad.join(&relay, "testRoom").await;
+
+    let bad_id = bad.id();
+    bad.send_raw(r#"{"clientOperation":{"RoomJoin":"#).await;
+    bad.expect_closed().await;
+    relay.wait_for_member("testRoom", bad_id, false).await;
+
+    a.say("testRoom", "testChannel", "unaffected").await;
+    assert_eq!(
+        a.recv().await,
+        room_message(1, a.id(), "testRoom", "testChannel", "unaffected")
+    );
+}
+
+#[tokio::test]
+async fn unknown_operation_is_rejected() {
+    let relay = TestRelay::start().await;
+    let mut client = relay.connect().await;
+    client
+        .send_raw(r#"{"clientOperation":"SelfDestruct"}"#)
+        .await;
+    client.expect_closed().await;
+}
@@ -0,0 +1,95 @@
+use super::TestRelay;
+use crate::config::Config;
+use crate::protocol::ServerOperation;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn clients_are_told_to_reconnect_then_disconnected() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+
+    assert!(relay.shutdown().await);
+
+    for client in [&mut a, &mut b] {
+        assert_eq!(
+            client.recv().await,
+            ServerOperation::ServerShuttingDown { reconnect_after: 5 }
+        );
+        client.expect_closed().await;
+    }
+}
+
+#[tokio::test]
+async fn reconnect_delay_comes_from_config() {
+    let mut config = Config::default();
+    config.shutdown.reconnect_after_secs = 30;
+    let relay = TestRelay::start_with(config).await;
+    let mut client = relay.connect().await;
+
+    relay.shutdown().await;
+
+    assert_eq!(
+        client.recv().await,
+        ServerOperation::ServerShuttingDown {
+            reconnect_after: 30
+        }
+    );
+}
+
+#[tokio::test]
+async fn queued_messages_are_flushed_before_the_notice() {
+    let relay = TestRelay::start().await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+    b.join(&relay, "testRoom").await;
+
+    for i in 0..50 {
+        a.say("testRoom", "testChannel", &format!("message {}", i))
+            .await;
+    }
+    // a seeing all of its own copies means every fan-out has been queued for b as well
+    for _ in 0..50 {
+        a.recv().await;
+    }
+
+    assert!(relay.shutdown().await);
+
+    for i in 0..50 {
+        match b.recv().await {
+            ServerOperation::RoomMessage { message, .. } => {
+                assert_eq!(message.0, format!("message {}", i))
+            }
+            other => panic!("expected message {}, got {:?}", i, other),
+        }
+    }
+    assert!(matches!(
+        b.recv().await,
+        ServerOperation::ServerShuttingDown { .. }
+    ));
+    b.expect_closed().await;
+}
+
+#[tokio::test]
+async fn new_connections_are_refused_after_shutdown() {
+    let relay = TestRelay::start().await;
+    relay.shutdown().await;
+
+    // the accept loop hands its listener back on the way out, give it a moment
+    let mut refused = false;
+    for _ in 0..50 {
+        if TcpStream::connect(relay.addr).await.is_err() {
+            refused = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(refused, "relay still accepting after shutdown");
+}
+
+#[tokio::test]
+async fn shutdown_with_no_clients_completes() {
+    let relay = TestRelay::start().await;
+    assert!(relay.shutdown().await);
+}
@@ -0,0 +1,259 @@
+use super::lua::operation;
+use super::{identities, FakeClient, TestRelay};
+use crate::config::{Config, RoomConfig};
+use crate::error::OperationError;
+use crate::protocol::{ClientId, Room, ServerOperation};
+use serde_json::json;
+
+/// A relay with a private "raid" that "friend" can only read
+async fn relay_with(configure: impl FnOnce(&mut Config)) -> TestRelay {
+    let mut config = identities(&["leader", "friend", "tank"]);
+    config.rooms.push(RoomConfig {
+        name: "raid".to_string(),
+        owner: Some("leader".to_string()),
+        read_only: vec!["friend".to_string()],
+        ..Default::default()
+    });
+    configure(&mut config);
+    TestRelay::start_with(config).await
+}
+
+async fn set(client: &mut FakeClient, room: &str, key: &str, value: &str) {
+    client
+        .send_raw(&operation(
+            json!({ "StateSet": { "room": room, "key": key, "value": value } }),
+        ))
+        .await;
+}
+
+async fn watch(client: &mut FakeClient, room: &str, key: Option<&str>) {
+    client
+        .send_raw(&operation(
+            json!({ "StateWatch": { "room": room, "key": key } }),
+        ))
+        .await;
+}
+
+async fn get(client: &mut FakeClient, room: &str, key: &str) -> ServerOperation {
+    client
+        .send_raw(&operation(
+            json!({ "StateGet": { "room": room, "key": key } }),
+        ))
+        .await;
+    client.recv().await
+}
+
+fn changed(key: &str, value: Option<&str>, version: u64, by: Option<ClientId>) -> ServerOperation {
+    ServerOperation::StateChanged {
+        room: Room("testRoom".to_string()),
+        key: key.to_string(),
+        value: value.map(str::to_string),
+        version,
+        by,
+    }
+}
+
+#[tokio::test]
+async fn watchers_see_every_change_with_its_version() {
+    let relay = relay_with(|_| {}).await;
+    let mut puller = relay.connect().await;
+    let mut assist = relay.connect().await;
+    puller.join(&relay, "testRoom").await;
+    watch(&mut assist, "testRoom", Some("target")).await;
+    relay.wait_until(|server| !server.state.is_empty()).await;
+
+    set(&mut puller, "testRoom", "target", "a_gnoll").await;
+    set(&mut puller, "testRoom", "aggro", "tank").await;
+    set(&mut puller, "testRoom", "target", "a_rat").await;
+    let by = Some(puller.id());
+    assert_eq!(
+        assist.recv().await,
+        changed("target", Some("a_gnoll"), 1, by)
+    );
+    assert_eq!(assist.recv().await, changed("target", Some("a_rat"), 3, by));
+    assist.expect_silence().await;
+
+    assert_eq!(
+        get(&mut assist, "testRoom", "aggro").await,
+        ServerOperation::StateValue {
+            room: Room("testRoom".to_string()),
+            key: "aggro".to_string(),
+            value: Some("tank".to_string()),
+            version: 2,
+        }
+    );
+}
+
+#[tokio::test]
+async fn a_new_watch_starts_from_the_current_values() {
+    let relay = relay_with(|_| {}).await;
+    let mut puller = relay.connect().await;
+    puller.join(&relay, "testRoom").await;
+    set(&mut puller, "testRoom", "target", "a_gnoll").await;
+    set(&mut puller, "testRoom", "aggro", "tank").await;
+
+    let mut late = relay.connect().await;
+    watch(&mut late, "testRoom", None).await;
+    assert_eq!(late.recv().await, changed("aggro", Some("tank"), 2, None));
+    assert_eq!(
+        late.recv().await,
+        changed("target", Some("a_gnoll"), 1, None)
+    );
+
+    puller
+        .send_raw(&operation(
+            json!({ "StateDelete": { "room": "testRoom", "key": "aggro" } }),
+        ))
+        .await;
+    assert_eq!(
+        late.recv().await,
+        changed("aggro", None, 3, Some(puller.id()))
+    );
+}
+
+#[tokio::test]
+async fn conditional_writes_fail_once_the_key_moved_on() {
+    let relay = relay_with(|_| {}).await;
+    let mut a = relay.connect().await;
+    let mut b = relay.connect().await;
+    a.join(&relay, "testRoom").await;
+
+    // both claim aggro, only the first finds it unset
+    for client in [&mut a, &mut b] {
+        client
+            .send_raw(&operation(json!({ "StateSet": {
+                "room": "testRoom", "key": "aggro", "value": "me", "if_version": 0,
+            } })))
+            .await;
+    }
+    b.expect_nack(OperationError::VersionMismatch {
+        key: "aggro".to_string(),
+        expected: 0,
+        actual: 1,
+    })
+    .await;
+
+    b.send_raw(&operation(json!({ "StateDelete": {
+        "room": "testRoom", "key": "aggro", "if_version": 1,
+    } })))
+    .await;
+    relay
+        .wait_until(|server| {
+            server
+                .state
+                .get(&Room("testRoom".to_string()))
+                .is_some_and(|state| state.version == 2)
+        })
+        .await;
+    a.expect_silence().await;
+}
+
+#[tokio::test]
+async fn private_room_state_is_for_members() {
+    let relay = relay_with(|_| {}).await;
+    let mut leader = relay.login("leader").await;
+    let mut friend = relay.login("friend").await;
+    let mut tank = relay.login("tank").await;
+    leader.join(&relay, "raid").await;
+    friend.join(&relay, "raid").await;
+    set(&mut leader, "raid", "target", "a_dragon").await;
+
+    assert!(matches!(
+        get(&mut friend, "raid", "target").await,
+        ServerOperation::StateValue { version: 1, .. }
+    ));
+    set(&mut friend, "raid", "target", "a_rat").await;
+    friend
+        .expect_nack(OperationError::ReadOnly(Room("raid".to_string())))
+        .await;
+
+    watch(&mut tank, "raid", None).await;
+    tank.expect_nack(OperationError::NotMember(Room("raid".to_string())))
+        .await;
+    set(&mut tank, "nowhere", "target", "a_rat").await;
+    tank.expect_nack(OperationError::NoSuchRoom(Room("nowhere".to_string())))
+        .await;
+}
+
+#[tokio::test]
+async fn rooms_hold_a_limited_amount_of_state() {
+    let relay = relay_with(|config| {
+        config.state.max_keys_per_room = 2;
+        config.state.max_value_bytes = 8;
+    })
+    .await;
+    let mut client = relay.connect().await;
+    client.join(&relay, "testRoom").await;
+
+    set(&mut client, "testRoom", "target", "a_rat_king").await;
+    client.expect_nack(OperationError::ValueTooLarge(8)).await;
+    set(&mut client, "testRoom", "a", "1").await;
+    set(&mut client, "testRoom", "b", "2").await;
+    set(&mut client, "testRoom", "c", "3").await;
+    client
+        .expect_nack(OperationError::TooManyKeys(Room("testRoom".to_string()), 2))
+        .await;
+    // replacing a value does not take another key
+    set(&mut client, "testRoom", "a", "4").await;
+    client.expect_silence().await;
+}
+
+#[tokio::test]
+async fn watches_end_with_unwatch_leave_or_disconnect() {
+    let relay = relay_with(|_| {}).await;
+    let mut setter = relay.connect().await;
+    let mut watcher = relay.connect().await;
+    setter.join(&relay, "testRoom").await;
+    watcher.join(&relay, "testRoom").await;
+    let room = Room("testRoom".to_string());
+
+    watch(&mut watcher, "testRoom", Some("target")).await;
+    watcher
+        .send_raw(&operation(
+            json!({ "StateUnwatch": { "room": "testRoom", "key": "target" } }),
+        ))
+        .await;
+    set(&mut setter, "testRoom", "target", "a_gnoll").await;
+    relay
+        .wait_until(|server| {
+            server
+                .state
+                .get(&room)
+                .is_some_and(|state| state.version == 1)
+        })
+        .await;
+    watcher.expect_silence().await;
+
+    watch(&mut watcher, "testRoom", None).await;
+    watcher.recv().await;
+    watcher.leave(&relay, "testRoom").await;
+    relay
+        .wait_until(|server| server.state[&room].watchers.is_empty())
+        .await;
+
+    let watcher_id = watcher.id();
+    watch(&mut watcher, "testRoom", None).await;
+    watcher.recv().await;
+    drop(watcher);
+    relay
+        .wait_until(|server| !server.state[&room].watchers.contains_key(&watcher_id))
+        .await;
+}
+
+#[tokio::test]
+async fn watching_alone_does_not_keep_a_room() {
+    let relay = relay_with(|_| {}).await;
+    let mut a = relay.connect().await;
+    let lobby = Room("lobby".to_string());
+    a.join(&relay, "lobby").await;
+    watch(&mut a, "lobby", None).await;
+    relay
+        .wait_until(|server| server.state.contains_key(&lobby))
+        .await;
+
+    a.send_raw(&operation(json!({ "RoomLeave": "lobby" })))
+        .await;
+    relay
+        .wait_until(|server| !server.rooms.contains_key(&lobby) && server.state.is_empty())
+        .await;
+}
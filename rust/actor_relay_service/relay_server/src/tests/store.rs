@@ -0,0 +1,572 @@
+use super::lua::operation;
+use super::{identities, lua, FakeClient, TestRelay};
+use crate::config::{Config, MailboxConfig, RoomConfig, StoreConfig};
+use crate::protocol::{Channel, ClientId, Identity, Message, Priority, Room, ServerOperation};
+use crate::server::{unix_now, Ban, Mail};
+use crate::store::{Compacted, Store};
+use serde_json::json;
+use std::path::PathBuf;
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("relay-store-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn store(&self) -> StoreConfig {
+        StoreConfig {
+            path: Some(self.0.join("relay.db")),
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// "gm" is an admin
+fn config(dir: &TempDir) -> Config {
+    let mut config = identities(&["gm", "leader", "tank", "griefer"]);
+    config.auth.admins = vec!["gm".to_string()];
+    config.store = dir.store();
+    config
+}
+
+fn room_message(room: &str, message: &str) -> ServerOperation {
+    ServerOperation::RoomMessage {
+        sender: ClientId::RELAY,
+        room: Room(room.to_string()),
+        channel: Channel("chat".to_string()),
+        message: Message(message.to_string()),
+        seq: 0,
+    }
+}
+
+#[tokio::test]
+async fn rooms_and_bans_survive_a_restart() {
+    let dir = TempDir::new();
+    {
+        let relay = TestRelay::start_with(config(&dir)).await;
+        let mut leader = relay.login("leader").await;
+        let mut gm = relay.login("gm").await;
+        leader
+            .send_raw(&operation(
+                json!({ "RoomCreate": { "room": "team", "password": "ours" } }),
+            ))
+            .await;
+        relay.wait_for_member("team", leader.id(), true).await;
+        leader
+            .send_raw(&operation(
+                json!({ "RoomInvite": { "room": "team", "identity": "tank" } }),
+            ))
+            .await;
+        gm.send_raw(&operation(json!({ "Ban": { "identity": "griefer" } })))
+            .await;
+        relay
+            .wait_until(|server| {
+                !server.bans.is_empty() && server.acls[&Room("team".to_string())].invited.len() == 1
+            })
+            .await;
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config(&dir)).await;
+    {
+        let server = relay.server.lock().await;
+        let acl = &server.acls[&Room("team".to_string())];
+        assert_eq!(acl.owner, Some(Identity("leader".to_string())));
+        assert_eq!(acl.password.as_deref(), Some("ours"));
+        assert!(acl.invited.contains(&Identity("tank".to_string())));
+        assert!(server.rooms[&Room("team".to_string())].is_empty());
+    }
+    // the invitation still lets tank past the password
+    let mut tank = relay.login("tank").await;
+    tank.join(&relay, "team").await;
+
+    let mut griefer = relay.open().await;
+    griefer.send_raw(&lua::connect_with_token("griefer")).await;
+    assert_eq!(
+        griefer.recv().await,
+        ServerOperation::ConnectRejected {
+            reason: "banned".to_string()
+        }
+    );
+}
+
+#[tokio::test]
+async fn configured_rooms_keep_owner_changes_across_a_restart() {
+    let dir = TempDir::new();
+    let mut config = config(&dir);
+    config.rooms = vec![RoomConfig {
+        name: "raid".to_string(),
+        owner: Some("leader".to_string()),
+        invite_only: true,
+        ..Default::default()
+    }];
+    {
+        let relay = TestRelay::start_with(config.clone()).await;
+        let mut leader = relay.login("leader").await;
+        for change in [
+            json!({ "RoomInvite": { "room": "raid", "identity": "tank" } }),
+            json!({ "RoomInvite": { "room": "raid", "identity": "griefer" } }),
+            json!({ "RoomSetReadOnly": { "room": "raid", "identity": "griefer", "read_only": true } }),
+        ] {
+            leader.send_raw(&operation(change)).await;
+        }
+        relay
+            .wait_until(|server| {
+                server.acls[&Room("raid".to_string())]
+                    .read_only
+                    .contains(&Identity("griefer".to_string()))
+            })
+            .await;
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config).await;
+    {
+        let server = relay.server.lock().await;
+        let acl = &server.acls[&Room("raid".to_string())];
+        assert_eq!(acl.owner, Some(Identity("leader".to_string())));
+        assert!(acl.invite_only);
+        assert!(acl.read_only.contains(&Identity("griefer".to_string())));
+    }
+    let mut tank = relay.login("tank").await;
+    tank.join(&relay, "raid").await;
+}
+
+#[tokio::test]
+async fn history_survives_a_restart() {
+    let dir = TempDir::new();
+    let mut config = config(&dir);
+    config.history.room_messages = 2;
+    {
+        let relay = TestRelay::start_with(config.clone()).await;
+        let mut server = relay.server.lock().await;
+        for message in ["one", "two", "three"] {
+            server.record_history(&Room("raid".to_string()), &room_message("raid", message));
+        }
+        drop(server);
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config).await;
+    let mut client = relay.connect().await;
+    client.join(&relay, "raid").await;
+    assert_eq!(client.recv().await, room_message("raid", "two"));
+    assert_eq!(client.recv().await, room_message("raid", "three"));
+    client.expect_silence().await;
+}
+
+#[tokio::test]
+async fn sequences_carry_on_after_a_restart() {
+    let dir = TempDir::new();
+    let mut config = config(&dir);
+    config.history.room_messages = 5;
+    {
+        let relay = TestRelay::start_with(config.clone()).await;
+        let mut client = relay.connect().await;
+        client.join(&relay, "raid").await;
+        client.say("raid", "chat", "one").await;
+        client.say("raid", "chat", "two").await;
+        client.recv().await;
+        client.recv().await;
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config).await;
+    let mut client = relay.connect().await;
+    client.join(&relay, "raid").await;
+    client.recv().await;
+    client.recv().await;
+    client.say("raid", "chat", "three").await;
+    match client.recv().await {
+        ServerOperation::RoomMessage { seq, .. } => assert_eq!(seq, 3),
+        other => panic!("expected RoomMessage, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn room_state_survives_a_restart() {
+    let dir = TempDir::new();
+    {
+        let relay = TestRelay::start_with(config(&dir)).await;
+        let mut client = relay.connect().await;
+        client.join(&relay, "raid").await;
+        for (key, value) in [
+            ("target", "a_gnoll"),
+            ("aggro", "tank"),
+            ("target", "a_rat"),
+        ] {
+            client
+                .send_raw(&operation(json!({ "StateSet": {
+                    "room": "raid", "key": key, "value": value,
+                } })))
+                .await;
+        }
+        client
+            .send_raw(&operation(
+                json!({ "StateDelete": { "room": "raid", "key": "aggro" } }),
+            ))
+            .await;
+        relay
+            .wait_until(|server| {
+                server
+                    .state
+                    .get(&Room("raid".to_string()))
+                    .is_some_and(|state| state.version == 4)
+            })
+            .await;
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config(&dir)).await;
+    let mut client = relay.connect().await;
+    client
+        .send_raw(&operation(
+            json!({ "StateGet": { "room": "raid", "key": "target" } }),
+        ))
+        .await;
+    assert_eq!(
+        client.recv().await,
+        ServerOperation::StateValue {
+            room: Room("raid".to_string()),
+            key: "target".to_string(),
+            value: Some("a_rat".to_string()),
+            version: 3,
+        }
+    );
+    client
+        .send_raw(&operation(
+            json!({ "StateGet": { "room": "raid", "key": "aggro" } }),
+        ))
+        .await;
+    assert!(matches!(
+        client.recv().await,
+        ServerOperation::StateValue { value: None, .. }
+    ));
+}
+
+#[tokio::test]
+async fn state_versions_carry_on_past_a_delete_after_a_restart() {
+    let dir = TempDir::new();
+    {
+        let relay = TestRelay::start_with(config(&dir)).await;
+        let mut client = relay.connect().await;
+        client.join(&relay, "raid").await;
+        client
+            .send_raw(&operation(json!({ "StateSet": {
+                "room": "raid", "key": "target", "value": "a_gnoll",
+            } })))
+            .await;
+        client
+            .send_raw(&operation(
+                json!({ "StateDelete": { "room": "raid", "key": "target" } }),
+            ))
+            .await;
+        relay
+            .wait_until(|server| {
+                server
+                    .state
+                    .get(&Room("raid".to_string()))
+                    .is_some_and(|state| state.version == 2)
+            })
+            .await;
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config(&dir)).await;
+    relay
+        .wait_until(|server| {
+            server
+                .state
+                .get(&Room("raid".to_string()))
+                .is_some_and(|state| state.version == 2 && state.entries.is_empty())
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn identities_are_remembered() {
+    let dir = TempDir::new();
+    {
+        let relay = TestRelay::start_with(config(&dir)).await;
+        relay.login("tank").await;
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config(&dir)).await;
+    let server = relay.server.lock().await;
+    let record = server.identities[&Identity("tank".to_string())];
+    assert!(record.first_seen <= record.last_seen);
+    assert!(record.last_seen <= unix_now());
+}
+
+#[tokio::test]
+async fn closed_rooms_are_forgotten() {
+    let dir = TempDir::new();
+    {
+        let relay = TestRelay::start_with(config(&dir)).await;
+        let mut leader = relay.login("leader").await;
+        let mut gm = relay.login("gm").await;
+        leader
+            .send_raw(&operation(json!({ "RoomCreate": { "room": "team" } })))
+            .await;
+        relay.wait_for_member("team", leader.id(), true).await;
+        gm.send_raw(&operation(json!({ "CloseRoom": "team" })))
+            .await;
+        assert_eq!(
+            leader.recv().await,
+            ServerOperation::RoomClosed(Room("team".to_string()))
+        );
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config(&dir)).await;
+    assert!(relay.server.lock().await.acls.is_empty());
+}
+
+#[tokio::test]
+async fn mailboxes_survive_a_restart() {
+    let dir = TempDir::new();
+    {
+        let relay = TestRelay::start_with(config(&dir)).await;
+        let mut leader = relay.login("leader").await;
+        leader
+            .send_raw(&operation(json!({
+                "DirectMessage": { "to": "tank", "channel": "assign", "message": "pull at 8" }
+            })))
+            .await;
+        relay
+            .wait_until(|server| !server.mailboxes.is_empty())
+            .await;
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config(&dir)).await;
+    let mut tank = relay.login("tank").await;
+    match tank.recv().await {
+        ServerOperation::DirectMessage {
+            from,
+            message,
+            queued_at,
+            ..
+        } => {
+            assert_eq!(from, Some(Identity("leader".to_string())));
+            assert_eq!(message.0, "pull at 8");
+            assert!(queued_at.is_some());
+        }
+        other => panic!("expected DirectMessage, got {:?}", other),
+    }
+    drop(tank);
+    relay.shutdown().await;
+
+    // and are gone from the store once delivered
+    let relay = TestRelay::start_with(config(&dir)).await;
+    assert!(relay.server.lock().await.mailboxes.is_empty());
+}
+
+#[test]
+fn bans_that_never_end_fit_the_store() {
+    let dir = TempDir::new();
+    let store = Store::open(&dir.store()).unwrap();
+    let griefer = Identity("griefer".to_string());
+    store
+        .save_ban(
+            &griefer,
+            Ban {
+                until: Some(u64::MAX),
+            },
+        )
+        .unwrap();
+    assert_eq!(store.bans().unwrap()[&griefer].until, Some(i64::MAX as u64));
+}
+
+#[test]
+fn mail_that_never_expires_fits_the_store() {
+    let dir = TempDir::new();
+    let store = Store::open(&dir.store()).unwrap();
+    let mail = Mail {
+        at: 5,
+        operation: ServerOperation::RoomClosed(Room("raid".to_string())),
+        priority: Priority::Normal,
+        expires_ms: Some(u64::MAX),
+    };
+    store
+        .push_mail(&Identity("tank".to_string()), &mail, 10)
+        .unwrap();
+    let mailboxes = store.mailboxes().unwrap();
+    assert_eq!(
+        mailboxes[&Identity("tank".to_string())][0].expires_ms,
+        Some(i64::MAX as u64)
+    );
+}
+
+#[test]
+fn history_is_capped_per_room_as_it_is_written() {
+    let dir = TempDir::new();
+    let store = Store::open(&StoreConfig {
+        history_per_room: 2,
+        ..dir.store()
+    })
+    .unwrap();
+    for message in ["one", "two", "three"] {
+        store
+            .append_history(&Room("raid".to_string()), &room_message("raid", message), 0)
+            .unwrap();
+    }
+    store
+        .append_history(&Room("team".to_string()), &room_message("team", "hi"), 0)
+        .unwrap();
+
+    let history = store.history(10).unwrap();
+    assert_eq!(
+        Vec::from(history[&Room("raid".to_string())].clone()),
+        vec![room_message("raid", "two"), room_message("raid", "three")]
+    );
+    assert_eq!(history[&Room("team".to_string())].len(), 1);
+}
+
+#[test]
+fn compaction_applies_retention() {
+    let dir = TempDir::new();
+    let now = 10_000;
+    {
+        let store = Store::open(&StoreConfig {
+            history_per_room: 0,
+            ..dir.store()
+        })
+        .unwrap();
+        let raid = Room("raid".to_string());
+        for (at, message) in [
+            (10, "ancient"),
+            (9_990, "one"),
+            (9_995, "two"),
+            (9_999, "three"),
+        ] {
+            store
+                .append_history(&raid, &room_message("raid", message), at)
+                .unwrap();
+        }
+        store
+            .save_ban(
+                &Identity("old".to_string()),
+                Ban {
+                    until: Some(now - 1),
+                },
+            )
+            .unwrap();
+        store
+            .save_ban(&Identity("forever".to_string()), Ban { until: None })
+            .unwrap();
+    }
+
+    let store = Store::open(&StoreConfig {
+        history_per_room: 2,
+        history_max_age_secs: 100,
+        ..dir.store()
+    })
+    .unwrap();
+    assert_eq!(
+        store.compact(now, &MailboxConfig::default()).unwrap(),
+        Compacted {
+            history: 2,
+            bans: 1,
+            mail: 0,
+        }
+    );
+    assert_eq!(
+        Vec::from(store.history(10).unwrap()[&Room("raid".to_string())].clone()),
+        vec![room_message("raid", "two"), room_message("raid", "three")]
+    );
+    assert_eq!(
+        store.bans().unwrap().into_keys().collect::<Vec<_>>(),
+        vec![Identity("forever".to_string())]
+    );
+}
+
+#[test]
+fn store_limit_can_not_be_below_the_replayed_history() {
+    let mut config = Config::default();
+    config.history.room_messages = 50;
+    config.store.history_per_room = 10;
+    let problems = config.validate().unwrap_err().to_string();
+    assert!(problems.contains("store.history_per_room"), "{}", problems);
+}
+
+#[tokio::test]
+async fn a_burst_of_messages_is_relayed_and_stored() {
+    let dir = TempDir::new();
+    let mut config = config(&dir);
+    config.history.room_messages = 50;
+    config.store.history_per_room = 50;
+    let relay = TestRelay::start_with(config.clone()).await;
+    let mut sender = relay.connect().await;
+    sender.join(&relay, "raid").await;
+    let mut listener = relay.connect().await;
+    listener.join(&relay, "raid").await;
+
+    for n in 0..500 {
+        sender.say("raid", "chat", &n.to_string()).await;
+    }
+    for n in 0..500 {
+        match listener.recv().await {
+            ServerOperation::RoomMessage { message, seq, .. } => {
+                assert_eq!(message.0, n.to_string());
+                assert_eq!(seq, n + 1);
+            }
+            other => panic!("expected RoomMessage, got {:?}", other),
+        }
+    }
+    relay.shutdown().await;
+
+    let history = Store::open(&config.store).unwrap().history(100).unwrap();
+    let raid = &history[&Room("raid".to_string())];
+    assert_eq!(raid.len(), 50);
+    assert!(matches!(
+        raid.back(),
+        Some(ServerOperation::RoomMessage { message, .. }) if message.0 == "499"
+    ));
+}
+
+#[tokio::test]
+async fn fencing_tokens_keep_growing_across_a_close_and_a_restart() {
+    async fn loot_token(relay: &TestRelay, client: &mut FakeClient) -> u64 {
+        client.join(relay, "raid").await;
+        client
+            .send_raw(&operation(json!({ "LockAcquire": {
+                "room": "raid", "name": "loot", "ttl_ms": 60_000,
+            } })))
+            .await;
+        match client.recv().await {
+            ServerOperation::LockGranted { token, .. } => token,
+            other => panic!("expected LockGranted, got {:?}", other),
+        }
+    }
+
+    let dir = TempDir::new();
+    {
+        let relay = TestRelay::start_with(config(&dir)).await;
+        let mut tank = relay.login("tank").await;
+        assert_eq!(loot_token(&relay, &mut tank).await, 1);
+        let mut gm = relay.login("gm").await;
+        gm.send_raw(&operation(json!({ "CloseRoom": "raid" })))
+            .await;
+        assert_eq!(
+            tank.recv().await,
+            ServerOperation::RoomClosed(Room("raid".to_string()))
+        );
+        assert_eq!(loot_token(&relay, &mut tank).await, 2);
+        relay.shutdown().await;
+    }
+
+    let relay = TestRelay::start_with(config(&dir)).await;
+    let mut tank = relay.login("tank").await;
+    assert_eq!(loot_token(&relay, &mut tank).await, 3);
+}
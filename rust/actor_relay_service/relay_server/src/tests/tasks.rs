@@ -0,0 +1,205 @@
+use super::lua::operation;
+use super::{identities, FakeClient, TestRelay};
+use crate::config::Config;
+use crate::error::OperationError;
+use crate::protocol::{Identity, Recipient, Room, ServerOperation, TaskMember};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+async fn define(client: &mut FakeClient, steps: &[&str]) {
+    client
+        .send_raw(&operation(json!({ "TaskDefine": {
+            "room": "testRoom", "name": "raid", "steps": steps,
+        } })))
+        .await;
+}
+
+async fn report(client: &mut FakeClient, step: usize) {
+    client
+        .send_raw(&operation(json!({ "TaskReport": {
+            "room": "testRoom", "name": "raid", "step": step,
+        } })))
+        .await;
+}
+
+async fn query(client: &mut FakeClient) -> Vec<TaskMember> {
+    client
+        .send_raw(&operation(
+            json!({ "TaskQuery": { "room": "testRoom", "name": "raid" } }),
+        ))
+        .await;
+    match client.recv().await {
+        ServerOperation::TaskProgress { members, .. } => members,
+        other => panic!("expected TaskProgress, got {:?}", other),
+    }
+}
+
+fn reached(step: usize) -> ServerOperation {
+    ServerOperation::TaskStepReached {
+        room: Room("testRoom".to_string()),
+        name: "raid".to_string(),
+        step,
+    }
+}
+
+fn poll() -> ServerOperation {
+    ServerOperation::RequestCurrentTaskStep {
+        room: Room("testRoom".to_string()),
+        name: "raid".to_string(),
+    }
+}
+
+async fn group(relay: &TestRelay, count: usize) -> Vec<FakeClient> {
+    let mut clients = Vec::new();
+    for _ in 0..count {
+        let mut client = relay.connect().await;
+        client.join(relay, "testRoom").await;
+        clients.push(client);
+    }
+    clients
+}
+
+#[tokio::test]
+async fn the_room_hears_once_everyone_got_to_a_step() {
+    let relay = TestRelay::start().await;
+    let mut raid = group(&relay, 2).await;
+
+    define(&mut raid[0], &["gather", "pull", "loot"]).await;
+    for member in &mut raid {
+        match member.recv().await {
+            ServerOperation::TaskProgress { steps, members, .. } => {
+                assert_eq!(steps, ["gather", "pull", "loot"]);
+                assert!(members.iter().all(|member| member.step.is_none()));
+            }
+            other => panic!("expected TaskProgress, got {:?}", other),
+        }
+    }
+
+    report(&mut raid[0], 2).await;
+    report(&mut raid[1], 1).await;
+    for member in &mut raid {
+        assert_eq!(member.recv().await, reached(1));
+    }
+    // everyone done is one past the last step
+    report(&mut raid[1], 3).await;
+    for member in &mut raid {
+        assert_eq!(member.recv().await, reached(2));
+    }
+    report(&mut raid[0], 3).await;
+    for member in &mut raid {
+        assert_eq!(member.recv().await, reached(3));
+    }
+}
+
+#[tokio::test]
+async fn members_can_ask_who_is_on_which_step() {
+    let relay = TestRelay::start_with(identities(&["healer"])).await;
+    let mut healer = relay.login("healer").await;
+    healer.join(&relay, "testRoom").await;
+    let mut anonymous = relay.connect().await;
+    anonymous.join(&relay, "testRoom").await;
+
+    define(&mut anonymous, &["gather", "pull"]).await;
+    healer.recv().await;
+    anonymous.recv().await;
+    report(&mut healer, 1).await;
+    relay
+        .wait_until(|server| {
+            server.tasks.values().any(|task| {
+                task.step_of(&Recipient::Identity(Identity("healer".to_string()))) == Some(1)
+            })
+        })
+        .await;
+
+    let members = query(&mut anonymous).await;
+    assert_eq!(members.len(), 2);
+    assert!(members.contains(&TaskMember {
+        who: Recipient::Identity(Identity("healer".to_string())),
+        step: Some(1),
+    }));
+    assert!(members.contains(&TaskMember {
+        who: Recipient::Client(anonymous.id()),
+        step: None,
+    }));
+}
+
+#[tokio::test]
+async fn stragglers_are_asked_where_they_are() {
+    let mut config = Config::default();
+    config.tasks.poll_after_ms = 150;
+    let relay = TestRelay::start_with(config).await;
+    let mut raid = group(&relay, 2).await;
+    let started = Instant::now();
+    define(&mut raid[0], &["gather", "pull"]).await;
+    for member in &mut raid {
+        member.recv().await;
+    }
+
+    // nobody is behind until someone moves on
+    raid[1].expect_silence().await;
+    report(&mut raid[0], 1).await;
+    assert_eq!(raid[1].recv().await, poll());
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    // and asked again while it stays quiet
+    assert_eq!(raid[1].recv().await, poll());
+
+    report(&mut raid[1], 1).await;
+    for member in &mut raid {
+        assert_eq!(member.recv().await, reached(1));
+    }
+    raid[0].expect_silence().await;
+    raid[1].expect_silence().await;
+}
+
+#[tokio::test]
+async fn leaving_stops_holding_the_room_back() {
+    let relay = TestRelay::start().await;
+    let mut raid = group(&relay, 3).await;
+    define(&mut raid[0], &["gather", "pull"]).await;
+    for member in &mut raid {
+        member.recv().await;
+    }
+    report(&mut raid[0], 2).await;
+    report(&mut raid[1], 2).await;
+    relay
+        .wait_until(|server| {
+            server
+                .tasks
+                .values()
+                .any(|task| task.step_of(&Recipient::Client(raid[1].id())) == Some(2))
+        })
+        .await;
+
+    raid[2].leave(&relay, "testRoom").await;
+    assert_eq!(raid[0].recv().await, reached(2));
+    assert_eq!(raid[1].recv().await, reached(2));
+}
+
+#[tokio::test]
+async fn refused_task_operations() {
+    let mut config = Config::default();
+    config.tasks.max_steps = 2;
+    let relay = TestRelay::start_with(config).await;
+    let mut raid = group(&relay, 1).await;
+    let mut outsider = relay.connect().await;
+
+    define(&mut raid[0], &[]).await;
+    raid[0].expect_nack(OperationError::NoSteps).await;
+    define(&mut raid[0], &["gather", "pull", "loot"]).await;
+    raid[0].expect_nack(OperationError::TooManySteps(2)).await;
+    report(&mut raid[0], 0).await;
+    raid[0]
+        .expect_nack(OperationError::NoSuchTask("raid".to_string()))
+        .await;
+
+    define(&mut raid[0], &["gather", "pull"]).await;
+    raid[0].recv().await;
+    report(&mut raid[0], 3).await;
+    raid[0]
+        .expect_nack(OperationError::NoSuchStep("raid".to_string(), 2))
+        .await;
+    report(&mut outsider, 1).await;
+    outsider
+        .expect_nack(OperationError::NotMember(Room("testRoom".to_string())))
+        .await;
+}
@@ -0,0 +1,268 @@
+use super::{lua, FakeClient, TestRelay};
+use crate::config::{Config, TlsConfig};
+use crate::protocol::Identity;
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// A throwaway CA with a server certificate for localhost, all written out as PEM files
+struct Pki {
+    dir: PathBuf,
+    ca: rcgen::Certificate,
+    ca_key: KeyPair,
+}
+
+impl Pki {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("relay-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "relay test ca");
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        let pki = Self { dir, ca, ca_key };
+        pki.issue("server", "localhost", vec!["localhost".to_string()]);
+        pki
+    }
+
+    /// Signs a certificate for `common_name`, written to `<name>.pem` and `<name>.key`
+    fn issue(&self, name: &str, common_name: &str, sans: Vec<String>) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(sans).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+        std::fs::write(self.path(&format!("{}.pem", name)), cert.pem()).unwrap();
+        std::fs::write(self.path(&format!("{}.key", name)), key.serialize_pem()).unwrap();
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn server_config(&self) -> TlsConfig {
+        TlsConfig {
+            cert: Some(self.path("server.pem")),
+            key: Some(self.path("server.key")),
+            ..TlsConfig::default()
+        }
+    }
+
+    fn mutual_config(&self, require_client_cert: bool) -> TlsConfig {
+        TlsConfig {
+            client_ca: Some(self.path("ca.pem")),
+            require_client_cert,
+            ..self.server_config()
+        }
+    }
+
+    /// Opens a TLS connection trusting the test CA, presenting `<client>.pem` if given
+    async fn open(&self, addr: SocketAddr, client: Option<&str>) -> std::io::Result<FakeClient> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(self.path("ca.pem")).unwrap())
+            .unwrap();
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        let config = match client {
+            Some(name) => builder
+                .with_client_auth_cert(
+                    vec![
+                        CertificateDer::from_pem_file(self.path(&format!("{}.pem", name))).unwrap(),
+                    ],
+                    PrivateKeyDer::from_pem_file(self.path(&format!("{}.key", name))).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let stream = TcpStream::connect(addr).await?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await?;
+        Ok(FakeClient::over(stream))
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn identity_of(relay: &TestRelay, client: &FakeClient) -> Option<Identity> {
+    let server = relay.server.lock().await;
+    server.clients[&client.id()].identity.clone()
+}
+
+#[tokio::test]
+async fn tls_clients_speak_the_same_protocol() {
+    let pki = Pki::new();
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_tls(pki.server_config()).await;
+    let mut plain = relay.connect().await;
+    let mut secure = pki.open(addr, None).await.unwrap();
+    secure.connect_attempt().await;
+
+    plain.join(&relay, "testRoom").await;
+    secure.join(&relay, "testRoom").await;
+    secure.say("testRoom", "testChannel", "encrypted").await;
+
+    for client in [&mut plain, &mut secure] {
+        match client.recv().await {
+            crate::protocol::ServerOperation::RoomMessage { message, .. } => {
+                assert_eq!(message.0, "encrypted")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert_eq!(identity_of(&relay, &secure).await, None);
+}
+
+#[tokio::test]
+async fn plaintext_on_the_tls_port_goes_nowhere() {
+    let pki = Pki::new();
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_tls(pki.server_config()).await;
+
+    let mut client = FakeClient::over(TcpStream::connect(addr).await.unwrap());
+    client.send_raw(&lua::connect_request()).await;
+    // at most a TLS alert comes back, never an approval
+    if let Some(reply) = client.recv_line().await {
+        assert!(serde_json::from_str::<crate::protocol::ServerOperation>(&reply).is_err());
+    }
+    assert!(relay.server.lock().await.clients.is_empty());
+}
+
+#[tokio::test]
+async fn client_certificate_common_name_becomes_the_identity() {
+    let pki = Pki::new();
+    pki.issue("box1", "box1", vec![]);
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_tls(pki.mutual_config(true)).await;
+
+    let mut client = pki.open(addr, Some("box1")).await.unwrap();
+    client.connect_attempt().await;
+
+    assert_eq!(
+        identity_of(&relay, &client).await,
+        Some(Identity("box1".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn client_certificate_satisfies_required_auth() {
+    let pki = Pki::new();
+    pki.issue("box1", "box1", vec![]);
+    let mut config = Config::default();
+    config.auth.required = true;
+    let relay = TestRelay::start_with(config).await;
+    let addr = relay.listen_tls(pki.mutual_config(false)).await;
+
+    let mut client = pki.open(addr, Some("box1")).await.unwrap();
+    client.connect_attempt().await;
+    client.join(&relay, "testRoom").await;
+}
+
+#[tokio::test]
+async fn identities_table_renames_subjects() {
+    let pki = Pki::new();
+    pki.issue("cleric", "Zenclericus", vec![]);
+    let mut tls = pki.mutual_config(true);
+    tls.identities
+        .insert("Zenclericus".to_string(), "main-healer".to_string());
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_tls(tls).await;
+
+    let mut client = pki.open(addr, Some("cleric")).await.unwrap();
+    client.connect_attempt().await;
+
+    assert_eq!(
+        identity_of(&relay, &client).await,
+        Some(Identity("main-healer".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn missing_client_certificate_is_refused_when_required() {
+    let pki = Pki::new();
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_tls(pki.mutual_config(true)).await;
+
+    // with TLS 1.3 the client can finish its side before the relay rejects it
+    if let Ok(mut client) = pki.open(addr, None).await {
+        client.send_raw(&lua::connect_request()).await;
+        client.expect_closed().await;
+    }
+    assert!(relay.server.lock().await.clients.is_empty());
+}
+
+#[tokio::test]
+async fn client_certificate_is_optional_unless_required() {
+    let pki = Pki::new();
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_tls(pki.mutual_config(false)).await;
+
+    let mut client = pki.open(addr, None).await.unwrap();
+    client.connect_attempt().await;
+    assert_eq!(identity_of(&relay, &client).await, None);
+}
+
+#[tokio::test]
+async fn certificates_from_another_ca_are_refused() {
+    let pki = Pki::new();
+    let other = Pki::new();
+    other.issue("impostor", "box1", vec![]);
+    std::fs::copy(other.path("impostor.pem"), pki.path("impostor.pem")).unwrap();
+    std::fs::copy(other.path("impostor.key"), pki.path("impostor.key")).unwrap();
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_tls(pki.mutual_config(true)).await;
+
+    if let Ok(mut client) = pki.open(addr, Some("impostor")).await {
+        client.send_raw(&lua::connect_request()).await;
+        client.expect_closed().await;
+    }
+    assert!(relay.server.lock().await.clients.is_empty());
+}
+
+#[test]
+fn key_without_certificate_is_a_config_error() {
+    let mut config = Config::default();
+    config.tls.key = Some(PathBuf::from("/nonexistent/relay.key"));
+    let err = config.validate().unwrap_err().to_string();
+    assert!(
+        err.contains("tls.cert and tls.key must be set together"),
+        "{}",
+        err
+    );
+    assert!(
+        err.contains("/nonexistent/relay.key does not exist"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn unreadable_key_names_the_file() {
+    let pki = Pki::new();
+    std::fs::write(pki.path("server.key"), "not a key").unwrap();
+    let err = match crate::tls::acceptor(&pki.server_config()) {
+        Ok(_) => panic!("acceptor built from a bad key"),
+        Err(err) => err,
+    };
+    assert!(format!("{:#}", err).contains("server.key"));
+}
@@ -0,0 +1,84 @@
+use super::TestRelay;
+use crate::config::Config;
+use crate::connection;
+
+fn socket_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("relay-{}.sock", uuid::Uuid::new_v4()))
+}
+
+#[tokio::test]
+async fn unix_clients_speak_the_same_protocol() {
+    let relay = TestRelay::start().await;
+    let path = socket_path();
+    relay.listen_unix(&path);
+
+    let mut local = relay.open_unix(&path).await;
+    local.connect_attempt().await;
+    local.join(&relay, "testRoom").await;
+    local
+        .say("testRoom", "testChannel", "over the socket")
+        .await;
+
+    match local.recv().await {
+        crate::protocol::ServerOperation::RoomMessage { message, .. } => {
+            assert_eq!(message.0, "over the socket")
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn unix_and_tcp_clients_share_rooms() {
+    let relay = TestRelay::start().await;
+    let path = socket_path();
+    relay.listen_unix(&path);
+
+    let mut remote = relay.connect().await;
+    let mut local = relay.open_unix(&path).await;
+    local.connect_attempt().await;
+    remote.join(&relay, "testRoom").await;
+    local.join(&relay, "testRoom").await;
+
+    local.say("testRoom", "testChannel", "from the box").await;
+    remote.say("testRoom", "testChannel", "from afar").await;
+
+    for client in [&mut remote, &mut local] {
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            if let crate::protocol::ServerOperation::RoomMessage { message, .. } =
+                client.recv().await
+            {
+                seen.push(message.0);
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, ["from afar", "from the box"]);
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn stale_socket_file_is_replaced() {
+    let path = socket_path();
+    let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    drop(stale);
+
+    connection::bind_unix(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn unix_only_config_is_valid() {
+    let config: Config =
+        toml::from_str("[server]\ntcp = false\nunix = \"/tmp/relay.sock\"\n").unwrap();
+    config.validate().unwrap();
+    assert!(config.server.tcp.is_none());
+}
+
+#[test]
+fn config_without_listeners_is_rejected() {
+    let config: Config = toml::from_str("[server]\ntcp = false\n").unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("no listeners"));
+}
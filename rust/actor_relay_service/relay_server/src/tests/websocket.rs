@@ -0,0 +1,187 @@
+use super::{lua, TestRelay, RECV_TIMEOUT};
+use crate::protocol::{ClientId, ServerOperation};
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+struct WsClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    clientId: Option<ClientId>,
+}
+
+impl WsClient {
+    async fn connect(addr: SocketAddr, path: &str) -> Self {
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}{}", addr, path))
+            .await
+            .unwrap();
+        let mut client = Self {
+            socket,
+            clientId: None,
+        };
+        client.send_text(&lua::connect_request()).await;
+        match client.recv().await {
+            ServerOperation::ClientConnectApproved(id) => client.clientId = Some(id),
+            other => panic!("expected ClientConnectApproved, got {:?}", other),
+        }
+        client
+    }
+
+    async fn send_text(&mut self, frame: &str) {
+        self.socket.send(WsMessage::text(frame)).await.unwrap();
+    }
+
+    async fn send_binary(&mut self, frame: &str) {
+        self.socket
+            .send(WsMessage::binary(frame.as_bytes().to_vec()))
+            .await
+            .unwrap();
+    }
+
+    async fn recv(&mut self) -> ServerOperation {
+        loop {
+            let frame = timeout(RECV_TIMEOUT, self.socket.next())
+                .await
+                .expect("timed out waiting for a frame from the relay")
+                .expect("relay closed the connection")
+                .unwrap();
+            if let WsMessage::Text(text) = frame {
+                assert!(!text.ends_with('\n'));
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn websocket_clients_get_approved() {
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_websocket("/relay").await;
+
+    let client = WsClient::connect(addr, "/relay").await;
+    let server = relay.server.lock().await;
+    assert!(server.clients.contains_key(&client.clientId.unwrap()));
+}
+
+#[tokio::test]
+async fn text_and_binary_frames_are_each_one_message() {
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_websocket("/relay").await;
+    let mut dashboard = WsClient::connect(addr, "/relay").await;
+    let id = dashboard.clientId.unwrap();
+
+    dashboard
+        .send_binary(&lua::room_join(Some(id), "testRoom"))
+        .await;
+    relay.wait_for_member("testRoom", id, true).await;
+    dashboard
+        .send_text(&lua::actor_message(Some(id), "testRoom", "ui", "hello"))
+        .await;
+
+    match dashboard.recv().await {
+        ServerOperation::RoomMessage { message, .. } => assert_eq!(message.0, "hello"),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn websocket_and_tcp_clients_share_rooms() {
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_websocket("/relay").await;
+    let mut dashboard = WsClient::connect(addr, "/relay").await;
+    let mut box1 = relay.connect().await;
+    let dashboard_id = dashboard.clientId.unwrap();
+
+    box1.join(&relay, "testRoom").await;
+    dashboard
+        .send_text(&lua::room_join(Some(dashboard_id), "testRoom"))
+        .await;
+    relay.wait_for_member("testRoom", dashboard_id, true).await;
+
+    box1.say("testRoom", "testChannel", "status from box1")
+        .await;
+    match dashboard.recv().await {
+        ServerOperation::RoomMessage {
+            sender, message, ..
+        } => {
+            assert_eq!(sender, box1.id());
+            assert_eq!(message.0, "status from box1");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    dashboard
+        .send_text(&lua::actor_message(
+            Some(dashboard_id),
+            "testRoom",
+            "testChannel",
+            "stop attacking",
+        ))
+        .await;
+    box1.recv().await;
+    match box1.recv().await {
+        ServerOperation::RoomMessage {
+            sender, message, ..
+        } => {
+            assert_eq!(sender, dashboard_id);
+            assert_eq!(message.0, "stop attacking");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn other_paths_are_rejected() {
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_websocket("/relay").await;
+
+    let result = tokio_tungstenite::connect_async(format!("ws://{}/elsewhere", addr)).await;
+    match result {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+            assert_eq!(response.status(), 404)
+        }
+        other => panic!("expected a 404, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn stalled_handshakes_are_dropped() {
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_websocket("/relay").await;
+    let mut stalled = TcpStream::connect(addr).await.unwrap();
+    let connected = tokio::time::Instant::now();
+
+    // the clock is paused, so this returns once the relay gave up on the handshake
+    let mut buffer = [0; 64];
+    assert_eq!(stalled.read(&mut buffer).await.unwrap(), 0);
+    assert!(connected.elapsed() >= crate::tls::HANDSHAKE_TIMEOUT);
+}
+
+#[tokio::test]
+async fn closing_the_socket_removes_the_client() {
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_websocket("/relay").await;
+    let mut dashboard = WsClient::connect(addr, "/relay").await;
+    let id = dashboard.clientId.unwrap();
+
+    dashboard.socket.close(None).await.unwrap();
+    relay
+        .wait_until(|server| !server.clients.contains_key(&id))
+        .await;
+}
+
+#[tokio::test]
+async fn malformed_frame_closes_the_socket() {
+    let relay = TestRelay::start().await;
+    let addr = relay.listen_websocket("/relay").await;
+    let mut dashboard = WsClient::connect(addr, "/relay").await;
+    let id = dashboard.clientId.unwrap();
+
+    dashboard.send_text("not json").await;
+    relay
+        .wait_until(|server| !server.clients.contains_key(&id))
+        .await;
+}
@@ -0,0 +1,147 @@
+use crate::config::TlsConfig;
+use crate::connection;
+use crate::protocol::Identity;
+use crate::server::Server;
+use anyhow::Context;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+// a client that connects and never finishes the handshake should not hold a task forever, plain
+// WebSocket handshakes get as long
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Loads the certificates named in the config and builds the acceptor every TLS listener shares
+pub fn acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let (cert, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => anyhow::bail!("tls.cert and tls.key are both required"),
+    };
+    let chain = load_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("could not read a private key from {}", key.display()))?;
+
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("bad CA certificate in {}", ca.display()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.require_client_cert {
+                verifier.build()?
+            } else {
+                verifier.allow_unauthenticated().build()?
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder
+        .with_single_cert(chain, key)
+        .context("certificate and private key do not match")?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("could not read certificates from {}", path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("could not parse certificates in {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// Runs the TLS handshake, giving back the stream and who the client certificate says it is
+pub async fn handshake<S>(
+    acceptor: &TlsAcceptor,
+    config: &TlsConfig,
+    stream: S,
+) -> Option<(TlsStream<S>, Option<Identity>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            warn!("TLS handshake failed: {}", e);
+            return None;
+        }
+        Err(_) => {
+            warn!("TLS handshake timed out");
+            return None;
+        }
+    };
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|chain| chain.first())
+        .and_then(|cert| identity_for(cert, config));
+    Some((stream, identity))
+}
+
+/// connection::serve_stream behind TLS
+pub async fn serve_stream<S>(
+    acceptor: TlsAcceptor,
+    config: Arc<TlsConfig>,
+    stream: S,
+    server: Arc<Mutex<Server>>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    if let Some((stream, identity)) = handshake(&acceptor, &config, stream).await {
+        connection::serve_stream_as(stream, server, identity).await;
+    }
+}
+
+/// websocket::serve_websocket behind TLS
+pub async fn serve_websocket<S>(
+    acceptor: TlsAcceptor,
+    config: Arc<TlsConfig>,
+    stream: S,
+    server: Arc<Mutex<Server>>,
+    path: Arc<str>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    if let Some((stream, identity)) = handshake(&acceptor, &config, stream).await {
+        crate::websocket::serve_websocket(stream, server, path, identity).await;
+    }
+}
+
+/// Maps a client certificate to an identity: its subject common name, renamed through
+/// tls.identities when listed there
+pub fn identity_for(cert: &CertificateDer, config: &TlsConfig) -> Option<Identity> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let common_name = parsed
+        .subject()
+        .iter_common_name()
+        .next()?
+        .as_str()
+        .ok()?
+        .to_string();
+    let identity = config
+        .identities
+        .get(&common_name)
+        .cloned()
+        .unwrap_or(common_name);
+    Some(Identity(identity))
+}
@@ -0,0 +1,121 @@
+use crate::protocol::Identity;
+use crate::server::Server;
+use crate::session::{Flow, Session};
+use crate::tls::HANDSHAKE_TIMEOUT;
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::{error, info, warn, Instrument};
+
+/* WebSocket transport.
+    Every text or binary frame from the browser is one ClientMessage, so unlike the stream
+    transports there is no newline framing on the way in.  Frames going out are sent as text
+    without the trailing newline, compressed ones included.
+*/
+// the handshake callback's error type is fixed by tungstenite
+#[allow(clippy::result_large_err)]
+pub async fn serve_websocket<S>(
+    stream: S,
+    server: Arc<Mutex<Server>>,
+    path: Arc<str>,
+    identity: Option<Identity>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (max_frame_bytes, shutdown) = {
+        let server = server.lock().await;
+        (
+            server.config.limits.max_frame_bytes,
+            server.shutdown.clone(),
+        )
+    };
+
+    let check_path = |request: &Request, response: Response| {
+        if request.uri().path() == &*path {
+            Ok(response)
+        } else {
+            let mut rejection = ErrorResponse::new(Some("no relay here".to_string()));
+            *rejection.status_mut() = StatusCode::NOT_FOUND;
+            Err(rejection)
+        }
+    };
+    let ws_config = WebSocketConfig::default().max_message_size(Some(max_frame_bytes));
+    let handshake =
+        tokio_tungstenite::accept_hdr_async_with_config(stream, check_path, Some(ws_config));
+    let socket = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(socket)) => socket,
+        Ok(Err(e)) => {
+            warn!("WebSocket handshake failed: {}", e);
+            return;
+        }
+        Err(_) => {
+            warn!("WebSocket handshake timed out");
+            return;
+        }
+    };
+
+    let (mut sink, mut frames) = socket.split();
+    let (mut session, mut rx) = Session::open(server, identity).await;
+    let clientId = session.clientId;
+    let metrics = Arc::clone(&session.metrics);
+    let codec = session.codec.clone();
+
+    let span = session.span.clone();
+
+    // same writer contract as the stream transports, runs until every sender is gone
+    shutdown.tasks.spawn(
+        async move {
+            while let Some(message) = rx.recv().await {
+                let message = codec.encode(message);
+                let text = message.trim_end_matches('\n').to_string();
+                let bytes = text.len();
+                if let Err(e) = sink.send(WsMessage::text(text)).await {
+                    error!("Failed to write message to client {}: {}", clientId, e);
+                    metrics.dropped();
+                    return;
+                }
+                metrics.sent(bytes);
+            }
+            if let Err(e) = sink.close().await {
+                warn!("Failed to close connection to client {}: {}", clientId, e);
+            }
+        }
+        .instrument(span.clone()),
+    );
+
+    async move {
+        loop {
+            let frame = tokio::select! {
+                frame = frames.next() => frame,
+                _ = session.closed.cancelled() => break,
+            };
+            let text = match frame {
+                None | Some(Ok(WsMessage::Close(_))) => {
+                    info!("Client {} closed the connection", clientId);
+                    break;
+                }
+                Some(Ok(WsMessage::Text(text))) => text.to_string(),
+                Some(Ok(WsMessage::Binary(bytes))) => String::from_utf8_lossy(&bytes).into_owned(),
+                // pings are answered by tungstenite itself
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    error!("Failed to read from client {}: {}", clientId, e);
+                    break;
+                }
+            };
+
+            if session.handle_frame(&text).await == Flow::Close {
+                break;
+            }
+        }
+
+        session.close().await;
+    }
+    .instrument(span)
+    .await;
+}
@@ -9,6 +9,10 @@ local Settings = {
 	channel = "testChannel",
 	room = "testRoom",
 	ClientId = cjson.null,
+	-- a token from the relay's [auth.tokens], nil to connect without credentials
+	token = nil,
+	-- password of Settings.room, nil for open rooms
+	roomPassword = nil,
 }
 
 --local MessageTypes = {
@@ -31,14 +35,25 @@ local state = {
 
 local currentState = state.CONNECTING
 
-local function sendActorMessage(room, channel, message)
+-- every message gets an id, so the relay drops a retried send instead of relaying it twice
+local messageCount = 0
+local function nextMessageId()
+	messageCount = messageCount + 1
+	return string.format("%s-%d-%d", mq.TLO.Me.CleanName(), os.time(), messageCount)
+end
+
+-- returns the id it sent with, pass it back in as id when retrying the same message so the relay
+-- can tell it is a retry; leave id out for a new message
+local function sendActorMessage(room, channel, message, id)
+	id = id or nextMessageId()
 	local ClientSendMessage = {
 		clientId = Settings.ClientId,
 		clientOperation = {
 			Message = {
 				room = room,
 				channel = channel,
-				message = message
+				message = message,
+				id = id
 			},
 		}
 	}
@@ -46,12 +61,18 @@ local function sendActorMessage(room, channel, message)
 	local json_message = cjson.encode(ClientSendMessage) .. "\n"
 	BL.info("Sending message JSON: %s", json_message)
 	tcp:send(json_message)
+	return id
 end
 
 local function sendClientConnectRequest()
 	local ClientConnectRequest = {
 		clientOperation = "ConnectAttempt",
 	}
+	if Settings.token then
+		ClientConnectRequest.clientOperation = {
+			ConnectAttempt = { credentials = { Token = Settings.token } },
+		}
+	end
 	local json_message = cjson.encode(ClientConnectRequest) .. "\n"
 	tcp:send(json_message)
 	BL.dump(json_message, "Sent connect request:")
@@ -65,6 +86,11 @@ local function sendRoomJoinRequest(room)
 			RoomJoin = room
 		}
 	}
+	if Settings.roomPassword then
+		RoomJoinRequest.clientOperation = {
+			RoomJoinWithPassword = { room = room, password = Settings.roomPassword },
+		}
+	end
 
 	-- Use the cjson.encode function to convert the table into a JSON string, uses \n as stream ending delimiter	
 	local json_message = cjson.encode(RoomJoinRequest) .. "\n"
@@ -98,9 +124,10 @@ while true do
 	BL.dump(status, "status")
 	BL.dump(partial, "partial")
 
+	-- the relay terminates every frame with \n, so a complete frame arrives in s
 	if currentState == state.CONNECTING then
-		if BL.NotNil(partial) and type(partial) == "string" then
-			local message = cjson.decode(partial)
+		if BL.NotNil(s) and type(s) == "string" then
+			local message = cjson.decode(s)
 			--set my new client ID from server
 
 			Settings.ClientId = message.ClientConnectApproved
@@ -111,8 +138,8 @@ while true do
 
 	if currentState == state.CONNECTED then
 		--get messages from server
-		if BL.NotNil(partial) then
-			BL.dump(partial, "Message From Server")
+		if BL.NotNil(s) then
+			BL.dump(cjson.decode(s), "Message From Server")
 		end
 		local myname = "Test message from " .. mq.TLO.Me.CleanName()
 		sendActorMessage(Settings.room, Settings.channel, myname)